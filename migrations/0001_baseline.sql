@@ -0,0 +1,166 @@
+-- The schema as it stood before migrations were introduced. Every statement is
+-- idempotent so that databases created by the old start-up code are adopted
+-- as they are.
+
+CREATE TABLE IF NOT EXISTS users (
+    id SERIAL PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    wins INTEGER DEFAULT 0,
+    loses INTEGER DEFAULT 0,
+    points INTEGER GENERATED ALWAYS AS (GREATEST(wins - loses, 0)) STORED,
+    token TEXT
+);
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS elo INTEGER DEFAULT 1200;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS current_streak INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS best_streak INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deactivated BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_secret TEXT;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS guest BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_seen TIMESTAMPTZ NOT NULL DEFAULT now();
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email TEXT;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_email ON users (lower(email));
+
+CREATE TABLE IF NOT EXISTS elo_history (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    elo INTEGER NOT NULL,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS elo_history_user_time ON elo_history (user_id, recorded_at);
+
+CREATE TABLE IF NOT EXISTS games (
+    id BIGSERIAL PRIMARY KEY,
+    player1_id INTEGER NOT NULL REFERENCES users(id),
+    player2_id INTEGER NOT NULL REFERENCES users(id),
+    winner_id INTEGER REFERENCES users(id),
+    board TEXT[] NOT NULL,
+    status TEXT NOT NULL,
+    ranked BOOLEAN NOT NULL,
+    finished_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS games_player1 ON games (player1_id);
+
+CREATE INDEX IF NOT EXISTS games_player2 ON games (player2_id);
+
+CREATE INDEX IF NOT EXISTS games_finished_at ON games (finished_at DESC);
+
+ALTER TABLE games ADD COLUMN IF NOT EXISTS handicap BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE games ADD COLUMN IF NOT EXISTS started_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS moves (
+    game_id BIGINT NOT NULL REFERENCES games(id),
+    ply INTEGER NOT NULL,
+    player_id INTEGER NOT NULL REFERENCES users(id),
+    field INTEGER NOT NULL,
+    board INTEGER,
+    think_ms INTEGER NOT NULL,
+    played_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (game_id, ply)
+);
+
+CREATE INDEX IF NOT EXISTS moves_player ON moves (player_id);
+
+CREATE TABLE IF NOT EXISTS auth_events (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    event TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS auth_events_user_time ON auth_events (username, created_at DESC);
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    code_hash TEXT NOT NULL,
+    PRIMARY KEY (user_id, code_hash)
+);
+
+CREATE TABLE IF NOT EXISTS password_resets (
+    token_hash TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS email_verifications (
+    token_hash TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    email TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS banned_ips (
+    ip TEXT PRIMARY KEY,
+    reason TEXT,
+    banned_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS blocks (
+    blocker_id INTEGER NOT NULL REFERENCES users(id),
+    blocked_id INTEGER NOT NULL REFERENCES users(id),
+    PRIMARY KEY (blocker_id, blocked_id)
+);
+
+CREATE TABLE IF NOT EXISTS friends (
+    requester_id INTEGER NOT NULL REFERENCES users(id),
+    addressee_id INTEGER NOT NULL REFERENCES users(id),
+    accepted BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (requester_id, addressee_id)
+);
+
+CREATE INDEX IF NOT EXISTS friends_addressee ON friends (addressee_id);
+
+CREATE TABLE IF NOT EXISTS tournaments (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    format TEXT NOT NULL,
+    finished BOOLEAN NOT NULL DEFAULT FALSE,
+    winner_id INTEGER REFERENCES users(id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS tournament_matches (
+    id BIGSERIAL PRIMARY KEY,
+    tournament_id BIGINT NOT NULL REFERENCES tournaments(id),
+    round INTEGER NOT NULL,
+    slot INTEGER NOT NULL,
+    player1_id INTEGER REFERENCES users(id),
+    player2_id INTEGER REFERENCES users(id),
+    winner_id INTEGER REFERENCES users(id),
+    UNIQUE (tournament_id, round, slot)
+);
+
+ALTER TABLE tournament_matches ADD COLUMN IF NOT EXISTS played BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE tournament_matches ADD COLUMN IF NOT EXISTS forfeit BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS achievements (
+    code TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    description TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS user_achievements (
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    code TEXT NOT NULL REFERENCES achievements(code),
+    earned_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, code)
+);
@@ -0,0 +1,9 @@
+-- Ranked draws get a counter of their own next to wins and loses, filled in
+-- from the games recorded so far.
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS draws INTEGER DEFAULT 0;
+
+UPDATE users u
+SET draws = (SELECT COUNT(*)
+             FROM games g
+             WHERE g.ranked AND g.winner_id IS NULL AND u.id IN (g.player1_id, g.player2_id));
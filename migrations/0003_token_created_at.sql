@@ -0,0 +1,6 @@
+-- Session tokens expire TOKEN_TTL_SECS after they were issued. Sessions that
+-- already exist get a fresh lifetime rather than being cut off at upgrade.
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS token_created_at TIMESTAMPTZ;
+
+UPDATE users SET token_created_at = now() WHERE token IS NOT NULL;
@@ -0,0 +1,4 @@
+-- Games between two given players, in either seat, for the head-to-head
+-- record. The single-column indexes only narrow it down to one of them.
+
+CREATE INDEX IF NOT EXISTS games_player_pair ON games (player1_id, player2_id);
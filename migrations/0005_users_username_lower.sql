@@ -0,0 +1,48 @@
+-- Usernames are unique ignoring case. Registering and renaming already check
+-- with lower(), but two requests racing for "Bob" and "bob" could both pass
+-- that check; the index settles it.
+--
+-- Accounts created before the check existed may clash. The oldest keeps the
+-- name and each of the others is renamed to something the registration rules
+-- accept (at most 20 characters of A-Z, a-z, 0-9 and _) ending in its id, e.g.
+-- "Bob" with id 42 becomes "Bob_42". If that is taken too, a counter follows:
+-- "Bob_42_2". Every rename is raised as a notice and recorded in auth_events
+-- as a "renamed" event under the new name, so admins can find and tell them.
+
+DO $$
+DECLARE
+    clash RECORD;
+    base TEXT;
+    suffix TEXT;
+    candidate TEXT;
+    attempt INTEGER;
+BEGIN
+    FOR clash IN
+        SELECT u.id, u.username FROM users u
+        WHERE EXISTS (SELECT 1 FROM users o WHERE lower(o.username) = lower(u.username) AND o.id < u.id)
+        ORDER BY u.id
+    LOOP
+        base := regexp_replace(clash.username, '[^A-Za-z0-9_]', '', 'g');
+        IF base = '' THEN
+            base := 'user';
+        END IF;
+
+        attempt := 1;
+        LOOP
+            suffix := '_' || clash.id || CASE WHEN attempt > 1 THEN '_' || attempt ELSE '' END;
+            candidate := left(base, 20 - length(suffix)) || suffix;
+            EXIT WHEN NOT EXISTS (SELECT 1 FROM users WHERE lower(username) = lower(candidate));
+            attempt := attempt + 1;
+        END LOOP;
+
+        UPDATE users SET username = candidate WHERE id = clash.id;
+        INSERT INTO auth_events (username, event, ip) VALUES (candidate, 'renamed', '');
+        RAISE NOTICE 'renamed user % from % to % (username clashed ignoring case)', clash.id, clash.username, candidate;
+    END LOOP;
+END
+$$;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_username_lower ON users (lower(username));
+
+-- Lookups by name go through lower() too, including the auth history.
+CREATE INDEX IF NOT EXISTS auth_events_lower_user_time ON auth_events (lower(username), created_at DESC);
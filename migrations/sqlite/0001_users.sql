@@ -0,0 +1,59 @@
+-- The SQLite schema for local development: the users, games, moves and
+-- elo_history tables of the Postgres migrations, minus the columns only the
+-- Postgres-only features use. SERIAL becomes AUTOINCREMENT, GREATEST becomes
+-- MAX, booleans are integers, game and move times are RFC 3339 text and the
+-- other timestamps Unix seconds.
+
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    wins INTEGER NOT NULL DEFAULT 0,
+    loses INTEGER NOT NULL DEFAULT 0,
+    draws INTEGER NOT NULL DEFAULT 0,
+    points INTEGER GENERATED ALWAYS AS (MAX(wins - loses, 0)) STORED,
+    token TEXT,
+    token_created_at INTEGER,
+    elo INTEGER NOT NULL DEFAULT 1200,
+    current_streak INTEGER NOT NULL DEFAULT 0,
+    best_streak INTEGER NOT NULL DEFAULT 0,
+    deactivated INTEGER NOT NULL DEFAULT 0,
+    guest INTEGER NOT NULL DEFAULT 0,
+    last_seen INTEGER NOT NULL DEFAULT (unixepoch()),
+    email TEXT
+);
+
+CREATE UNIQUE INDEX users_username_lower ON users (lower(username));
+
+CREATE UNIQUE INDEX users_email ON users (lower(email));
+
+CREATE TABLE elo_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    elo INTEGER NOT NULL,
+    recorded_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+CREATE TABLE games (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    player1_id INTEGER NOT NULL REFERENCES users(id),
+    player2_id INTEGER NOT NULL REFERENCES users(id),
+    winner_id INTEGER REFERENCES users(id),
+    board TEXT NOT NULL, -- JSON array of the cells
+    status TEXT NOT NULL,
+    ranked INTEGER NOT NULL,
+    handicap INTEGER NOT NULL DEFAULT 0,
+    started_at TEXT,
+    finished_at TEXT NOT NULL
+);
+
+CREATE TABLE moves (
+    game_id INTEGER NOT NULL REFERENCES games(id),
+    ply INTEGER NOT NULL,
+    player_id INTEGER NOT NULL REFERENCES users(id),
+    field INTEGER NOT NULL,
+    board INTEGER,
+    think_ms INTEGER NOT NULL,
+    played_at TEXT NOT NULL,
+    PRIMARY KEY (game_id, ply)
+);
@@ -0,0 +1,164 @@
+//! # Achievements
+//!
+//! Badges earned for milestones in ranked play, such as a first win or ten wins
+//! in a row.
+//!
+//! After every ranked game [`check_achievements`] looks at the player's updated
+//! stats and the finished game and returns every achievement whose condition
+//! holds. It is a pure function; the caller inserts the results into
+//! `user_achievements` and tells the player about the ones that are new.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use crate::database::{user_achievements, user_id_from_name};
+use crate::{db_error_status, AppMod};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Achievement {
+    FirstWin,
+    WinStreak10,
+    Veteran,
+    Speedster,
+    Flawless,
+}
+
+impl Achievement {
+    pub const ALL: [Achievement; 5] = [
+        Achievement::FirstWin,
+        Achievement::WinStreak10,
+        Achievement::Veteran,
+        Achievement::Speedster,
+        Achievement::Flawless,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Achievement::FirstWin => "first_win",
+            Achievement::WinStreak10 => "win_streak_10",
+            Achievement::Veteran => "veteran",
+            Achievement::Speedster => "speedster",
+            Achievement::Flawless => "flawless",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Achievement::FirstWin => "First Win",
+            Achievement::WinStreak10 => "On Fire",
+            Achievement::Veteran => "Veteran",
+            Achievement::Speedster => "Speedster",
+            Achievement::Flawless => "Flawless",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Achievement::FirstWin => "Win a ranked game",
+            Achievement::WinStreak10 => "Win 10 ranked games in a row",
+            Achievement::Veteran => "Play 100 ranked games",
+            Achievement::Speedster => "Win a standard game with only three marks on the board",
+            Achievement::Flawless => "Win an ultimate game without your opponent claiming a single board",
+        }
+    }
+}
+
+/// A player's standings after the game has been recorded.
+pub struct PlayerStats {
+    pub wins: i32,
+    pub current_streak: i32,
+    pub games_played: i64,
+}
+
+/// The finished game from one player's point of view.
+pub struct FinishedGame {
+    pub won: bool,
+    pub handicap: bool,
+    /// Own marks on a standard board; `None` for other variants.
+    pub marks_placed: Option<usize>,
+    /// Sub-boards claimed by the opponent in ultimate; `None` for other variants.
+    pub opponent_boards: Option<usize>,
+}
+
+pub fn check_achievements(stats: &PlayerStats, game: &FinishedGame) -> Vec<Achievement> {
+    Achievement::ALL
+        .into_iter()
+        .filter(|achievement| match achievement {
+            Achievement::FirstWin => game.won && stats.wins >= 1,
+            Achievement::WinStreak10 => stats.current_streak >= 10,
+            Achievement::Veteran => stats.games_played >= 100,
+            Achievement::Speedster => game.won && !game.handicap && game.marks_placed == Some(3),
+            Achievement::Flawless => game.won && game.opponent_boards == Some(0),
+        })
+        .collect()
+}
+
+pub async fn achievements(State(appmod): State<AppMod>, Path(username): Path<String>) -> impl IntoResponse {
+    let user_id = match user_id_from_name(appmod.pg(), &username).await {
+        Ok(Some(id)) => id,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => return Err(db_error_status(&e)),
+    };
+
+    user_achievements(appmod.pg(), user_id).await
+        .map(Json)
+        .map_err(|e| db_error_status(&e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(wins: i32, current_streak: i32, games_played: i64) -> PlayerStats {
+        PlayerStats { wins, current_streak, games_played }
+    }
+
+    /// A plain standard-board result with too many marks for Speedster.
+    fn game(won: bool) -> FinishedGame {
+        FinishedGame { won, handicap: false, marks_placed: Some(4), opponent_boards: None }
+    }
+
+    #[test]
+    fn first_win_needs_a_win() {
+        assert_eq!(check_achievements(&stats(1, 1, 1), &game(true)), [Achievement::FirstWin]);
+        assert!(check_achievements(&stats(0, 0, 1), &game(false)).is_empty());
+    }
+
+    #[test]
+    fn streak_of_ten() {
+        assert!(!check_achievements(&stats(9, 9, 9), &game(true)).contains(&Achievement::WinStreak10));
+        assert!(check_achievements(&stats(10, 10, 10), &game(true)).contains(&Achievement::WinStreak10));
+    }
+
+    #[test]
+    fn veteran_after_a_hundred_games() {
+        assert!(!check_achievements(&stats(0, 0, 99), &game(false)).contains(&Achievement::Veteran));
+        assert_eq!(check_achievements(&stats(0, 0, 100), &game(false)), [Achievement::Veteran]);
+    }
+
+    #[test]
+    fn speedster_wins_with_three_marks() {
+        let fast = FinishedGame { marks_placed: Some(3), ..game(true) };
+        assert!(check_achievements(&stats(1, 1, 1), &fast).contains(&Achievement::Speedster));
+
+        let given_marks = FinishedGame { handicap: true, ..fast };
+        assert!(!check_achievements(&stats(1, 1, 1), &given_marks).contains(&Achievement::Speedster));
+
+        let lost = FinishedGame { marks_placed: Some(3), ..game(false) };
+        assert!(check_achievements(&stats(0, 0, 1), &lost).is_empty());
+    }
+
+    #[test]
+    fn flawless_gives_up_no_board() {
+        let ultimate = |opponent_boards| FinishedGame { marks_placed: None, opponent_boards: Some(opponent_boards), ..game(true) };
+        assert!(check_achievements(&stats(1, 1, 1), &ultimate(0)).contains(&Achievement::Flawless));
+        assert!(!check_achievements(&stats(1, 1, 1), &ultimate(1)).contains(&Achievement::Flawless));
+    }
+}
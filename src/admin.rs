@@ -0,0 +1,138 @@
+//! # Admin Endpoints
+//!
+//! Operator-only HTTP handlers for intervening in a running server.
+//!
+//! Every request must carry `Authorization: Bearer <ADMIN_TOKEN>`, where
+//! `ADMIN_TOKEN` is read from the environment at startup. When the variable is
+//! unset the admin API is disabled and every call is answered with `403`.
+//! The token is checked in constant time, by comparing SHA-256 digests, so
+//! response timing says nothing about how much of a guess was right.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use subtle::ConstantTimeEq;
+use tracing::info;
+use crate::{bearer_token, db_error_status, AppMod, Limit};
+use crate::database::{auth_events, ban_ip, unban_ip};
+use crate::game::GameControl;
+
+pub fn is_admin(appmod: &AppMod, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(expected) = &appmod.config.admin_token else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+
+    match bearer_token(headers) {
+        Some(token) if bool::from(Sha256::digest(token).ct_eq(&Sha256::digest(expected))) => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+pub async fn end_game(State(appmod): State<AppMod>, headers: HeaderMap, Path(id): Path<u64>) -> StatusCode {
+    if let Err(status) = is_admin(&appmod, &headers) {
+        return status;
+    }
+
+    let control = appmod.games.lock().unwrap().get(&id).map(|handle| handle.control.clone());
+
+    match control {
+        Some(control) if control.send(GameControl::End).await.is_ok() => {
+            info!("Game {} was ended by an admin", id);
+            StatusCode::OK
+        }
+        _ => StatusCode::NOT_FOUND,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Announcement {
+    message: String,
+}
+
+pub async fn announce(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<Announcement>) -> StatusCode {
+    if let Err(status) = is_admin(&appmod, &headers) {
+        return status;
+    }
+
+    let receivers = appmod.announcements.send(payload.message).unwrap_or(0);
+    info!("Announcement delivered to {} connection(s)", receivers);
+    StatusCode::OK
+}
+
+#[derive(Deserialize)]
+pub struct Maintenance {
+    enabled: bool,
+}
+
+pub async fn set_maintenance(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<Maintenance>) -> impl IntoResponse {
+    if let Err(status) = is_admin(&appmod, &headers) {
+        return (status, Json(serde_json::json!({ "error": "UNAUTHORIZED" })));
+    }
+
+    appmod.maintenance.store(payload.enabled, Ordering::Relaxed);
+    info!("Maintenance mode {}", if payload.enabled { "enabled" } else { "disabled" });
+    (StatusCode::OK, Json(serde_json::json!({ "maintenance": payload.enabled })))
+}
+
+pub async fn auth_history(State(appmod): State<AppMod>, headers: HeaderMap, Path(username): Path<String>, Query(query): Query<Limit>) -> impl IntoResponse {
+    if let Err(status) = is_admin(&appmod, &headers) {
+        return (status, Json(Vec::new()));
+    }
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    match auth_events(appmod.pg(), &username, limit).await {
+        Ok(events) => (StatusCode::OK, Json(events)),
+        Err(e) => (db_error_status(&e), Json(Vec::new())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct IpBan {
+    ip: IpAddr,
+    reason: Option<String>,
+}
+
+pub async fn ban(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<IpBan>) -> StatusCode {
+    if let Err(status) = is_admin(&appmod, &headers) {
+        return status;
+    }
+
+    match ban_ip(appmod.pg(), &payload.ip.to_string(), payload.reason.as_deref()).await {
+        Ok(()) => {
+            appmod.ip_filter.ban(payload.ip);
+            info!("IP address {} was banned", payload.ip);
+            StatusCode::OK
+        }
+        Err(e) => db_error_status(&e),
+    }
+}
+
+pub async fn unban(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<IpBan>) -> StatusCode {
+    if let Err(status) = is_admin(&appmod, &headers) {
+        return status;
+    }
+
+    match unban_ip(appmod.pg(), &payload.ip.to_string()).await {
+        Ok(removed) => {
+            appmod.ip_filter.unban(payload.ip);
+            if removed {
+                info!("IP address {} was unbanned", payload.ip);
+                StatusCode::OK
+            } else {
+                StatusCode::NOT_FOUND
+            }
+        }
+        Err(e) => db_error_status(&e),
+    }
+}
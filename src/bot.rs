@@ -0,0 +1,122 @@
+//! # Bot Opponents
+//!
+//! Move selection for single-player games against the server.
+//!
+//! Two difficulty tiers are available:
+//!
+//! - [`Difficulty::Easy`] — plays a uniformly random legal move, for absolute beginners.
+//! - [`Difficulty::Hard`] — full minimax search; never loses.
+//!
+//! The bot always plays `X`, so the human moves first as `O`. Bots only know
+//! the standard rules; other variants and rule options are refused before a
+//! bot game starts.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use rand::seq::IndexedRandom;
+use serde::Deserialize;
+use crate::game::{check_winner, BoardOptions, Status};
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    Easy,
+    Hard,
+}
+
+impl Difficulty {
+    pub fn pick_move(self, board: &[BoardOptions; 9], symbol: BoardOptions) -> Option<usize> {
+        match self {
+            Difficulty::Easy => random_move(board),
+            Difficulty::Hard => minimax_move(board, symbol),
+        }
+    }
+}
+
+fn empty_cells(board: &[BoardOptions; 9]) -> Vec<usize> {
+    (0..9).filter(|&i| board[i] == BoardOptions::Null).collect()
+}
+
+pub fn random_move(board: &[BoardOptions; 9]) -> Option<usize> {
+    empty_cells(board).choose(&mut rand::rng()).copied()
+}
+
+pub fn minimax_move(board: &[BoardOptions; 9], symbol: BoardOptions) -> Option<usize> {
+    let mut board = *board;
+    let mut best: Option<(usize, i32)> = None;
+
+    for field in empty_cells(&board) {
+        board[field] = symbol;
+        let score = minimax(&mut board, symbol.opposite(), symbol, 1);
+        board[field] = BoardOptions::Null;
+
+        if best.is_none_or(|(_, best_score)| score > best_score) {
+            best = Some((field, score));
+        }
+    }
+
+    best.map(|(field, _)| field)
+}
+
+fn minimax(board: &mut [BoardOptions; 9], turn: BoardOptions, me: BoardOptions, depth: i32) -> i32 {
+    let winner = match check_winner(board) {
+        Status::Player1Won => BoardOptions::O,
+        Status::Player2Won => BoardOptions::X,
+        Status::Draw => return 0,
+        _ => BoardOptions::Null,
+    };
+
+    if winner != BoardOptions::Null {
+        return if winner == me { 10 - depth } else { depth - 10 };
+    }
+
+    let scores = empty_cells(board).into_iter().map(|field| {
+        board[field] = turn;
+        let score = minimax(board, turn.opposite(), me, depth + 1);
+        board[field] = BoardOptions::Null;
+        score
+    });
+
+    if turn == me {
+        scores.max().unwrap_or(0)
+    } else {
+        scores.min().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BoardOptions::{Null as N, O, X};
+
+    #[test]
+    fn random_move_only_picks_empty_cells() {
+        let board = [O, N, X, N, O, X, N, X, N];
+        for _ in 0..500 {
+            let field = random_move(&board).unwrap();
+            assert_eq!(board[field], N, "picked taken cell {}", field);
+        }
+    }
+
+    #[test]
+    fn random_move_on_a_full_board_is_none() {
+        assert_eq!(random_move(&[O, X, O, O, X, X, X, O, O]), None);
+    }
+
+    #[test]
+    fn minimax_takes_an_immediate_win() {
+        // X completes the middle row rather than blocking O's top row.
+        let board = [O, O, N, X, X, N, O, N, N];
+        assert_eq!(minimax_move(&board, X), Some(5));
+    }
+
+    #[test]
+    fn minimax_blocks_an_immediate_loss() {
+        let board = [O, O, N, X, N, N, N, N, N];
+        assert_eq!(minimax_move(&board, X), Some(2));
+    }
+}
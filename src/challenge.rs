@@ -0,0 +1,149 @@
+//! # Challenges
+//!
+//! Friendly games against a chosen friend instead of whoever is in the queue.
+//!
+//! `POST /api/challenge/{friend}` leaves a pending challenge for an accepted
+//! friend. If the friend is waiting in a queue or playing, they are told right
+//! away with a `{"challenge": {...}}` message; otherwise they get it the next
+//! time they connect to `/api/search`. To play, both players connect to
+//! `/api/search` with `"challenge": "<the other's username>"`. Whoever comes
+//! first waits for the other, and the two then play a casual game with the rules
+//! given when the challenge was made. A challenge nobody takes up within
+//! `CHALLENGE_TIMEOUT_SECS` is dropped.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tracing::info;
+use crate::database::{are_friends, is_blocked, username_from_id};
+use crate::game::{Player, Rules};
+use crate::protocol::{ChallengeInvite, ServerMessage};
+use crate::{db_error_status, other_user, AppMod};
+
+struct Challenge {
+    from: i32,
+    from_name: String,
+    to: i32,
+    rules: Rules,
+    expires_at: Instant,
+    /// The side that connected first, waiting for the other one.
+    waiting: Option<(i32, oneshot::Sender<Player>)>,
+}
+
+impl Challenge {
+    fn between(&self, a: i32, b: i32) -> bool {
+        (self.from == a && self.to == b) || (self.from == b && self.to == a)
+    }
+}
+
+pub enum Join {
+    NoChallenge,
+    /// The other player is already waiting; send them this player.
+    Play(oneshot::Sender<Player>),
+    /// This player waits until the deadline for the other to connect.
+    Wait(Rules, Instant),
+}
+
+#[derive(Default)]
+pub struct Challenges {
+    pending: Vec<Challenge>,
+}
+
+impl Challenges {
+    fn purge(&mut self) {
+        let now = Instant::now();
+        self.pending.retain(|challenge| challenge.expires_at > now);
+    }
+
+    /// Replaces any earlier challenge between the two players.
+    fn create(&mut self, from: i32, from_name: String, to: i32, rules: Rules, expires_at: Instant) {
+        self.purge();
+        self.pending.retain(|challenge| !challenge.between(from, to));
+        self.pending.push(Challenge { from, from_name, to, rules, expires_at, waiting: None });
+    }
+
+    /// Challengers and rules of the challenges waiting for `user_id` to accept.
+    pub fn incoming(&mut self, user_id: i32) -> Vec<(String, Rules, Instant)> {
+        self.purge();
+        self.pending
+            .iter()
+            .filter(|challenge| challenge.to == user_id)
+            .map(|challenge| (challenge.from_name.clone(), challenge.rules, challenge.expires_at))
+            .collect()
+    }
+
+    pub fn join(&mut self, player_id: i32, opponent_id: i32, sender: oneshot::Sender<Player>) -> Join {
+        self.purge();
+        let Some(index) = self.pending.iter().position(|challenge| challenge.between(player_id, opponent_id)) else {
+            return Join::NoChallenge;
+        };
+
+        match self.pending[index].waiting.take() {
+            Some((waiting_id, waiting)) if waiting_id == opponent_id => {
+                self.pending.remove(index);
+                Join::Play(waiting)
+            }
+            _ => {
+                let challenge = &mut self.pending[index];
+                challenge.waiting = Some((player_id, sender));
+                Join::Wait(challenge.rules, challenge.expires_at)
+            }
+        }
+    }
+
+    /// Stops `player_id` waiting; `false` if the opponent has already taken them.
+    pub fn leave(&mut self, player_id: i32, opponent_id: i32) -> bool {
+        self.pending
+            .iter_mut()
+            .find(|challenge| challenge.between(player_id, opponent_id))
+            .and_then(|challenge| challenge.waiting.take_if(|(waiting_id, _)| *waiting_id == player_id))
+            .is_some()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewChallenge {
+    #[serde(flatten)]
+    rules: Rules,
+}
+
+pub async fn challenge(State(appmod): State<AppMod>, headers: HeaderMap, Path(friend): Path<String>, payload: Option<Json<NewChallenge>>) -> impl IntoResponse {
+    let (me, other) = match other_user(&appmod, &headers, &friend).await {
+        Ok(ids) => ids,
+        Err(status) => return (status, Json(None)),
+    };
+
+    let allowed = match (are_friends(appmod.pg(), me, other).await, is_blocked(appmod.pg(), me, other).await) {
+        (Ok(friends), Ok(blocked)) => friends && !blocked,
+        (Err(e), _) | (_, Err(e)) => return (db_error_status(&e), Json(None)),
+    };
+    if !allowed {
+        return (StatusCode::FORBIDDEN, Json(Some(serde_json::json!({ "error": "NOT_FRIENDS" }))));
+    }
+
+    let my_name = match username_from_id(appmod.pg(), me).await {
+        Ok(name) => name,
+        Err(e) => return (db_error_status(&e), Json(None)),
+    };
+
+    let rules = payload.map(|Json(body)| body.rules).unwrap_or_default();
+    let expires_in = appmod.config.challenge_timeout;
+    appmod.challenges.lock().unwrap().create(me, my_name.clone(), other, rules, Instant::now() + expires_in);
+    info!("{} challenged {}", my_name, friend);
+
+    appmod.presence.notify(other, ServerMessage::Challenge {
+        challenge: ChallengeInvite { from: my_name, rules, expires_in_secs: expires_in.as_secs() },
+    });
+
+    (StatusCode::CREATED, Json(Some(serde_json::json!({ "to": friend, "expires_in_secs": expires_in.as_secs() }))))
+}
@@ -12,14 +12,127 @@
 //!     password TEXT    NOT NULL,           -- bcrypt hash
 //!     wins     INTEGER DEFAULT 0,
 //!     loses    INTEGER DEFAULT 0,
+//!     draws    INTEGER DEFAULT 0,          -- ranked games only, like wins and loses
 //!     points   INTEGER GENERATED ALWAYS AS (GREATEST(wins - loses, 0)) STORED,
-//!     token    TEXT                        -- UUID session token, refreshed on every login
+//!     token    TEXT,                       -- UUID session token, refreshed on every login
+//!     token_created_at TIMESTAMPTZ,        -- the token expires TOKEN_TTL_SECS later
+//!     elo      INTEGER DEFAULT 1200,
+//!     current_streak INTEGER NOT NULL DEFAULT 0,  -- ranked wins in a row
+//!     best_streak    INTEGER NOT NULL DEFAULT 0,
+//!     totp_secret  TEXT,                      -- sealed with TOTP_KEY, see crate::two_factor
+//!     totp_enabled BOOLEAN NOT NULL DEFAULT FALSE,
+//!     deactivated BOOLEAN NOT NULL DEFAULT FALSE,  -- hidden until the next login
+//!     guest    BOOLEAN NOT NULL DEFAULT FALSE,  -- created by /api/guest, no password
+//!     last_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+//!     email    TEXT,                       -- optional, unique ignoring case
+//!     email_verified BOOLEAN NOT NULL DEFAULT FALSE
+//! );
+//!
+//! CREATE TABLE elo_history (
+//!     id          BIGSERIAL   PRIMARY KEY,
+//!     user_id     INTEGER     NOT NULL REFERENCES users(id),
+//!     elo         INTEGER     NOT NULL,
+//!     recorded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+//! );
+//!
+//! CREATE TABLE games (
+//!     id          BIGSERIAL   PRIMARY KEY,
+//!     player1_id  INTEGER     NOT NULL REFERENCES users(id),  -- played O
+//!     player2_id  INTEGER     NOT NULL REFERENCES users(id),  -- played X
+//!     winner_id   INTEGER     REFERENCES users(id),           -- NULL for a draw
+//!     board       TEXT[]      NOT NULL,                       -- "X" / "O" / "" per cell
+//!     status      TEXT        NOT NULL,
+//!     ranked      BOOLEAN     NOT NULL,
+//!     started_at  TIMESTAMPTZ,                            -- both players ready
+//!     finished_at TIMESTAMPTZ NOT NULL DEFAULT now()
+//! );
+//!
+//! CREATE TABLE moves (
+//!     game_id   BIGINT      NOT NULL REFERENCES games(id),
+//!     ply       INTEGER     NOT NULL,                 -- 1 for the first move
+//!     player_id INTEGER     NOT NULL REFERENCES users(id),
+//!     field     INTEGER     NOT NULL,
+//!     board     INTEGER,                              -- sub-board, ultimate only
+//!     think_ms  INTEGER     NOT NULL,                 -- since the turn started
+//!     played_at TIMESTAMPTZ NOT NULL,
+//!     PRIMARY KEY (game_id, ply)
+//! );
+//!
+//! CREATE TABLE auth_events (
+//!     id         BIGSERIAL   PRIMARY KEY,
+//!     username   TEXT        NOT NULL,        -- as typed, may not exist
+//!     event      TEXT        NOT NULL,        -- see AuthEvent
+//!     ip         TEXT        NOT NULL,
+//!     created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+//! );
+//!
+//! CREATE TABLE recovery_codes (
+//!     user_id   INTEGER NOT NULL REFERENCES users(id),
+//!     code_hash TEXT    NOT NULL,          -- SHA-256, deleted once used
+//!     PRIMARY KEY (user_id, code_hash)
+//! );
+//!
+//! CREATE TABLE password_resets (
+//!     token_hash TEXT        PRIMARY KEY,     -- SHA-256, deleted once used
+//!     user_id    INTEGER     NOT NULL REFERENCES users(id),
+//!     expires_at TIMESTAMPTZ NOT NULL
+//! );
+//!
+//! CREATE TABLE email_verifications (
+//!     token_hash TEXT        PRIMARY KEY,     -- SHA-256, deleted once used
+//!     user_id    INTEGER     NOT NULL REFERENCES users(id),
+//!     email      TEXT        NOT NULL,        -- the address the token confirms
+//!     expires_at TIMESTAMPTZ NOT NULL
+//! );
+//!
+//! CREATE TABLE banned_ips (
+//!     ip        TEXT        PRIMARY KEY,
+//!     reason    TEXT,
+//!     banned_at TIMESTAMPTZ NOT NULL DEFAULT now()
+//! );
+//!
+//! CREATE TABLE blocks (
+//!     blocker_id INTEGER NOT NULL REFERENCES users(id),
+//!     blocked_id INTEGER NOT NULL REFERENCES users(id),
+//!     PRIMARY KEY (blocker_id, blocked_id)
+//! );
+//!
+//! CREATE TABLE friends (
+//!     requester_id INTEGER     NOT NULL REFERENCES users(id),
+//!     addressee_id INTEGER     NOT NULL REFERENCES users(id),
+//!     accepted     BOOLEAN     NOT NULL DEFAULT FALSE,  -- pending until the addressee accepts
+//!     created_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+//!     PRIMARY KEY (requester_id, addressee_id)
 //! );
 //! ```
 //!
+//! The schema is versioned: each change is a numbered SQL file in `migrations/`,
+//! embedded at compile time and applied in order by `sqlx::migrate!` when the
+//! server starts. SQLx records what has run in `_sqlx_migrations`, so a schema
+//! change means a new file — never an edit to one that has shipped. The first
+//! migration only uses `IF NOT EXISTS`, so databases created before migrations
+//! existed are picked up as they are.
+//!
+//! Finished games are written by [`save_game`] in a single transaction: the game
+//! row, its move log and, for ranked games, the win and the loss (or both draws) and both rating changes commit
+//! together or not at all.
+//!
+//! The same transaction keeps the win streak columns up to date: a win extends
+//! `current_streak` (and `best_streak` if it is beaten), a loss resets it, and a
+//! draw leaves it as it was.
+//!
+//! Every rating change made by [`update_elo`] appends a row to `elo_history`
+//! within the same statement, so the series can be charted over time.
+//!
 //! Passwords are hashed with bcrypt ([`bcrypt`] crate) before storage and are
 //! never stored or logged in plain text. Session tokens are random UUIDs generated
-//! with [`uuid::Uuid::new_v4`] and rotated on every successful login.
+//! by [`generate_token`] — UUID v4 unless `TOKEN_BYTES` asks for longer opaque
+//! tokens — and rotated on every successful login.
+//!
+//! Guest accounts from [`create_guest`] have no password and are left out of the
+//! leaderboard; [`expire_guests`] cleans them up once they go idle.
+//! Deactivated accounts ([`deactivate_user`]) are hidden the same way but keep
+//! their name and data, and come back on the next successful login.
 //!
 //! ## Author
 //! Marcel Gruszecki
@@ -27,141 +140,1089 @@
 //! ## License
 //! MIT — see `LICENSE` in the repository root.
 
-use sqlx::postgres::PgPoolOptions;
-use std::env;
-use sqlx::{Executor, FromRow, Pool, Postgres};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use std::str::FromStr;
+use sqlx::{FromRow, PgConnection, Pool, Postgres};
 use crate::Login;
-use bcrypt::{DEFAULT_COST, hash, verify};
+use crate::config::Config;
+use crate::achievements::{Achievement, PlayerStats};
+use crate::leaderboard::RankingStrategy;
+use crate::tournament::BRACKET;
+use bcrypt::{hash, verify};
+use chrono::{DateTime, Utc};
 use serde::Serialize;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rand::TryRngCore;
+
+const ELO_K: f64 = 32.0;
+
+/// Connects the pool and brings the schema up to date. Fails if `DATABASE_URL`
+/// can't be parsed, the server can't be reached or a migration fails.
+pub async fn connect_to_database(config: &Config) -> Result<Pool<Postgres>, sqlx::Error> {
+    let timeout = config.db_query_timeout;
+
+    let mut options = PgConnectOptions::from_str(&config.database_url)?;
+    let mut pool_options = PgPoolOptions::new().max_connections(config.db_pool_size);
+    if !timeout.is_zero() {
+        let millis = timeout.as_millis().to_string();
+        options = options.options([("statement_timeout", millis.as_str())]);
+        pool_options = pool_options.acquire_timeout(timeout);
+    }
+
+    let pool = pool_options.connect_with(options).await?;
 
-pub async fn connect_to_database() -> Pool<Postgres> {
-    let db_url = env::var("DATABASE_URL")
-        .expect("DATABASE_URL must be set");
+    database_init(pool.clone()).await?;
+
+    Ok(pool)
+}
 
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&db_url)
+/// `true` for errors caused by `DB_QUERY_TIMEOUT_SECS`: a statement cancelled by
+/// Postgres' `statement_timeout` or no free pool connection in time.
+pub fn is_timeout(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Database(db) => db.code().as_deref() == Some("57014"),
+        _ => false,
+    }
+}
+
+async fn database_init(pool: Pool<Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::migrate!().run(&pool).await?;
+
+    for achievement in Achievement::ALL {
+        sqlx::query(
+            "INSERT INTO achievements (code, name, description) VALUES ($1, $2, $3)
+             ON CONFLICT (code) DO UPDATE SET name = EXCLUDED.name, description = EXCLUDED.description"
+        )
+        .bind(achievement.code())
+        .bind(achievement.name())
+        .bind(achievement.description())
+        .execute(&pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// `false` if the username is already taken.
+pub async fn create_new_user(pool: Pool<Postgres>, log: &Login, bcrypt_cost: u32) -> Result<bool, sqlx::Error> {
+    if does_user_exist(pool.clone(), log).await? {
+        return Ok(false);
+    }
+
+    let hashed_password = hash_password(&log.password, bcrypt_cost)?;
+
+    // Someone may have taken the name, in any case, since the check above.
+    let inserted = sqlx::query("INSERT INTO users (username, password, email) VALUES ($1, $2, $3)")
+        .bind(&log.name)
+        .bind(hashed_password)
+        .bind(&log.email)
+        .execute(&pool)
+        .await;
+
+    match inserted {
+        Ok(_) => Ok(true),
+        Err(sqlx::Error::Database(e)) if is_username_clash(e.as_ref()) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// A unique violation on the username, as opposed to the email address.
+fn is_username_clash(e: &dyn sqlx::error::DatabaseError) -> bool {
+    e.is_unique_violation() && matches!(e.constraint(), Some("users_username_key" | "users_username_lower"))
+}
+
+/// Ignores case, so "Alice" counts as taken once "alice" exists.
+pub async fn does_user_exist(pool: Pool<Postgres>, log: &Login) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE lower(username) = lower($1))")
+        .bind(&log.name)
+        .fetch_one(&pool)
         .await
-        .expect("Connecting to database failed.");
+}
 
-    database_init(pool.clone()).await;
+/// bcrypt only fails for a cost outside 4–31, which the config already rules
+/// out; the error is passed on like a query error all the same.
+fn hash_password(password: &str, bcrypt_cost: u32) -> Result<String, sqlx::Error> {
+    hash(password, bcrypt_cost).map_err(|e| sqlx::Error::Encode(Box::new(e)))
+}
 
-    pool
+pub async fn is_email_taken(pool: Pool<Postgres>, email: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE lower(email) = lower($1))")
+        .bind(email)
+        .fetch_one(&pool)
+        .await
 }
 
-async fn database_init(pool: Pool<Postgres>) {
-    pool.execute(sqlx::query(
-        "
-        CREATE TABLE IF NOT EXISTS users (
-            id SERIAL PRIMARY KEY,
-            username TEXT NOT NULL UNIQUE,
-            password TEXT NOT NULL,
-            wins INTEGER DEFAULT 0,
-            loses INTEGER DEFAULT 0,
-            points INTEGER GENERATED ALWAYS AS (GREATEST(wins - loses, 0)) STORED,
-            token TEXT
+/// Sets or changes the account's address, which then needs verifying again.
+/// `false` if another account already uses it.
+pub async fn set_email(pool: Pool<Postgres>, id: i32, email: &str) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let updated = sqlx::query(
+        "UPDATE users SET email = $1, email_verified = FALSE
+         WHERE id = $2
+           AND NOT EXISTS (SELECT 1 FROM users WHERE lower(email) = lower($1) AND id <> $2)"
     )
-            "
-    )).await.expect("Database failed in database_init.");
+        .bind(email)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
 
+    sqlx::query("DELETE FROM email_verifications WHERE user_id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(updated > 0)
 }
 
-pub async fn create_new_user(pool: Pool<Postgres>, log: &Login) -> bool {
-    let does_exist: bool = does_user_exist(pool.clone(), &log).await;
+/// Stores a verification token for the account's current address, replacing any
+/// earlier one, and clears out expired tokens. Returns the address, or `None` if
+/// there is none or it is already verified.
+pub async fn create_email_verification(pool: Pool<Postgres>, id: i32, token_hash: &str, ttl_secs: f64) -> Result<Option<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
 
-    if !does_exist {
-        let hashed_password = hash(&log.password, DEFAULT_COST).expect("Password hashing error.");
+    sqlx::query("DELETE FROM email_verifications WHERE expires_at <= now() OR user_id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
 
-        sqlx::query("INSERT INTO users (username, password) VALUES ($1, $2)")
-            .bind(&log.name)
-            .bind(hashed_password)
-            .execute(&pool)
-            .await.expect("Inserting user error.");
+    let email = sqlx::query_scalar(
+        "INSERT INTO email_verifications (token_hash, user_id, email, expires_at)
+         SELECT $1, id, email, now() + make_interval(secs => $3)
+         FROM users
+         WHERE id = $2 AND email IS NOT NULL AND NOT email_verified
+         RETURNING email"
+    )
+        .bind(token_hash)
+        .bind(id)
+        .bind(ttl_secs)
+        .fetch_optional(&mut *tx)
+        .await?;
 
-        return true
-    }
+    tx.commit().await?;
+    Ok(email)
+}
+
+/// Consumes a verification token and marks the address it was issued for as
+/// verified. Returns the username; `None` if the token is unknown, used or
+/// expired.
+pub async fn verify_email(pool: Pool<Postgres>, token_hash: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "WITH used AS (
+             DELETE FROM email_verifications WHERE token_hash = $1 AND expires_at > now()
+             RETURNING user_id, email
+         )
+         UPDATE users u SET email_verified = TRUE
+         FROM used
+         WHERE u.id = used.user_id AND u.email = used.email
+         RETURNING u.username"
+    )
+    .bind(token_hash)
+    .fetch_optional(&pool)
+    .await
+}
 
-    false
+pub async fn username_from_verified_email(pool: Pool<Postgres>, email: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT username FROM users WHERE lower(email) = lower($1) AND email_verified")
+        .bind(email)
+        .fetch_optional(&pool)
+        .await
 }
 
-pub async fn does_user_exist(pool: Pool<Postgres>, log: &Login) -> bool {
-    sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE username = $1)")
+pub async fn verify_password(pool: Pool<Postgres>, log: &Login) -> Result<bool, sqlx::Error> {
+    // Guests have no password, so they can never log in by name.
+    let result: Option<String> = sqlx::query_scalar("SELECT password FROM users WHERE lower(username) = lower($1) AND NOT guest")
         .bind(&log.name)
+        .fetch_optional(&pool)
+        .await?;
+
+    Ok(result.is_some_and(|result| verify(&log.password, &result).unwrap_or(false)))
+}
+
+#[derive(Serialize)]
+pub struct Guest {
+    pub username: String,
+    pub token: String,
+}
+
+pub async fn create_guest(pool: Pool<Postgres>, format: TokenFormat) -> Result<Guest, sqlx::Error> {
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    let guest = Guest {
+        username: format!("guest-{}", &suffix[..8]),
+        token: generate_token(format),
+    };
+
+    sqlx::query("INSERT INTO users (username, password, token, token_created_at, guest) VALUES ($1, '', $2, now(), TRUE)")
+        .bind(&guest.username)
+        .bind(&guest.token)
+        .execute(&pool)
+        .await?;
+
+    Ok(guest)
+}
+
+/// Hides the account from leaderboards and ends its session. Logging in again
+/// reactivates it; nothing is deleted.
+pub async fn deactivate_user(pool: Pool<Postgres>, id: i32) -> Result<String, sqlx::Error> {
+    sqlx::query_scalar("UPDATE users SET deactivated = TRUE, token = NULL WHERE id = $1 RETURNING username")
+        .bind(id)
         .fetch_one(&pool)
         .await
-        .expect("Checking if user exists error.")
 }
 
-pub async fn check_password(pool: Pool<Postgres>, log: &Login) -> (bool, String) {
-    if does_user_exist(pool.clone(), &log).await {
-        let result: String = sqlx::query_scalar("SELECT password FROM users WHERE username = $1")
-            .bind(&log.name)
-            .fetch_one(&pool)
-            .await
-            .expect("Error in password checking.");
-        let token = new_token(pool.clone(), &log).await;
-        (verify(&log.password, &result).expect("Hash verify error."), token)
-    } else {
-        (false, String::from(""))
+#[derive(Clone, Copy, Debug)]
+pub enum AuthEvent {
+    LoginSuccess,
+    LoginFailure,
+    Logout,
+    Register,
+    Guest,
+    Deactivate,
+    PasswordReset,
+}
+
+impl AuthEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuthEvent::LoginSuccess => "login_success",
+            AuthEvent::LoginFailure => "login_failure",
+            AuthEvent::Logout => "logout",
+            AuthEvent::Register => "register",
+            AuthEvent::Guest => "guest",
+            AuthEvent::Deactivate => "deactivate",
+            AuthEvent::PasswordReset => "password_reset",
+        }
+    }
+}
+
+pub async fn log_auth_event(pool: Pool<Postgres>, username: &str, event: AuthEvent, ip: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO auth_events (username, event, ip) VALUES ($1, $2, $3)")
+        .bind(username)
+        .bind(event.as_str())
+        .bind(ip)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
+
+#[derive(Serialize, FromRow)]
+pub struct AuthEventRecord {
+    pub event: String,
+    pub ip: String,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn auth_events(pool: Pool<Postgres>, username: &str, limit: i64) -> Result<Vec<AuthEventRecord>, sqlx::Error> {
+    sqlx::query_as::<_, AuthEventRecord>(
+        "SELECT event, ip, created_at
+         FROM auth_events
+         WHERE lower(username) = lower($1)
+         ORDER BY created_at DESC
+         LIMIT $2"
+    )
+    .bind(username)
+    .bind(limit)
+    .fetch_all(&pool)
+    .await
+}
+
+pub async fn banned_ips(pool: Pool<Postgres>) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT ip FROM banned_ips")
+        .fetch_all(&pool)
+        .await
+}
+
+/// Bans `ip`, or updates the reason if it is already banned.
+pub async fn ban_ip(pool: Pool<Postgres>, ip: &str, reason: Option<&str>) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO banned_ips (ip, reason) VALUES ($1, $2) ON CONFLICT (ip) DO UPDATE SET reason = EXCLUDED.reason")
+        .bind(ip)
+        .bind(reason)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
+
+/// `false` if `ip` wasn't banned.
+pub async fn unban_ip(pool: Pool<Postgres>, ip: &str) -> Result<bool, sqlx::Error> {
+    let deleted = sqlx::query("DELETE FROM banned_ips WHERE ip = $1")
+        .bind(ip)
+        .execute(&pool)
+        .await?
+        .rows_affected();
+    Ok(deleted > 0)
+}
+
+#[derive(FromRow)]
+pub struct TwoFactor {
+    pub id: i32,
+    pub username: String,
+    pub secret: Option<String>,
+    pub enabled: bool,
+}
+
+pub async fn two_factor_by_id(pool: Pool<Postgres>, id: i32) -> Result<TwoFactor, sqlx::Error> {
+    sqlx::query_as::<_, TwoFactor>(
+        "SELECT id, username, totp_secret AS secret, totp_enabled AS enabled FROM users WHERE id = $1"
+    )
+    .bind(id)
+    .fetch_one(&pool)
+    .await
+}
+
+pub async fn two_factor_by_name(pool: Pool<Postgres>, username: &str) -> Result<Option<TwoFactor>, sqlx::Error> {
+    sqlx::query_as::<_, TwoFactor>(
+        "SELECT id, username, totp_secret AS secret, totp_enabled AS enabled FROM users WHERE lower(username) = lower($1)"
+    )
+    .bind(username)
+    .fetch_optional(&pool)
+    .await
+}
+
+pub async fn set_totp_secret(pool: Pool<Postgres>, id: i32, sealed: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET totp_secret = $1 WHERE id = $2 AND NOT totp_enabled")
+        .bind(sealed)
+        .bind(id)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
+
+/// Switches 2FA on and replaces any previous recovery codes.
+pub async fn enable_two_factor(pool: Pool<Postgres>, id: i32, code_hashes: &[String]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("UPDATE users SET totp_enabled = TRUE WHERE id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) SELECT $1, UNNEST($2::TEXT[])")
+        .bind(id)
+        .bind(code_hashes)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+/// Consumes a recovery code; `false` if it doesn't exist or was already used.
+pub async fn use_recovery_code(pool: Pool<Postgres>, id: i32, code_hash: &str) -> Result<bool, sqlx::Error> {
+    let deleted = sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1 AND code_hash = $2")
+        .bind(id)
+        .bind(code_hash)
+        .execute(&pool)
+        .await?
+        .rows_affected();
+    Ok(deleted > 0)
+}
+
+/// Stores a reset token for `username`, replacing any earlier one, and clears out
+/// expired tokens. `false` if there is no such account; guests have no password
+/// to reset.
+pub async fn create_password_reset(pool: Pool<Postgres>, username: &str, token_hash: &str, ttl_secs: f64) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        "DELETE FROM password_resets
+         WHERE expires_at <= now()
+            OR user_id = (SELECT id FROM users WHERE lower(username) = lower($1))"
+    )
+        .bind(username)
+        .execute(&mut *tx)
+        .await?;
+
+    let created = sqlx::query(
+        "INSERT INTO password_resets (token_hash, user_id, expires_at)
+         SELECT $1, id, now() + make_interval(secs => $3) FROM users WHERE lower(username) = lower($2) AND NOT guest"
+    )
+        .bind(token_hash)
+        .bind(username)
+        .bind(ttl_secs)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+    tx.commit().await?;
+    Ok(created > 0)
+}
+
+/// Consumes a reset token and returns the account it belongs to; `None` if it
+/// doesn't exist, was already used or has expired.
+pub async fn use_password_reset(pool: Pool<Postgres>, token_hash: &str) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar("DELETE FROM password_resets WHERE token_hash = $1 AND expires_at > now() RETURNING user_id")
+        .bind(token_hash)
+        .fetch_optional(&pool)
+        .await
+}
+
+/// Sets a new password and logs the account out everywhere. Returns the username.
+pub async fn update_password(pool: Pool<Postgres>, id: i32, password: &str, bcrypt_cost: u32) -> Result<String, sqlx::Error> {
+    let hashed_password = hash_password(password, bcrypt_cost)?;
+
+    let mut tx = pool.begin().await?;
+
+    let username = sqlx::query_scalar("UPDATE users SET password = $1, token = NULL WHERE id = $2 RETURNING username")
+        .bind(hashed_password)
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM password_resets WHERE user_id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(username)
+}
+
+pub async fn mark_seen(pool: Pool<Postgres>, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET last_seen = now() WHERE id = $1")
+        .bind(id)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
+
+/// Removes guests that have been idle for longer than `ttl_secs`. Guests that
+/// took part in a recorded game or a tournament are kept for the history, but
+/// their token is revoked so the session cannot be resumed.
+pub async fn expire_guests(pool: Pool<Postgres>, ttl_secs: i64) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let idle: Vec<i32> = sqlx::query_scalar("SELECT id FROM users WHERE guest AND last_seen < now() - make_interval(secs => $1) FOR UPDATE")
+        .bind(ttl_secs as f64)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    // Everything else that points at a guest goes with them.
+    for dependents in [
+        "DELETE FROM blocks WHERE blocker_id = ANY($1) OR blocked_id = ANY($1)",
+        "DELETE FROM friends WHERE requester_id = ANY($1) OR addressee_id = ANY($1)",
+        "DELETE FROM recovery_codes WHERE user_id = ANY($1)",
+        "DELETE FROM password_resets WHERE user_id = ANY($1)",
+        "DELETE FROM email_verifications WHERE user_id = ANY($1)",
+    ] {
+        sqlx::query(dependents).bind(&idle).execute(&mut *tx).await?;
+    }
+
+    let deleted = sqlx::query(
+        "DELETE FROM users u
+         WHERE id = ANY($1)
+           AND NOT EXISTS (SELECT 1 FROM games WHERE player1_id = u.id OR player2_id = u.id)
+           AND NOT EXISTS (SELECT 1 FROM tournament_matches WHERE player1_id = u.id OR player2_id = u.id)"
+    )
+        .bind(&idle)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+    sqlx::query("UPDATE users SET token = NULL WHERE id = ANY($1)")
+        .bind(&idle)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(deleted)
+}
+
+pub const TOKEN_BYTES_RANGE: std::ops::RangeInclusive<usize> = 16..=128;
+
+#[derive(Clone, Copy, Debug)]
+pub enum TokenFormat {
+    Uuid,
+    Random { bytes: usize },
+}
+
+/// UUID v4 by default; with `TOKEN_BYTES` set, that many bytes from the OS RNG,
+/// base64url-encoded without padding.
+pub fn generate_token(format: TokenFormat) -> String {
+    match format {
+        TokenFormat::Uuid => uuid::Uuid::new_v4().to_string(),
+        TokenFormat::Random { bytes } => {
+            let mut buffer = vec![0u8; bytes];
+            rand::rngs::OsRng.try_fill_bytes(&mut buffer).expect("OS random number generator error.");
+            URL_SAFE_NO_PAD.encode(buffer)
+        }
     }
 }
 
-async fn new_token(pool: Pool<Postgres>, log: &Login) -> String {
-    let token = uuid::Uuid::new_v4().to_string();
-    sqlx::query("UPDATE users SET token = $1 WHERE username = $2")
+pub async fn new_token(pool: Pool<Postgres>, log: &Login, format: TokenFormat) -> Result<String, sqlx::Error> {
+    let token = generate_token(format);
+    sqlx::query("UPDATE users SET token = $1, token_created_at = now(), deactivated = FALSE WHERE lower(username) = lower($2)")
         .bind(&token)
         .bind(&log.name)
         .execute(&pool)
+        .await?;
+    Ok(token)
+}
+
+/// Ends the session; returns the username, or `None` if no one holds the token.
+pub async fn clear_token(pool: Pool<Postgres>, token: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("UPDATE users SET token = NULL WHERE token = $1 RETURNING username")
+        .bind(token)
+        .fetch_optional(&pool)
         .await
-        .expect("New_token function error");
-    token
 }
 
-pub async fn does_token_exists(pool: Pool<Postgres>, token: &str) -> bool {
-    sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE token = $1)")
+/// Matches the session token bound as `$1` while it is younger than the TTL
+/// bound as `$2`, in seconds. A TTL of zero never expires; an expired token is
+/// treated as if it didn't exist.
+const LIVE_TOKEN: &str = "token = $1 AND ($2 = 0 OR token_created_at > now() - make_interval(secs => $2))";
+
+/// Id and username of the token's owner; `None` once the token has been
+/// replaced, revoked or has expired.
+pub async fn player_from_token(pool: Pool<Postgres>, token: &str, ttl_secs: f64) -> Result<Option<(i32, String)>, sqlx::Error> {
+    sqlx::query_as(&format!("SELECT id, username FROM users WHERE {LIVE_TOKEN}"))
         .bind(token)
-        .fetch_one(&pool)
+        .bind(ttl_secs)
+        .fetch_optional(&pool)
         .await
-        .expect("Checking if token exists error.")
 }
 
-pub async fn player_from_token(pool: Pool<Postgres>, token: &str) -> (i32, String) {
-    let username: String = sqlx::query_scalar("SELECT username FROM users WHERE token = $1")
-        .bind(&token)
+pub struct GameRecord {
+    pub player1_id: i32,
+    pub player2_id: i32,
+    pub winner_id: Option<i32>,
+    pub board: Vec<String>,
+    pub status: String,
+    pub ranked: bool,
+    pub handicap: bool,
+    /// Whether a ranked result moves Elo. Wins, losses and draws are counted either way.
+    pub rated: bool,
+    /// When both players were ready; `None` if the game never got that far.
+    pub started_at: Option<DateTime<Utc>>,
+    /// `None` for now; only seeded games are back-dated.
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Every accepted move, in the order it was played.
+    pub moves: Vec<MoveRecord>,
+}
+
+pub struct MoveRecord {
+    pub player_id: i32,
+    pub field: i32,
+    /// Sub-board of an ultimate move.
+    pub board: Option<i32>,
+    /// Time from the start of the player's turn until the move was accepted.
+    pub think_ms: i32,
+    pub played_at: DateTime<Utc>,
+}
+
+/// What [`save_game`] does to the players' standings.
+#[derive(Debug, PartialEq)]
+pub enum StandingsUpdate {
+    /// Casual games are stored but never counted.
+    None,
+    Win { winner_id: i32, loser_id: i32, rated: bool },
+    Draw { rated: bool },
+}
+
+impl StandingsUpdate {
+    pub fn of(record: &GameRecord) -> Self {
+        match (record.ranked, record.winner_id) {
+            (false, _) => StandingsUpdate::None,
+            (true, Some(winner_id)) => StandingsUpdate::Win {
+                winner_id,
+                loser_id: if winner_id == record.player1_id { record.player2_id } else { record.player1_id },
+                rated: record.rated,
+            },
+            (true, None) => StandingsUpdate::Draw { rated: record.rated },
+        }
+    }
+}
+
+/// Stores a finished game and returns its id.
+pub async fn save_game(pool: Pool<Postgres>, record: &GameRecord) -> Result<i64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let game_id: i64 = sqlx::query_scalar(
+        "INSERT INTO games (player1_id, player2_id, winner_id, board, status, ranked, handicap, started_at, finished_at)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, COALESCE($9, now()))
+         RETURNING id"
+    )
+        .bind(record.player1_id)
+        .bind(record.player2_id)
+        .bind(record.winner_id)
+        .bind(&record.board)
+        .bind(&record.status)
+        .bind(record.ranked)
+        .bind(record.handicap)
+        .bind(record.started_at)
+        .bind(record.finished_at)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    if !record.moves.is_empty() {
+        sqlx::query(
+            "INSERT INTO moves (game_id, ply, player_id, field, board, think_ms, played_at)
+             SELECT $1, m.ply, m.player_id, m.field, m.board, m.think_ms, m.played_at
+             FROM UNNEST($2::int[], $3::int[], $4::int[], $5::int[], $6::timestamptz[])
+                  WITH ORDINALITY AS m(player_id, field, board, think_ms, played_at, ply)"
+        )
+            .bind(game_id)
+            .bind(record.moves.iter().map(|m| m.player_id).collect::<Vec<_>>())
+            .bind(record.moves.iter().map(|m| m.field).collect::<Vec<_>>())
+            .bind(record.moves.iter().map(|m| m.board).collect::<Vec<_>>())
+            .bind(record.moves.iter().map(|m| m.think_ms).collect::<Vec<_>>())
+            .bind(record.moves.iter().map(|m| m.played_at).collect::<Vec<_>>())
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let update = StandingsUpdate::of(record);
+    if update != StandingsUpdate::None {
+        lock_players(&mut tx, record.player1_id, record.player2_id).await?;
+    }
+
+    match update {
+        StandingsUpdate::Win { winner_id, loser_id, rated } => {
+            add_win_id(&mut tx, winner_id).await?;
+            add_lose_id(&mut tx, loser_id).await?;
+            if rated {
+                update_elo(&mut tx, winner_id, loser_id, 1.0).await?;
+            }
+        }
+        StandingsUpdate::Draw { rated } => {
+            add_draw_id(&mut tx, record.player1_id).await?;
+            add_draw_id(&mut tx, record.player2_id).await?;
+            if rated {
+                update_elo(&mut tx, record.player1_id, record.player2_id, 0.5).await?;
+            }
+        }
+        StandingsUpdate::None => {}
+    }
+
+    tx.commit().await?;
+    Ok(game_id)
+}
+
+/// Locks both players' rows, lower id first, so that two games between the
+/// same pair finishing at once, with the seats swapped, can't deadlock.
+/// `NO KEY UPDATE` is what the updates below take anyway, and leaves the
+/// foreign-key checks of other games' inserts alone.
+async fn lock_players(conn: &mut PgConnection, player1_id: i32, player2_id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT id FROM users WHERE id IN ($1, $2) ORDER BY id FOR NO KEY UPDATE")
+        .bind(player1_id)
+        .bind(player2_id)
+        .fetch_all(conn)
+        .await?;
+    Ok(())
+}
+
+async fn add_win_id(conn: &mut PgConnection, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE users
+         SET wins = wins + 1,
+             current_streak = current_streak + 1,
+             best_streak = GREATEST(best_streak, current_streak + 1)
+         WHERE id = $1"
+    )
+        .bind(id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+async fn add_lose_id(conn: &mut PgConnection, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET loses = loses + 1, current_streak = 0 WHERE id = $1")
+        .bind(id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+async fn add_draw_id(conn: &mut PgConnection, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET draws = draws + 1 WHERE id = $1")
+        .bind(id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Points `elo` gains from scoring `score` (1 for a win, 0.5 for a draw, 0 for
+/// a loss) against `opponent_elo`; the opponent loses the same amount. A draw
+/// moves the lower-rated player up and the higher-rated one down.
+pub fn elo_change(elo: i32, opponent_elo: i32, score: f64) -> i32 {
+    let expected = 1.0 / (1.0 + 10f64.powf(f64::from(opponent_elo - elo) / 400.0));
+    (ELO_K * (score - expected)).round() as i32
+}
+
+/// Expects both rows locked by [`lock_players`].
+async fn update_elo(conn: &mut PgConnection, player_id: i32, opponent_id: i32, score: f64) -> Result<(), sqlx::Error> {
+    let player_elo: i32 = sqlx::query_scalar("SELECT elo FROM users WHERE id = $1")
+        .bind(player_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    let opponent_elo: i32 = sqlx::query_scalar("SELECT elo FROM users WHERE id = $1")
+        .bind(opponent_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    let delta = elo_change(player_elo, opponent_elo, score);
+
+    sqlx::query(
+        "WITH updated AS (
+             UPDATE users SET elo = CASE WHEN id = $1 THEN $3 ELSE $4 END
+             WHERE id IN ($1, $2)
+             RETURNING id, elo
+         )
+         INSERT INTO elo_history (user_id, elo) SELECT id, elo FROM updated"
+    )
+        .bind(player_id)
+        .bind(opponent_id)
+        .bind(player_elo + delta)
+        .bind(opponent_elo - delta)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[derive(Serialize, FromRow)]
+pub struct EloPoint {
+    pub elo: i32,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// Returns `false` if `new_name` already belongs to another account, in any case.
+pub async fn rename_user(pool: Pool<Postgres>, id: i32, new_name: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE users SET username = $1
+         WHERE id = $2 AND NOT EXISTS (SELECT 1 FROM users WHERE lower(username) = lower($1) AND id <> $2)"
+    )
+        .bind(new_name)
+        .bind(id)
+        .execute(&pool)
+        .await;
+
+    match result {
+        Ok(done) => Ok(done.rows_affected() == 1),
+        Err(sqlx::Error::Database(e)) if is_username_clash(e.as_ref()) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn block_user(pool: Pool<Postgres>, blocker: i32, blocked: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO blocks (blocker_id, blocked_id) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(blocker)
+        .bind(blocked)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn unblock_user(pool: Pool<Postgres>, blocker: i32, blocked: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM blocks WHERE blocker_id = $1 AND blocked_id = $2")
+        .bind(blocker)
+        .bind(blocked)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Friend {
+    #[serde(skip)]
+    pub id: i32,
+    pub username: String,
+    /// `accepted`, `incoming` or `outgoing`.
+    pub status: String,
+}
+
+/// Sends a friend request from `from` to `to`; if `to` has already asked `from`,
+/// that request is accepted instead. Returns whether the two are now friends.
+pub async fn request_friend(pool: Pool<Postgres>, from: i32, to: i32) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let answered = sqlx::query("UPDATE friends SET accepted = TRUE WHERE requester_id = $1 AND addressee_id = $2")
+        .bind(to)
+        .bind(from)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected() > 0;
+
+    if !answered {
+        sqlx::query("INSERT INTO friends (requester_id, addressee_id) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+            .bind(from)
+            .bind(to)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let accepted: bool = sqlx::query_scalar(
+        "SELECT COALESCE(bool_or(accepted), FALSE) FROM friends
+         WHERE (requester_id = $1 AND addressee_id = $2) OR (requester_id = $2 AND addressee_id = $1)"
+    )
+        .bind(from)
+        .bind(to)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(accepted)
+}
+
+/// Accepts `from`'s pending request to `to`; `false` if there was none.
+pub async fn accept_friend(pool: Pool<Postgres>, to: i32, from: i32) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE friends SET accepted = TRUE WHERE requester_id = $1 AND addressee_id = $2 AND NOT accepted")
+        .bind(from)
+        .bind(to)
+        .execute(&pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Ends a friendship or withdraws/declines a request, whichever way it went.
+pub async fn remove_friend(pool: Pool<Postgres>, a: i32, b: i32) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM friends WHERE (requester_id = $1 AND addressee_id = $2) OR (requester_id = $2 AND addressee_id = $1)"
+    )
+        .bind(a)
+        .bind(b)
+        .execute(&pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn friends(pool: Pool<Postgres>, user_id: i32) -> Result<Vec<Friend>, sqlx::Error> {
+    sqlx::query_as::<_, Friend>(
+        "SELECT u.id, u.username,
+                CASE WHEN f.accepted THEN 'accepted'
+                     WHEN f.requester_id = $1 THEN 'outgoing'
+                     ELSE 'incoming' END AS status
+         FROM friends f
+         JOIN users u ON u.id = CASE WHEN f.requester_id = $1 THEN f.addressee_id ELSE f.requester_id END
+         WHERE $1 IN (f.requester_id, f.addressee_id) AND NOT u.deactivated
+         ORDER BY NOT f.accepted, u.username"
+    )
+    .bind(user_id)
+    .fetch_all(&pool)
+    .await
+}
+
+pub async fn are_friends(pool: Pool<Postgres>, a: i32, b: i32) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM friends WHERE accepted AND ((requester_id = $1 AND addressee_id = $2) OR (requester_id = $2 AND addressee_id = $1)))"
+    )
+        .bind(a)
+        .bind(b)
         .fetch_one(&pool)
         .await
-        .expect("Error in username select in login_from_token function");
+}
 
-    let id: i32 = sqlx::query_scalar("SELECT id FROM users WHERE token = $1")
-        .bind(&token)
+/// `true` if either player has blocked the other.
+pub async fn is_blocked(pool: Pool<Postgres>, a: i32, b: i32) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM blocks WHERE (blocker_id = $1 AND blocked_id = $2) OR (blocker_id = $2 AND blocked_id = $1))"
+    )
+        .bind(a)
+        .bind(b)
         .fetch_one(&pool)
         .await
-        .expect("Error in id select in login_from_token function");
-
-    (id, username)
 }
 
-pub async fn add_win_id(pool: Pool<Postgres>, id: i32) {
-    sqlx::query("UPDATE users SET wins = wins + 1 WHERE id = $1")
+pub async fn username_from_id(pool: Pool<Postgres>, id: i32) -> Result<String, sqlx::Error> {
+    sqlx::query_scalar("SELECT username FROM users WHERE id = $1")
         .bind(id)
-        .execute(&pool)
+        .fetch_one(&pool)
         .await
-        .expect("Add win to database error.");
 }
 
-pub async fn add_lose_id(pool: Pool<Postgres>, id: i32) {
-    sqlx::query("UPDATE users SET loses = loses + 1 WHERE id = $1")
-        .bind(id)
-        .execute(&pool)
+pub async fn user_id_from_name(pool: Pool<Postgres>, username: &str) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar("SELECT id FROM users WHERE lower(username) = lower($1)")
+        .bind(username)
+        .fetch_optional(&pool)
         .await
-        .expect("Add loose to database error.");
+}
+
+pub async fn elo_history_from_database(pool: Pool<Postgres>, user_id: i32, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Result<Vec<EloPoint>, sqlx::Error> {
+    sqlx::query_as::<_, EloPoint>(
+        "SELECT elo, recorded_at
+         FROM elo_history
+         WHERE user_id = $1
+           AND ($2::TIMESTAMPTZ IS NULL OR recorded_at >= $2)
+           AND ($3::TIMESTAMPTZ IS NULL OR recorded_at <= $3)
+         ORDER BY recorded_at"
+    )
+    .bind(user_id)
+    .bind(from)
+    .bind(to)
+    .fetch_all(&pool)
+    .await
 }
 
 #[derive(Serialize, FromRow)]
+pub struct H2H {
+    pub wins: i64,
+    pub losses: i64,
+    pub draws: i64,
+}
+
+pub async fn head_to_head(pool: Pool<Postgres>, a: i32, b: i32) -> Result<H2H, sqlx::Error> {
+    sqlx::query_as::<_, H2H>(
+        "SELECT COUNT(*) FILTER (WHERE winner_id = $1) AS wins,
+                COUNT(*) FILTER (WHERE winner_id = $2) AS losses,
+                COUNT(*) FILTER (WHERE winner_id IS NULL) AS draws
+         FROM games
+         WHERE (player1_id = $1 AND player2_id = $2)
+            OR (player1_id = $2 AND player2_id = $1)"
+    )
+    .bind(a)
+    .bind(b)
+    .fetch_one(&pool)
+    .await
+}
+
+#[derive(Serialize, FromRow)]
+pub struct GameSummary {
+    pub opponent: String,
+    /// `win`, `loss` or `draw`, from the player's side.
+    pub result: String,
+    pub ranked: bool,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// The last 20 games of user `id`, newest first.
+pub async fn history_from_database(pool: Pool<Postgres>, id: i32) -> Result<Vec<GameSummary>, sqlx::Error> {
+    sqlx::query_as::<_, GameSummary>(
+        "SELECT o.username AS opponent,
+                CASE WHEN g.winner_id IS NULL THEN 'draw'
+                     WHEN g.winner_id = $1 THEN 'win'
+                     ELSE 'loss' END AS result,
+                g.ranked, g.finished_at
+         FROM games g
+         JOIN users o ON o.id = CASE WHEN g.player1_id = $1 THEN g.player2_id ELSE g.player1_id END
+         WHERE $1 IN (g.player1_id, g.player2_id)
+         ORDER BY g.finished_at DESC
+         LIMIT 20"
+    )
+    .bind(id)
+    .fetch_all(&pool)
+    .await
+}
+
+#[derive(Serialize, FromRow)]
+pub struct RecentGame {
+    pub player1: String,
+    pub player2: String,
+    pub winner: Option<String>,
+    pub status: String,
+    pub finished_at: DateTime<Utc>,
+}
+
+pub async fn recent_games(pool: Pool<Postgres>, limit: i64) -> Result<Vec<RecentGame>, sqlx::Error> {
+    sqlx::query_as::<_, RecentGame>(
+        "SELECT p1.username AS player1, p2.username AS player2, w.username AS winner,
+                g.status, g.finished_at
+         FROM games g
+         JOIN users p1 ON p1.id = g.player1_id
+         JOIN users p2 ON p2.id = g.player2_id
+         LEFT JOIN users w ON w.id = g.winner_id
+         ORDER BY g.finished_at DESC
+         LIMIT $1"
+    )
+    .bind(limit)
+    .fetch_all(&pool)
+    .await
+}
+
+/// Usernames starting with `prefix`, case-insensitively, in alphabetical order.
+/// `%` and `_` in the prefix match only themselves.
+pub async fn search_users(pool: Pool<Postgres>, prefix: &str, limit: i64) -> Result<Vec<String>, sqlx::Error> {
+    let escaped = prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    sqlx::query_scalar(
+        "SELECT username
+         FROM users
+         WHERE username ILIKE $1 || '%' AND NOT guest AND NOT deactivated
+         ORDER BY username
+         LIMIT $2"
+    )
+    .bind(escaped)
+    .bind(limit)
+    .fetch_all(&pool)
+    .await
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Profile {
+    pub username: String,
+    pub wins: i32,
+    pub loses: i32,
+    pub draws: i64,
+    pub games_played: i64,
+    pub points: i32,
+    pub elo: i32,
+    pub current_streak: i32,
+    pub best_streak: i32,
+    pub score: i64,
+    pub rank: i64,
+    pub total_players: i64,
+    pub percentile: f64,
+    /// Mean think time over every recorded move; `None` before the first one.
+    pub avg_move_ms: Option<f64>,
+}
+
+/// Every listed player with their stats, as the `players` CTE for the ranking
+/// queries.
+const RANKED_PLAYERS: &str = "
+    players AS (
+        SELECT id, username,
+               COALESCE(wins, 0) AS wins,
+               COALESCE(loses, 0) AS loses,
+               COALESCE(points, 0) AS points,
+               COALESCE(elo, 1200) AS elo,
+               COALESCE(draws, 0)::BIGINT AS draws,
+               current_streak,
+               best_streak
+        FROM users
+        WHERE NOT guest AND NOT deactivated
+    )";
+
+/// Rank is a dense rank by the [`RankingStrategy`] score: players with equal
+/// scores share a rank and the next distinct score takes the following number.
+/// `percentile` is `rank / total_players` as a percentage, so lower is better.
+pub async fn profile(pool: Pool<Postgres>, username: &str, ranking: RankingStrategy) -> Result<Option<Profile>, sqlx::Error> {
+    sqlx::query_as::<_, Profile>(&format!(
+        "WITH {players},
+         ranked AS (
+             SELECT *,
+                    {score}::BIGINT AS score,
+                    DENSE_RANK() OVER (ORDER BY {order}) AS rank,
+                    COUNT(*) OVER () AS total_players
+             FROM players
+         )
+         SELECT username, wins, loses, draws, wins + loses + draws AS games_played,
+                points, elo, current_streak, best_streak, score, rank, total_players,
+                (100.0 * rank / total_players)::float8 AS percentile,
+                (SELECT AVG(think_ms)::float8 FROM moves WHERE player_id = ranked.id) AS avg_move_ms
+         FROM ranked
+         WHERE lower(username) = lower($1)",
+        players = RANKED_PLAYERS,
+        score = ranking.score(),
+        order = ranking.order_clause(),
+    ))
+    .bind(username)
+    .fetch_optional(&pool)
+    .await
+}
+
+#[derive(Serialize, FromRow, Clone)]
 pub struct UserRank {
     pub username: String,
     pub wins: i32,
+    pub draws: i64,
+    pub games_played: i64,
     pub points: i32,
+    pub score: i64,
 }
 
 impl UserRank {
@@ -169,21 +1230,720 @@ impl UserRank {
         Self {
             username: String::new(),
             wins: 0,
+            draws: 0,
+            games_played: 0,
             points: 0,
+            score: 0,
         }
     }
 }
 
-pub async fn top10_from_database(pool: Pool<Postgres>) -> Result<Vec<UserRank>, sqlx::Error> {
-    let top_users = sqlx::query_as::<_, UserRank>(
-        "SELECT username, wins, points
+pub async fn top10_from_database(pool: Pool<Postgres>, ranking: RankingStrategy) -> Result<Vec<UserRank>, sqlx::Error> {
+    let top_users = sqlx::query_as::<_, UserRank>(&format!(
+        "WITH {players}
+         SELECT username, wins, draws, wins + loses + draws AS games_played, points, {score}::BIGINT AS score
+         FROM players
+         ORDER BY {order}, username
+         LIMIT 10",
+        players = RANKED_PLAYERS,
+        score = ranking.score(),
+        order = ranking.order_clause(),
+    ))
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(top_users)
+}
+
+#[derive(Serialize, FromRow, Clone)]
+pub struct GlobalStats {
+    pub players: i64,
+    pub games: i64,
+    pub average_game_secs: Option<f64>,
+    pub median_game_secs: Option<f64>,
+}
+
+/// Site-wide totals. Durations only cover games with a recorded start that ended
+/// in under `max_game_secs`; games that ran into the time limit were most
+/// likely abandoned and would drag the average up.
+pub async fn global_stats(pool: Pool<Postgres>, max_game_secs: f64) -> Result<GlobalStats, sqlx::Error> {
+    sqlx::query_as::<_, GlobalStats>(
+        "SELECT (SELECT COUNT(*) FROM users WHERE NOT guest AND NOT deactivated) AS players,
+                (SELECT COUNT(*) FROM games) AS games,
+                AVG(secs) AS average_game_secs,
+                PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY secs) AS median_game_secs
+         FROM (
+             SELECT EXTRACT(EPOCH FROM finished_at - started_at)::float8 AS secs
+             FROM games
+             WHERE started_at IS NOT NULL
+         ) durations
+         WHERE secs < $1"
+    )
+    .bind(max_game_secs)
+    .fetch_one(&pool)
+    .await
+}
+
+
+#[derive(Serialize, FromRow)]
+pub struct StreakRank {
+    pub username: String,
+    pub best_streak: i32,
+    pub current_streak: i32,
+}
+
+pub async fn top_streaks_from_database(pool: Pool<Postgres>) -> Result<Vec<StreakRank>, sqlx::Error> {
+    sqlx::query_as::<_, StreakRank>(
+        "SELECT username, best_streak, current_streak
          FROM users
-         ORDER BY points DESC
+         WHERE NOT guest AND NOT deactivated AND best_streak > 0
+         ORDER BY best_streak DESC, current_streak DESC
          LIMIT 10"
     )
     .fetch_all(&pool)
+    .await
+}
+
+#[derive(Serialize, FromRow)]
+pub struct AccountExport {
+    pub username: String,
+    pub wins: i32,
+    pub loses: i32,
+    pub draws: i32,
+    pub points: i32,
+    pub elo: i32,
+    pub current_streak: i32,
+    pub best_streak: i32,
+    pub guest: bool,
+    pub two_factor_enabled: bool,
+    pub has_active_session: bool,
+    /// When the current session was signed in.
+    pub session_started_at: Option<DateTime<Utc>>,
+    pub last_seen: DateTime<Utc>,
+    pub email: Option<String>,
+    pub email_verified: bool,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct GameExport {
+    pub id: i64,
+    pub player1: String,
+    pub player2: String,
+    pub winner: Option<String>,
+    pub board: Vec<String>,
+    pub status: String,
+    pub ranked: bool,
+    pub finished_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct MoveExport {
+    pub game_id: i64,
+    pub ply: i32,
+    pub field: i32,
+    pub board: Option<i32>,
+    pub think_ms: i32,
+    pub played_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct PendingEmail {
+    pub email: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct UserExport {
+    pub account: AccountExport,
+    pub games: Vec<GameExport>,
+    /// The user's own moves in those games.
+    pub moves: Vec<MoveExport>,
+    pub elo_history: Vec<EloPoint>,
+    pub achievements: Vec<EarnedAchievement>,
+    pub friends: Vec<Friend>,
+    pub blocked_users: Vec<String>,
+    /// Addresses waiting to be confirmed.
+    pub pending_emails: Vec<PendingEmail>,
+    /// Logins, failed logins and the like under the current username, newest first.
+    pub auth_events: Vec<AuthEventRecord>,
+}
+
+/// Everything stored about user `id`, except the password hash, the session
+/// token and the hashes of recovery codes and one-time links.
+pub async fn export_user_data(pool: Pool<Postgres>, id: i32) -> Result<UserExport, sqlx::Error> {
+    let account = sqlx::query_as::<_, AccountExport>(
+        "SELECT username,
+                COALESCE(wins, 0) AS wins,
+                COALESCE(loses, 0) AS loses,
+                COALESCE(draws, 0) AS draws,
+                COALESCE(points, 0) AS points,
+                COALESCE(elo, 1200) AS elo,
+                current_streak, best_streak, guest,
+                totp_enabled AS two_factor_enabled,
+                token IS NOT NULL AS has_active_session,
+                token_created_at AS session_started_at,
+                last_seen, email, email_verified
+         FROM users
+         WHERE id = $1"
+    )
+    .bind(id)
+    .fetch_one(&pool)
+    .await?;
+
+    let games = sqlx::query_as::<_, GameExport>(
+        "SELECT g.id, p1.username AS player1, p2.username AS player2, w.username AS winner,
+                g.board, g.status, g.ranked, g.finished_at
+         FROM games g
+         JOIN users p1 ON p1.id = g.player1_id
+         JOIN users p2 ON p2.id = g.player2_id
+         LEFT JOIN users w ON w.id = g.winner_id
+         WHERE g.player1_id = $1 OR g.player2_id = $1
+         ORDER BY g.finished_at"
+    )
+    .bind(id)
+    .fetch_all(&pool)
     .await?;
 
-    Ok(top_users)
+    let moves = sqlx::query_as::<_, MoveExport>(
+        "SELECT game_id, ply, field, board, think_ms, played_at
+         FROM moves
+         WHERE player_id = $1
+         ORDER BY game_id, ply"
+    )
+    .bind(id)
+    .fetch_all(&pool)
+    .await?;
+
+    let elo_history = elo_history_from_database(pool.clone(), id, None, None).await?;
+    let achievements = user_achievements(pool.clone(), id).await?;
+    let friends = friends(pool.clone(), id).await?;
+
+    let blocked_users = sqlx::query_scalar(
+        "SELECT u.username
+         FROM blocks b
+         JOIN users u ON u.id = b.blocked_id
+         WHERE b.blocker_id = $1
+         ORDER BY u.username"
+    )
+    .bind(id)
+    .fetch_all(&pool)
+    .await?;
+
+    let pending_emails = sqlx::query_as::<_, PendingEmail>(
+        "SELECT email, expires_at FROM email_verifications WHERE user_id = $1 AND expires_at > now() ORDER BY expires_at"
+    )
+    .bind(id)
+    .fetch_all(&pool)
+    .await?;
+
+    let auth_events = sqlx::query_as::<_, AuthEventRecord>(
+        "SELECT event, ip, created_at FROM auth_events WHERE lower(username) = lower($1) ORDER BY created_at DESC"
+    )
+    .bind(&account.username)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(UserExport { account, games, moves, elo_history, achievements, friends, blocked_users, pending_emails, auth_events })
+}
+
+#[derive(FromRow)]
+pub struct Entrant {
+    pub id: i32,
+    pub username: String,
+}
+
+/// Registered, active players among `usernames`, strongest first.
+pub async fn entrants(pool: Pool<Postgres>, usernames: &[String]) -> Result<Vec<Entrant>, sqlx::Error> {
+    sqlx::query_as::<_, Entrant>(
+        "SELECT id, username
+         FROM users
+         WHERE lower(username) = ANY(SELECT lower(name) FROM unnest($1::text[]) AS name) AND NOT guest AND NOT deactivated
+         ORDER BY COALESCE(elo, 1200) DESC, id"
+    )
+    .bind(usernames)
+    .fetch_all(&pool)
+    .await
+}
+
+pub struct NewTournamentMatch {
+    pub round: i32,
+    pub slot: i32,
+    pub player1_id: Option<i32>,
+    pub player2_id: Option<i32>,
+    pub winner_id: Option<i32>,
+}
+
+pub async fn create_tournament(pool: Pool<Postgres>, name: &str, format: &str, matches: &[NewTournamentMatch]) -> Result<i64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let id: i64 = sqlx::query_scalar("INSERT INTO tournaments (name, format) VALUES ($1, $2) RETURNING id")
+        .bind(name)
+        .bind(format)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    for m in matches {
+        sqlx::query(
+            "INSERT INTO tournament_matches (tournament_id, round, slot, player1_id, player2_id, winner_id, played)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)"
+        )
+        .bind(id)
+        .bind(m.round)
+        .bind(m.slot)
+        .bind(m.player1_id)
+        .bind(m.player2_id)
+        .bind(m.winner_id)
+        .bind(m.winner_id.is_some())
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(id)
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Tournament {
+    pub id: i64,
+    pub name: String,
+    pub format: String,
+    pub finished: bool,
+    pub winner: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn tournament(pool: Pool<Postgres>, id: i64) -> Result<Option<Tournament>, sqlx::Error> {
+    sqlx::query_as::<_, Tournament>(
+        "SELECT t.id, t.name, t.format, t.finished, w.username AS winner, t.created_at
+         FROM tournaments t
+         LEFT JOIN users w ON w.id = t.winner_id
+         WHERE t.id = $1"
+    )
+    .bind(id)
+    .fetch_optional(&pool)
+    .await
+}
+
+#[derive(Serialize, FromRow)]
+pub struct TournamentMatch {
+    pub id: i64,
+    pub round: i32,
+    pub slot: i32,
+    pub player1: Option<String>,
+    pub player2: Option<String>,
+    pub winner: Option<String>,
+    pub played: bool,
+    pub forfeit: bool,
+}
+
+pub async fn tournament_matches(pool: Pool<Postgres>, tournament_id: i64) -> Result<Vec<TournamentMatch>, sqlx::Error> {
+    sqlx::query_as::<_, TournamentMatch>(
+        "SELECT m.id, m.round, m.slot, p1.username AS player1, p2.username AS player2, w.username AS winner,
+                m.played, m.forfeit
+         FROM tournament_matches m
+         LEFT JOIN users p1 ON p1.id = m.player1_id
+         LEFT JOIN users p2 ON p2.id = m.player2_id
+         LEFT JOIN users w ON w.id = m.winner_id
+         WHERE m.tournament_id = $1
+         ORDER BY m.round, m.slot"
+    )
+    .bind(tournament_id)
+    .fetch_all(&pool)
+    .await
+}
+
+/// Undecided matches in which `user_id` already knows their opponent, earliest first.
+pub async fn pending_tournament_matches(pool: Pool<Postgres>, tournament_id: i64, user_id: i32) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT m.id
+         FROM tournament_matches m
+         JOIN tournaments t ON t.id = m.tournament_id
+         WHERE m.tournament_id = $1
+           AND NOT t.finished
+           AND NOT m.played
+           AND m.player1_id IS NOT NULL
+           AND m.player2_id IS NOT NULL
+           AND $2 IN (m.player1_id, m.player2_id)
+         ORDER BY m.round, m.slot"
+    )
+    .bind(tournament_id)
+    .bind(user_id)
+    .fetch_all(&pool)
+    .await
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Standing {
+    #[serde(skip)]
+    pub user_id: i32,
+    pub username: String,
+    pub played: i64,
+    pub wins: i64,
+    pub draws: i64,
+    pub losses: i64,
+    pub points: i64,
 }
 
+const STANDINGS: &str =
+    "SELECT u.id AS user_id, u.username,
+            COUNT(*) FILTER (WHERE m.played) AS played,
+            COUNT(*) FILTER (WHERE m.winner_id = u.id) AS wins,
+            COUNT(*) FILTER (WHERE m.played AND m.winner_id IS NULL) AS draws,
+            COUNT(*) FILTER (WHERE m.winner_id <> u.id) AS losses,
+            2 * COUNT(*) FILTER (WHERE m.winner_id = u.id)
+              + COUNT(*) FILTER (WHERE m.played AND m.winner_id IS NULL) AS points
+     FROM tournament_matches m
+     JOIN users u ON u.id IN (m.player1_id, m.player2_id)
+     WHERE m.tournament_id = $1
+     GROUP BY u.id, u.username
+     ORDER BY points DESC, wins DESC, u.username";
+
+/// Points table of a round-robin: 2 for a win, 1 for a draw.
+pub async fn tournament_standings(pool: Pool<Postgres>, tournament_id: i64) -> Result<Vec<Standing>, sqlx::Error> {
+    sqlx::query_as::<_, Standing>(STANDINGS)
+        .bind(tournament_id)
+        .fetch_all(&pool)
+        .await
+}
+
+/// Records the result of a tournament match.
+///
+/// In a bracket the winner moves into the next round and winning the final
+/// finishes the tournament; a draw (`winner_id = None`) decides nothing and
+/// the match stays open. In a round-robin every result counts, and the
+/// tournament finishes with the last match — won by the points leader, or by
+/// nobody if the top is shared.
+pub async fn finish_tournament_match(pool: Pool<Postgres>, match_id: i64, winner_id: Option<i32>, forfeit: bool) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let open: Option<(String, i64, i32, i32)> = sqlx::query_as(
+        "SELECT t.format, m.tournament_id, m.round, m.slot
+         FROM tournament_matches m
+         JOIN tournaments t ON t.id = m.tournament_id
+         WHERE m.id = $1 AND NOT m.played
+         FOR UPDATE OF m"
+    )
+    .bind(match_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some((format, tournament_id, round, slot)) = open else {
+        return tx.commit().await;
+    };
+
+    if format == BRACKET && winner_id.is_none() {
+        return tx.commit().await;
+    }
+
+    sqlx::query("UPDATE tournament_matches SET winner_id = $2, played = TRUE, forfeit = $3 WHERE id = $1")
+        .bind(match_id)
+        .bind(winner_id)
+        .bind(forfeit)
+        .execute(&mut *tx)
+        .await?;
+
+    let champion = if format == BRACKET {
+        let next = sqlx::query(
+            "UPDATE tournament_matches
+             SET player1_id = CASE WHEN $4 % 2 = 0 THEN $5 ELSE player1_id END,
+                 player2_id = CASE WHEN $4 % 2 = 1 THEN $5 ELSE player2_id END
+             WHERE tournament_id = $1 AND round = $2 AND slot = $3"
+        )
+        .bind(tournament_id)
+        .bind(round + 1)
+        .bind(slot / 2)
+        .bind(slot)
+        .bind(winner_id)
+        .execute(&mut *tx)
+        .await?;
+
+        if next.rows_affected() > 0 {
+            return tx.commit().await;
+        }
+        winner_id
+    } else {
+        let remaining: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM tournament_matches WHERE tournament_id = $1 AND NOT played)")
+            .bind(tournament_id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        if remaining {
+            return tx.commit().await;
+        }
+
+        let standings = sqlx::query_as::<_, Standing>(STANDINGS)
+            .bind(tournament_id)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        match standings.as_slice() {
+            [first, second, ..] if first.points == second.points => None,
+            [first, ..] => Some(first.user_id),
+            [] => None,
+        }
+    };
+
+    sqlx::query("UPDATE tournaments SET finished = TRUE, winner_id = $2 WHERE id = $1")
+        .bind(tournament_id)
+        .bind(champion)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+pub async fn player_stats(pool: Pool<Postgres>, user_id: i32) -> Result<PlayerStats, sqlx::Error> {
+    let (wins, current_streak, games_played): (i32, i32, i64) = sqlx::query_as(
+        "SELECT COALESCE(wins, 0), current_streak,
+                (SELECT COUNT(*) FROM games WHERE ranked AND $1 IN (player1_id, player2_id))
+         FROM users
+         WHERE id = $1"
+    )
+    .bind(user_id)
+    .fetch_one(&pool)
+    .await?;
+
+    Ok(PlayerStats { wins, current_streak, games_played })
+}
+
+/// Stores `earned` for the user and returns the codes they didn't have yet.
+pub async fn award_achievements(pool: Pool<Postgres>, user_id: i32, earned: &[Achievement]) -> Result<Vec<String>, sqlx::Error> {
+    let codes: Vec<&str> = earned.iter().map(|achievement| achievement.code()).collect();
+
+    sqlx::query_scalar(
+        "INSERT INTO user_achievements (user_id, code)
+         SELECT $1, UNNEST($2::TEXT[])
+         ON CONFLICT DO NOTHING
+         RETURNING code"
+    )
+    .bind(user_id)
+    .bind(&codes)
+    .fetch_all(&pool)
+    .await
+}
+
+#[derive(Serialize, FromRow)]
+pub struct EarnedAchievement {
+    pub code: String,
+    pub name: String,
+    pub description: String,
+    pub earned_at: DateTime<Utc>,
+}
+
+pub async fn user_achievements(pool: Pool<Postgres>, user_id: i32) -> Result<Vec<EarnedAchievement>, sqlx::Error> {
+    sqlx::query_as::<_, EarnedAchievement>(
+        "SELECT a.code, a.name, a.description, ua.earned_at
+         FROM user_achievements ua
+         JOIN achievements a ON a.code = ua.code
+         WHERE ua.user_id = $1
+         ORDER BY ua.earned_at, a.code"
+    )
+    .bind(user_id)
+    .fetch_all(&pool)
+    .await
+}
+
+#[derive(Serialize, FromRow)]
+pub struct ActivePlayer {
+    pub username: String,
+    pub games: i64,
+}
+
+/// Players with the most finished games (ranked or casual) since `since`.
+pub async fn most_active(pool: Pool<Postgres>, since: DateTime<Utc>, limit: i64) -> Result<Vec<ActivePlayer>, sqlx::Error> {
+    sqlx::query_as::<_, ActivePlayer>(
+        "SELECT u.username, COUNT(*) AS games
+         FROM games g
+         JOIN users u ON u.id IN (g.player1_id, g.player2_id)
+         WHERE g.finished_at >= $1 AND NOT u.guest AND NOT u.deactivated
+         GROUP BY u.id, u.username
+         ORDER BY games DESC, u.username
+         LIMIT $2"
+    )
+    .bind(since)
+    .bind(limit)
+    .fetch_all(&pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ranked: bool, winner_id: Option<i32>) -> GameRecord {
+        GameRecord {
+            player1_id: 1,
+            player2_id: 2,
+            winner_id,
+            board: vec![String::new(); 9],
+            status: String::new(),
+            ranked,
+            handicap: false,
+            rated: true,
+            started_at: None,
+            finished_at: None,
+            moves: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn casual_games_leave_the_standings_alone() {
+        assert_eq!(StandingsUpdate::of(&record(false, Some(2))), StandingsUpdate::None);
+        assert_eq!(StandingsUpdate::of(&record(false, None)), StandingsUpdate::None);
+    }
+
+    #[test]
+    fn ranked_games_count_and_rate() {
+        assert_eq!(
+            StandingsUpdate::of(&record(true, Some(2))),
+            StandingsUpdate::Win { winner_id: 2, loser_id: 1, rated: true },
+        );
+        assert_eq!(StandingsUpdate::of(&record(true, None)), StandingsUpdate::Draw { rated: true });
+    }
+
+    /// A migrated database at `TEST_DATABASE_URL`, for the tests that need
+    /// Postgres; they pass without running when it isn't set.
+    async fn test_pool() -> Option<Pool<Postgres>> {
+        let url = std::env::var("TEST_DATABASE_URL").ok()?;
+        let pool = PgPoolOptions::new().max_connections(2).connect(&url).await.unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        Some(pool)
+    }
+
+    /// Registers a fresh account and returns its id.
+    async fn test_user(pool: &Pool<Postgres>) -> i32 {
+        let name = format!("test-{}", &uuid::Uuid::new_v4().simple().to_string()[..8]);
+        sqlx::query_scalar("INSERT INTO users (username, password) VALUES ($1, '') RETURNING id")
+            .bind(name)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    /// Creates a guest who was last seen two days ago and returns their id.
+    async fn idle_guest(pool: &Pool<Postgres>) -> i32 {
+        let guest = create_guest(pool.clone(), TokenFormat::Uuid).await.unwrap();
+        sqlx::query_scalar("UPDATE users SET last_seen = now() - interval '2 days' WHERE username = $1 RETURNING id")
+            .bind(guest.username)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn user_exists(pool: &Pool<Postgres>, id: i32) -> bool {
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE id = $1)")
+            .bind(id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn names_are_looked_up_ignoring_case() {
+        let Some(pool) = test_pool().await else { return };
+        let name = format!("Case-{}", &uuid::Uuid::new_v4().simple().to_string()[..8]);
+        let login = |name: String| Login { name, password: "secret123".to_string(), token: String::new(), code: None, email: None };
+        assert!(create_new_user(pool.clone(), &login(name.clone()), 4).await.unwrap());
+
+        let id = user_id_from_name(pool.clone(), &name).await.unwrap();
+        assert!(id.is_some());
+        assert_eq!(user_id_from_name(pool.clone(), &name.to_lowercase()).await.unwrap(), id);
+        assert!(verify_password(pool.clone(), &login(name.to_uppercase())).await.unwrap());
+        assert!(two_factor_by_name(pool.clone(), &name.to_lowercase()).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn guests_with_friend_requests_expire() {
+        let Some(pool) = test_pool().await else { return };
+        let (guest, user) = (idle_guest(&pool).await, test_user(&pool).await);
+        request_friend(pool.clone(), guest, user).await.unwrap();
+        let other = idle_guest(&pool).await;
+        request_friend(pool.clone(), user, other).await.unwrap();
+
+        expire_guests(pool.clone(), 3600).await.unwrap();
+        assert!(!user_exists(&pool, guest).await);
+        assert!(!user_exists(&pool, other).await);
+        assert!(user_exists(&pool, user).await);
+    }
+
+    #[tokio::test]
+    async fn guests_expire_with_everything_they_left_behind() {
+        let Some(pool) = test_pool().await else { return };
+        let guest = idle_guest(&pool).await;
+        for insert in [
+            "INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, 'x')",
+            "INSERT INTO password_resets (token_hash, user_id, expires_at) VALUES (gen_random_uuid()::text, $1, now())",
+            "INSERT INTO email_verifications (token_hash, user_id, email, expires_at) VALUES (gen_random_uuid()::text, $1, 'guest@example.com', now())",
+            "INSERT INTO blocks (blocker_id, blocked_id) SELECT $1, id FROM users WHERE id <> $1 LIMIT 1",
+        ] {
+            sqlx::query(insert).bind(guest).execute(&pool).await.unwrap();
+        }
+
+        expire_guests(pool.clone(), 3600).await.unwrap();
+        assert!(!user_exists(&pool, guest).await);
+    }
+
+    #[tokio::test]
+    async fn the_export_covers_friends_emails_and_auth_events() {
+        let Some(pool) = test_pool().await else { return };
+        let (user, friend) = (test_user(&pool).await, test_user(&pool).await);
+        request_friend(pool.clone(), user, friend).await.unwrap();
+        sqlx::query("INSERT INTO email_verifications (token_hash, user_id, email, expires_at) VALUES (gen_random_uuid()::text, $1, 'new@example.com', now() + interval '1 hour')")
+            .bind(user)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let username: String = sqlx::query_scalar("SELECT username FROM users WHERE id = $1").bind(user).fetch_one(&pool).await.unwrap();
+        log_auth_event(pool.clone(), &username.to_uppercase(), AuthEvent::LoginFailure, "192.0.2.1").await.unwrap();
+
+        let export = export_user_data(pool.clone(), user).await.unwrap();
+        assert_eq!(export.auth_events.len(), 1);
+        assert_eq!(export.auth_events[0].ip, "192.0.2.1");
+        assert_eq!(export.friends.len(), 1);
+        assert_eq!(export.friends[0].status, "outgoing");
+        assert_eq!(export.pending_emails.len(), 1);
+        assert_eq!(export.pending_emails[0].email, "new@example.com");
+    }
+
+    #[tokio::test]
+    async fn unrated_wins_count_without_moving_elo() {
+        let Some(pool) = test_pool().await else { return };
+        let (winner, loser) = (test_user(&pool).await, test_user(&pool).await);
+        let game = GameRecord { player1_id: winner, player2_id: loser, rated: false, ..record(true, Some(winner)) };
+        save_game(pool.clone(), &game).await.unwrap();
+
+        let standings: Vec<(i32, i32, i32)> = sqlx::query_as("SELECT wins, loses, elo FROM users WHERE id IN ($1, $2) ORDER BY id = $1 DESC")
+            .bind(winner)
+            .bind(loser)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(standings, [(1, 0, 1200), (0, 1, 1200)]);
+    }
+
+    #[test]
+    fn equal_ratings_trade_half_of_k() {
+        assert_eq!(elo_change(1200, 1200, 1.0), 16);
+        assert_eq!(elo_change(1200, 1200, 0.0), -16);
+        assert_eq!(elo_change(1200, 1200, 0.5), 0);
+    }
+
+    #[test]
+    fn elo_changes_are_symmetric() {
+        for (a, b) in [(1200, 1200), (1200, 1400), (1000, 1650), (1517, 1483)] {
+            for score in [0.0, 0.5, 1.0] {
+                assert_eq!(elo_change(a, b, score), -elo_change(b, a, 1.0 - score), "{} vs {} scoring {}", a, b, score);
+            }
+        }
+    }
+
+    #[test]
+    fn elo_changes_are_rounded() {
+        // A 1200 expects 0.2403 against a 1400: 32 × 0.7597 = 24.3 for the
+        // upset, 32 × 0.2403 = 7.7 for the favourite, 32 × 0.2597 = 8.3 for a draw.
+        assert_eq!(elo_change(1200, 1400, 1.0), 24);
+        assert_eq!(elo_change(1400, 1200, 1.0), 8);
+        assert_eq!(elo_change(1200, 1400, 0.5), 8);
+    }
+}
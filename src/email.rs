@@ -0,0 +1,117 @@
+//! # Email
+//!
+//! Optional email addresses and their verification.
+//!
+//! An address can be given at registration (`"email"` next to the password) or
+//! set later with `POST /api/email`. Each address belongs to at most one
+//! account, compared case-insensitively. A new address starts unverified:
+//! `POST /api/verify-email/send` issues a token for it, valid for
+//! `EMAIL_VERIFY_TTL_SECS`, and `POST /api/verify-email/confirm` with that token
+//! marks it verified. Changing the address drops the verification and any token
+//! issued for the old one.
+//!
+//! Only verified addresses can be used to ask for a password reset. Accounts
+//! without an email keep working exactly as before.
+//!
+//! Like reset tokens, verification tokens are stored hashed, work once and are
+//! only logged and returned when `MAIL_DEV` is on, until mail delivery exists.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use axum::Json;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use tracing::info;
+use crate::{authenticated_user, db_error_status, AppMod};
+use crate::database::{create_email_verification, generate_token, set_email, verify_email, TokenFormat};
+use crate::password_reset::hash_token;
+
+const VERIFY_TOKEN_BYTES: usize = 32;
+const MAX_EMAIL_LEN: usize = 254;
+
+#[derive(Deserialize)]
+pub struct NewEmail {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct Confirm {
+    token: String,
+}
+
+/// A plausible address: one `@`, something before it and a dotted domain after
+/// it, no whitespace. Whether it really exists is what verification is for.
+pub fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+
+    email.len() <= MAX_EMAIL_LEN
+        && !email.chars().any(char::is_whitespace)
+        && !local.is_empty()
+        && !domain.contains('@')
+        && domain.split('.').count() >= 2
+        && domain.split('.').all(|part| !part.is_empty())
+}
+
+pub async fn set(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<NewEmail>) -> StatusCode {
+    let id = match authenticated_user(&appmod, &headers).await {
+        Ok(id) => id,
+        Err(status) => return status,
+    };
+
+    let email = payload.email.trim();
+    if !is_valid_email(email) {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+
+    match set_email(appmod.pg(), id, email).await {
+        Ok(true) => {
+            info!("User {} set a new email address", id);
+            StatusCode::OK
+        }
+        Ok(false) => StatusCode::CONFLICT,
+        Err(e) => db_error_status(&e),
+    }
+}
+
+pub async fn send(State(appmod): State<AppMod>, headers: HeaderMap) -> impl IntoResponse {
+    let id = match authenticated_user(&appmod, &headers).await {
+        Ok(id) => id,
+        Err(status) => return (status, Json(None)),
+    };
+
+    let token = generate_token(TokenFormat::Random { bytes: VERIFY_TOKEN_BYTES });
+    let expires_in = appmod.config.email_verify_ttl;
+
+    let email = match create_email_verification(appmod.pg(), id, &hash_token(&token), expires_in.as_secs_f64()).await {
+        Ok(Some(email)) => email,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(None)),
+        Err(e) => return (db_error_status(&e), Json(None)),
+    };
+
+    let mut body = serde_json::json!({ "email": email, "expires_in_secs": expires_in.as_secs() });
+    if appmod.config.mail_dev {
+        info!("Email verification token for {}: {}", email, token);
+        body["token"] = token.into();
+    }
+
+    (StatusCode::ACCEPTED, Json(Some(body)))
+}
+
+pub async fn confirm(State(appmod): State<AppMod>, Json(payload): Json<Confirm>) -> StatusCode {
+    match verify_email(appmod.pg(), &hash_token(&payload.token)).await {
+        Ok(Some(username)) => {
+            info!("{} verified their email address", username);
+            StatusCode::OK
+        }
+        Ok(None) => StatusCode::NOT_FOUND,
+        Err(e) => db_error_status(&e),
+    }
+}
@@ -0,0 +1,103 @@
+//! # Friends
+//!
+//! Mutual friendships between accounts.
+//!
+//! A friendship starts as a request from one user that the other accepts. If
+//! both send a request to each other, the second one accepts the first. Either
+//! side can remove the friendship again, which also cancels or declines a
+//! pending request. Players who have blocked each other can't become friends.
+//!
+//! The friends list shows whether each friend is online, i.e. holds an open
+//! `/api/search` socket (see [`crate::presence`]). Pending requests never reveal
+//! that.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use axum::Json;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use crate::database::{self, accept_friend, is_blocked, remove_friend, request_friend};
+use crate::{authenticated_user, db_error_status, other_user, AppMod};
+
+#[derive(Deserialize)]
+pub struct FriendRequest {
+    username: String,
+}
+
+#[derive(Serialize)]
+pub struct FriendEntry {
+    username: String,
+    status: String,
+    online: bool,
+}
+
+pub async fn list(State(appmod): State<AppMod>, headers: HeaderMap) -> Result<Json<Vec<FriendEntry>>, StatusCode> {
+    let me = authenticated_user(&appmod, &headers).await?;
+    let friends = database::friends(appmod.pg(), me).await.map_err(|e| db_error_status(&e))?;
+
+    let entries: Vec<FriendEntry> = friends
+        .into_iter()
+        .map(|friend| FriendEntry {
+            online: friend.status == "accepted" && appmod.presence.is_online(friend.id),
+            username: friend.username,
+            status: friend.status,
+        })
+        .collect();
+
+    Ok(Json(entries))
+}
+
+pub async fn request(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<FriendRequest>) -> impl IntoResponse {
+    let (me, other) = match other_user(&appmod, &headers, &payload.username).await {
+        Ok(ids) => ids,
+        Err(status) => return (status, Json(None)),
+    };
+
+    match is_blocked(appmod.pg(), me, other).await {
+        Ok(false) => {}
+        Ok(true) => return (StatusCode::FORBIDDEN, Json(None)),
+        Err(e) => return (db_error_status(&e), Json(None)),
+    }
+
+    match request_friend(appmod.pg(), me, other).await {
+        Ok(accepted) => {
+            let status = if accepted { "accepted" } else { "pending" };
+            info!("Friend request from {} to {}: {}", me, other, status);
+            (StatusCode::OK, Json(Some(serde_json::json!({ "status": status }))))
+        }
+        Err(e) => (db_error_status(&e), Json(None)),
+    }
+}
+
+pub async fn accept(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<FriendRequest>) -> StatusCode {
+    let (me, other) = match other_user(&appmod, &headers, &payload.username).await {
+        Ok(ids) => ids,
+        Err(status) => return status,
+    };
+
+    match accept_friend(appmod.pg(), me, other).await {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => db_error_status(&e),
+    }
+}
+
+pub async fn remove(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<FriendRequest>) -> StatusCode {
+    let (me, other) = match other_user(&appmod, &headers, &payload.username).await {
+        Ok(ids) => ids,
+        Err(status) => return status,
+    };
+
+    match remove_friend(appmod.pg(), me, other).await {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => db_error_status(&e),
+    }
+}
@@ -4,16 +4,176 @@
 //!
 //! ## Matchmaking flow
 //!
-//! 1. A player connects to `/api/search` and sends their session token.
+//! 1. A player connects to `/api/search` and sends their session token. A client
+//!    that stays silent for longer than `TOKEN_TIMEOUT_SECS` is disconnected.
+//!    Alternatively the token (and the other [`SearchQuery`] options) can be passed
+//!    as query parameters, `/api/search?token=…`, in which case it is validated
+//!    before the upgrade — an unknown token gets `401` — and no first message is
+//!    expected. An `Authorization: Bearer …` header on the upgrade request works
+//!    the same way and takes precedence over the query parameter.
+//!    The token message should carry the client's `"protocol_version"` (missing
+//!    means 1). Versions outside [`SUPPORTED_PROTOCOLS`] are answered with
+//!    `{"error": "unsupported_protocol", "server_supports": [...]}` and closed.
+//!    An optional `"client_version"` (semver) is compared with
+//!    `MIN_CLIENT_VERSION`: older clients get `{"error": "client_outdated",
+//!    "min_version": ...}` and are disconnected. Clients older than
+//!    `RECOMMENDED_CLIENT_VERSION` are let in with a `"warning"` instead.
 //! 2. The token is validated; the player is pushed into a [`VecDeque`]-based
 //!    matchmaking queue shared across all Tokio tasks via `Arc<Mutex<…>>`.
+//!    The token message may carry `"mode": "ranked"` (default) or `"casual"`;
+//!    each mode has its own queue and only ranked games update the standings.
+//!    While waiting, the player receives `{"queue_position": n, "estimated_wait_secs": s}`
+//!    whenever their place in the queue changes. The estimate comes from a
+//!    [`WaitEstimator`] fed with the wait time of every pairing.
+//!    Sending `{"type": "cancel"}` (or closing the socket) takes the player back
+//!    out of the queue; a cancel gets `{"type": "search_cancelled"}` before the
+//!    socket is closed. A cancel that arrives after an opponent was found is
+//!    ignored and the match goes ahead.
 //! 3. When a second player connects, both are paired and a dedicated `game` task
 //!    begins, driving the match with [`tokio::select!`] so moves from either side
 //!    are handled concurrently without blocking.
+//!    When several opponents are waiting, the one a player has just faced is
+//!    skipped in favour of someone new; rematches only happen when nobody else
+//!    is available. Players who have blocked each other are never paired; if
+//!    the only waiting opponent is blocked, the newcomer queues up instead.
+//!    A player is never paired with another connection of their own account:
+//!    the queue skips such entries, and a match that would still pit an account
+//!    against itself is refused with `{"error": "SELF_MATCH"}`.
+//!    A coin flip decides who plays `O` and therefore moves first.
+//!    Each player first receives a one-off `{"match_found": {...}}` message naming
+//!    both players and their symbol, followed by the initial board.
+//!    Both clients then confirm with `{"ready": true}`; until both have done so,
+//!    moves are answered with [`MoveResponse::Waiting`]. Once everyone is ready
+//!    the server sends `{"all_ready": true}` and the game clock starts. If a
+//!    player does not ready up within `READY_TIMEOUT_SECS` the match is
+//!    [`Status::Aborted`] with [`EndReason::NotReady`] and nothing is recorded.
 //! 4. After each valid move the updated [`Game`] state is serialised to JSON and
 //!    broadcast to both sockets.
+//!    The move is also added to the mover's log together with how long they
+//!    took over it, counted from the start of their turn; the log is saved with
+//!    the finished game.
+//!    Every `PING_INTERVAL_SECS` both sockets are pinged with a timestamped
+//!    `Ping`; the round-trip time of the last few `Pong`s is averaged per
+//!    connection and reported as `your_latency_ms` / `opponent_latency_ms`.
+//!    A connection that answers no ping for two intervals is treated as gone,
+//!    even if the socket never reported an error: its owner loses as on a
+//!    disconnect, and the opponent's final state carries
+//!    [`EndReason::Unresponsive`].
+//!    Either player may send `{"type": "chat", "text": …}` at any time; it is
+//!    passed to the opponent as `{"type": "chat", "from": …, "text": …}` without
+//!    touching the board (at most 500 characters, one line a second).
+//!    A `{"server_time": …, "game_remaining_ms": …}` message is sent when the
+//!    game starts and periodically after that, so clients can keep their clocks
+//!    in line with the server's.
+//!    A match that outlives `MAX_GAME_SECS` is ended as a [`Status::Draw`] with
+//!    [`EndReason::TimeLimit`], and one in which no move is accepted from either
+//!    side for `IDLE_GAME_SECS` as a draw with [`EndReason::Idle`].
+//!    A player who lets `TURN_TIMEOUT_SECS` pass without getting a move accepted
+//!    loses on the spot, with [`EndReason::TurnTimeout`]; refused moves and chat
+//!    don't restart their clock.
+//!    A player whose connection drops mid-game gets `RECONNECT_GRACE_SECS` to
+//!    come back: the game waits, the opponent is sent
+//!    `{"type": "opponent_disconnected", "reconnect_within_secs": n}`, and the
+//!    next `/api/search` connection with the same account is put back into the
+//!    game instead of a queue (see `AppMod::rejoins`). It receives
+//!    `match_found`, the current state and `all_ready` as if the game had just
+//!    started, the opponent gets `{"type": "opponent_reconnected"}`, and the turn
+//!    and idle clocks start over. If nobody returns in time, the game ends as a
+//!    disconnect always has.
 //! 5. On game-over or disconnect the winner/loser statistics are persisted to the
-//!    database and both connections are closed gracefully.
+//!    database and both connections are closed gracefully. The result is read
+//!    off the final statuses and recorded in one place, once per game, so a
+//!    socket that drops while a won game is being reported doesn't turn the
+//!    result around. Once a ranked game is
+//!    over the cached leaderboard is marked dirty so `/api/top10` picks it up.
+//! 6. A game that finished on the board (a win or a draw) is followed by a
+//!    rematch offer, open for `REMATCH_SECS`. If both players send
+//!    `{"type": "rematch"}` in time, a new game starts on the same sockets with
+//!    the symbols swapped, so the previous `X` player opens; otherwise both get
+//!    `rematch_declined` and are closed. Tournament games get no offer.
+//!
+//! ## Wire format
+//!
+//! Messages are JSON text frames by default. A token message (or query string)
+//! with `"format": "msgpack"` switches everything the server sends afterwards to
+//! MessagePack binary frames with the same field names. Incoming frames are
+//! decoded by type either way: text as JSON, binary as MessagePack. The token
+//! message itself is always JSON.
+//! The message types and both codecs live in [`crate::protocol`].
+//!
+//! Admin announcements published on `AppMod::announcements` are forwarded to every
+//! queued and in-game socket as a separate `{"announcement": "..."}` message; they
+//! never alter the game state.
+//!
+//! While `AppMod::maintenance` is set, new `/api/search` clients receive
+//! `{"error": "MAINTENANCE"}` once signed in and are disconnected; games already
+//! running continue. Likewise, once `MAX_GAMES` games are running new clients
+//! receive `{"error": "SERVER_FULL"}` until one of them ends. Neither applies to
+//! a player reconnecting to their own game.
+//!
+//! Every running match is registered in `AppMod::games` under a numeric id with a
+//! [`GameHandle`], whose control channel lets an admin end a stuck game as
+//! [`Status::Aborted`] without touching either player's statistics. The handle
+//! also keeps the latest [`Snapshot`] for spectators (see `spectate.rs`).
+//!
+//! ## Variants
+//!
+//! The token message may also select a rule [`Variant`] with `"variant"`; players
+//! are only paired with others who asked for the same one.
+//!
+//! - `standard` (default) — classic rules, each player places their own symbol.
+//! - `wild` — on your turn you may place either `X` or `O` (`{"field": 4, "symbol": "X"}`)
+//!   and whoever completes any three-in-a-row wins.
+//! - `ultimate` — nine boards in a 3×3 grid (see [`crate::ultimate`]); moves name the
+//!   sub-board too (`{"board": 2, "field": 4}`) and the full state is sent as `ultimate`.
+//! - `gravity` — connect-four on a taller board (see [`crate::gravity`]); `field` is a
+//!   column, the full state is sent as `gravity` and a full column is answered with
+//!   [`MoveResponse::ColumnFull`].
+//!
+//! Independently of the variant, `"misere": true` inverts the result: the player who
+//! completes a line loses. With `"blitz": true` each turn lasts `BLITZ_TURN_SECS`;
+//! when it runs out the server plays a random legal move for the player, tells
+//! them with `{"auto_move": {...}}` and passes the turn. `"opening": "center"` or
+//! `"corner"` limits the first move of a 3×3 game to those cells ([`Opening`]); a
+//! first move elsewhere is refused with `"refusal": "Opening"`. These settings travel
+//! together as [`Rules`].
+//!
+//! ## Handicap
+//!
+//! A weaker player can ask for a head start with `"handicap": [4]` (one or two
+//! cells, standard variant only, not already a line). Their symbol is placed on
+//! those cells before the first move and play proceeds normally. They are only
+//! matched with someone who sent `"give_handicap": true`; an invalid request is
+//! answered with `{"error": "INVALID_HANDICAP"}`. Handicap games are stored with
+//! `handicap = TRUE` and count towards wins and losses but leave Elo untouched.
+//!
+//! ## Short games
+//!
+//! A ranked game decided in fewer than `MIN_RATED_MOVES` moves (default 5, the
+//! length of the quickest possible win) was abandoned rather than played: someone
+//! left or ran out of time before the board could say anything. It still counts
+//! as a win and a loss, but Elo stays put, so walking out on an opponent you'd
+//! rather not face costs a loss and nothing buys a rating. Each one is logged at
+//! `info` for anyone keeping an eye out for the pattern.
+//!
+//! ## Bot games
+//!
+//! A token message carrying `"bot": "easy"` or `"bot": "hard"` skips the queue and
+//! starts [`bot_game`] against a [`Difficulty`]-selected AI. Bot games never touch
+//! the database standings.
+//!
+//! ## Challenges
+//!
+//! `"challenge": "<username>"` skips the queue too: [`challenge_game`] pairs the
+//! player with that friend once both have connected, provided one of them has
+//! challenged the other (see `challenge.rs`). Pending challenges for a player
+//! who connects without one are sent as `{"challenge": {...}}` messages first.
+//!
+//! ## Private rooms
+//!
+//! `"room": "<code>"` pairs the player with whoever else joins that room, see
+//! `room.rs`. [`room_game`] works like [`challenge_game`], except that the code
+//! rather than a friendship decides who plays whom.
 //!
 //! ## Author
 //! Marcel Gruszecki
@@ -21,19 +181,36 @@
 //! ## License
 //! MIT — see `LICENSE` in the repository root.
 
-use std::cmp::PartialEq;
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 use axum::Error;
-use axum::extract::State;
-use axum::extract::ws::Message;
-use axum::extract::ws::WebSocket;
-use axum::extract::ws::WebSocketUpgrade;
-use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
+use axum::extract::{ConnectInfo, Query, State};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use semver::Version;
+use rand::seq::IndexedRandom;
 use serde::{Deserialize, Serialize};
-use serde_json;
 use sqlx::{Pool, Postgres};
-use tokio::sync::oneshot;
-use crate::AppMod;
-use crate::database::{add_lose_id, add_win_id, does_token_exists, player_from_token};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::{error, info, warn};
+use crate::{bearer_token, db_error_status, AppMod, Queue};
+use crate::achievements::{check_achievements, FinishedGame};
+use crate::bot::{random_move, Difficulty};
+use crate::challenge::Join;
+use crate::room;
+use crate::gravity::GravityBoard;
+use crate::presence::PresenceGuard;
+use crate::webhook::GameFinished;
+use crate::protocol::{decode, parse_client_message, send_message, AutoMove, ChallengeInvite, Chat, ChatMessage, ClientMessage, MatchFound, Move, ProtocolError, Ready, SearchQuery, ServerMessage, TokenRequest, TournamentForfeit, WireFormat, SUPPORTED_PROTOCOLS};
+use crate::rate_limit::ConnectionPermit;
+use crate::spectate::CHAT_INTERVAL;
+use crate::ultimate::UltimateBoard;
+use crate::database::{award_achievements, player_stats, is_blocked, pending_tournament_matches, user_id_from_name, GameRecord, MoveRecord};
 
 const WINNING_COMBINATIONS: [[usize; 3]; 8] = [
     [0, 1, 2], [3, 4, 5], [6, 7, 8],
@@ -41,40 +218,355 @@ const WINNING_COMBINATIONS: [[usize; 3]; 8] = [
     [0, 4, 8], [2, 4, 6]
 ];
 
+const WAIT_EMA_ALPHA: f64 = 0.2;
+const WAIT_MIN_SAMPLES: u32 = 3;
+const WAIT_DEFAULT_SECS: u64 = 30;
+
+const LATENCY_SAMPLES: usize = 5;
+const TIME_SYNC_INTERVAL: Duration = Duration::from_secs(15);
+const MAX_PLAYER_CHAT_LEN: usize = 500;
+
+
+pub struct WaitEstimator {
+    average_secs: f64,
+    samples: u32,
+}
+
+impl WaitEstimator {
+    pub fn new() -> Self {
+        Self {
+            average_secs: 0.0,
+            samples: 0,
+        }
+    }
+
+    fn record(&mut self, waited: Duration) {
+        let secs = waited.as_secs_f64();
+        self.average_secs = if self.samples == 0 {
+            secs
+        } else {
+            WAIT_EMA_ALPHA * secs + (1.0 - WAIT_EMA_ALPHA) * self.average_secs
+        };
+        self.samples = self.samples.saturating_add(1);
+    }
+
+    fn estimate(&self, position: usize) -> u64 {
+        if self.samples < WAIT_MIN_SAMPLES {
+            return WAIT_DEFAULT_SECS;
+        }
+        (self.average_secs * position as f64).round() as u64
+    }
+}
+
+struct Latency {
+    origin: Instant,
+    samples: VecDeque<u64>,
+    /// When the connection last answered a ping; a socket can die without
+    /// ever returning an error, and this is how we find out.
+    last_pong: Instant,
+}
+
+impl Latency {
+    fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            samples: VecDeque::with_capacity(LATENCY_SAMPLES),
+            last_pong: Instant::now(),
+        }
+    }
+
+    fn ping(&self) -> Message {
+        let sent = self.origin.elapsed().as_millis() as u64;
+        Message::Ping(sent.to_be_bytes().to_vec().into())
+    }
+
+    fn pong(&mut self, payload: &[u8]) {
+        self.last_pong = Instant::now();
+        let Ok(bytes) = payload.try_into() else { return };
+        let sent = u64::from_be_bytes(bytes);
+        let now = self.origin.elapsed().as_millis() as u64;
+
+        if let Some(round_trip) = now.checked_sub(sent) {
+            if self.samples.len() == LATENCY_SAMPLES {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(round_trip);
+        }
+    }
+
+    fn average_ms(&self) -> Option<u64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().sum::<u64>() / self.samples.len() as u64)
+    }
+}
+
+pub struct QueueEntry {
+    player_id: i32,
+    rules: Rules,
+    handicap: Handicap,
+    last_opponent: Option<i32>,
+    #[cfg(feature = "redis-queue")]
+    ticket: String,
+    sender: oneshot::Sender<Player>,
+}
+
+#[cfg(feature = "redis-queue")]
+impl QueueEntry {
+    pub fn player_id(&self) -> i32 {
+        self.player_id
+    }
+
+    pub fn rules(&self) -> Rules {
+        self.rules
+    }
+
+    pub fn ticket(&self) -> &str {
+        &self.ticket
+    }
+}
+
+fn pick_opponent(queue: &VecDeque<QueueEntry>, player_id: i32, last_opponent: Option<i32>, rules: Rules, handicap: &Handicap, blocked: &HashSet<i32>) -> Option<usize> {
+    let compatible = |entry: &QueueEntry| {
+        entry.player_id != player_id
+            && entry.rules == rules
+            && entry.handicap.pairs_with(handicap)
+            && !blocked.contains(&entry.player_id)
+    };
+
+    let fresh = queue.iter().position(|entry| {
+        compatible(entry)
+            && entry.last_opponent != Some(player_id)
+            && last_opponent != Some(entry.player_id)
+    });
+
+    fresh.or_else(|| queue.iter().position(compatible))
+}
+
+async fn blocked_in_queue(appmod: &AppMod, mode: GameMode, player_id: i32) -> HashSet<i32> {
+    // Blocks are kept in Postgres; without it nobody has blocked anyone.
+    let Some(pool) = appmod.pool.clone() else { return HashSet::new() };
+    let waiting: Vec<i32> = queue_for(appmod, mode).lock().unwrap().iter().map(|entry| entry.player_id).collect();
+
+    let mut blocked = HashSet::new();
+    for id in waiting {
+        match is_blocked(pool.clone(), player_id, id).await {
+            Ok(true) => {
+                blocked.insert(id);
+            }
+            Ok(false) => {}
+            Err(e) => error!("Failed to check block list: {}", e),
+        }
+    }
+    blocked
+}
+
+pub struct GameHandle {
+    pub control: mpsc::Sender<GameControl>,
+    /// Latest [`Snapshot`] of the game. Spectators subscribe to it, so they get
+    /// the current board straight away and every change after that.
+    pub view: watch::Sender<Snapshot>,
+    /// Chat between spectators; the players never see it.
+    pub spectator_chat: broadcast::Sender<ChatMessage>,
+}
+
+pub enum GameControl {
+    End,
+}
+
 pub struct Player {
     id: i32,
     name: String,
-    response: SerwerResponse,
+    response: ServerResponse,
     socket: WebSocket,
+    format: WireFormat,
+    handicap: Handicap,
+    latency: Latency,
+    moves: Vec<MoveRecord>,
+    turn_started: Instant,
+    started_at: Option<DateTime<Utc>>,
+    last_chat: Option<Instant>,
+    presence: PresenceGuard,
+    /// Counts towards the per-IP connection limit until the socket is gone.
+    _permit: ConnectionPermit,
 }
 
 impl Player {
-    async fn new(socket: WebSocket, token: &str, pool: Pool<Postgres>) -> Self {
-        let (id, name) = player_from_token(pool.clone(), token).await;
+    async fn new(socket: WebSocket, permit: ConnectionPermit, (id, name): (i32, String), format: WireFormat, appmod: &AppMod) -> Self {
+        if let Err(e) = appmod.users.mark_seen(id).await {
+            warn!("Failed to update last_seen for {}: {}", name, e);
+        }
         Self {
             id,
             name,
             socket,
-            response: SerwerResponse::new(),
+            format,
+            handicap: Handicap::None,
+            response: ServerResponse::new(),
+            latency: Latency::new(),
+            moves: Vec::new(),
+            turn_started: Instant::now(),
+            started_at: None,
+            last_chat: None,
+            presence: appmod.presence.connect(id),
+            _permit: permit,
         }
     }
+
+    /// Moves a fresh connection of the same account into this player's seat,
+    /// keeping the game as it stood.
+    fn take_over(&mut self, connection: Player) {
+        self.socket = connection.socket;
+        self.format = connection.format;
+        self.presence = connection.presence;
+        self.presence.set_in_game(true);
+        self._permit = connection._permit;
+        self.latency = Latency::new();
+    }
 }
 #[derive(Deserialize, Serialize, Clone, Debug, Copy, PartialEq)]
-enum BoardOptions {
+pub enum BoardOptions {
     X,
     O,
     Null,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-struct Move {
-    field: usize,
+impl BoardOptions {
+    pub fn opposite(self) -> Self {
+        match self {
+            BoardOptions::O => BoardOptions::X,
+            BoardOptions::X => BoardOptions::O,
+            BoardOptions::Null => BoardOptions::Null,
+        }
+    }
+
+    pub fn as_cell(self) -> &'static str {
+        match self {
+            BoardOptions::O => "O",
+            BoardOptions::X => "X",
+            BoardOptions::Null => "",
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    #[default]
+    Standard,
+    Wild,
+    Ultimate,
+    Gravity,
+}
+
+/// Where the first move of a game may go. Only the 3×3 board variants
+/// (`standard` and `wild`) have an opening rule.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Opening {
+    #[default]
+    Any,
+    Center,
+    Corner,
+}
+
+impl Opening {
+    const CORNERS: [usize; 4] = [0, 2, 6, 8];
+
+    fn allows(self, field: usize) -> bool {
+        match self {
+            Opening::Any => true,
+            Opening::Center => field == 4,
+            Opening::Corner => Self::CORNERS.contains(&field),
+        }
+    }
+
+    fn fields(self) -> Vec<usize> {
+        (0..9).filter(|&field| self.allows(field)).collect()
+    }
+}
+
+/// A player's stance on head-start games. Players asking to [`Receive`](Handicap::Receive)
+/// marks are only paired with players who offered to [`Give`](Handicap::Give) them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Handicap {
+    #[default]
+    None,
+    Receive(Vec<usize>),
+    Give,
+}
+
+impl Handicap {
+    const MAX_MARKS: usize = 2;
+
+    fn from_request(cells: Option<Vec<usize>>, give: bool, rules: Rules) -> Option<Self> {
+        match (cells, give) {
+            (None, false) => Some(Handicap::None),
+            (None, true) => Some(Handicap::Give),
+            (Some(_), true) => None,
+            (Some(cells), false) => {
+                let distinct: HashSet<usize> = cells.iter().copied().collect();
+                if rules.variant != Variant::Standard
+                    || rules.opening != Opening::Any
+                    || cells.is_empty()
+                    || cells.len() > Self::MAX_MARKS
+                    || distinct.len() != cells.len()
+                    || cells.iter().any(|&cell| cell > 8)
+                {
+                    return None;
+                }
+
+                let mut board = [BoardOptions::Null; 9];
+                for &cell in &cells {
+                    board[cell] = BoardOptions::O;
+                }
+                (check_winner(&board) == Status::InGame).then_some(Handicap::Receive(cells))
+            }
+        }
+    }
+
+    fn pairs_with(&self, other: &Handicap) -> bool {
+        match (self, other) {
+            (Handicap::Receive(_), other) | (other, Handicap::Receive(_)) => *other == Handicap::Give,
+            _ => true,
+        }
+    }
+
+    fn place(&self, symbol: BoardOptions, board: &mut [BoardOptions; 9]) {
+        if let Handicap::Receive(cells) = self {
+            for &cell in cells {
+                board[cell] = symbol;
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rules {
+    #[serde(default)]
+    variant: Variant,
+    #[serde(default)]
+    misere: bool,
+    #[serde(default)]
+    blitz: bool,
+    #[serde(default)]
+    opening: Opening,
+}
+
+impl Rules {
+    /// An opening rule needs a 3×3 board.
+    pub fn is_valid(&self) -> bool {
+        self.opening == Opening::Any || matches!(self.variant, Variant::Standard | Variant::Wild)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 struct Game {
     board: [BoardOptions; 9],
     current_move: BoardOptions,
+    #[serde(flatten)]
+    rules: Rules,
 }
 
 impl Default for Game {
@@ -82,50 +574,163 @@ impl Default for Game {
         Self {
             board: [BoardOptions::Null; 9],
             current_move: BoardOptions::O,
+            rules: Rules::default(),
         }
     }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
-enum MoveResponse {
+pub enum MoveResponse {
     Accepted,
     Refused,
     OtherPlayer,
     Waiting,
+    ColumnFull,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
-enum Status {
+pub enum Status {
     InGame,
     Player1Won,
     Player2Won,
     Draw,
     Error,
+    Aborted,
+}
+
+/// Why a move came back [`MoveResponse::Refused`], where the rules say more than
+/// "not your turn" or "cell taken".
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+enum Refusal {
+    Opening,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+enum EndReason {
+    TimeLimit,
+    NotReady,
+    Idle,
+    TurnTimeout,
+    Unresponsive,
+}
+
+/// The game as an onlooker sees it: both players and the board, without either
+/// side's point of view.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    players: [String; 2],
+    game: Game,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<EndReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ultimate: Option<UltimateBoard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gravity: Option<GravityBoard>,
+}
+
+impl Snapshot {
+    fn of(player1: &Player, player2: &Player) -> Self {
+        // After a disconnect only the player who stayed has a final status.
+        let status = if player1.response.status != Status::InGame {
+            player1.response.status.clone()
+        } else {
+            player2.response.status.clone()
+        };
+
+        Self {
+            players: [player1.name.clone(), player2.name.clone()],
+            game: player1.response.game.clone(),
+            status,
+            reason: player1.response.reason.clone(),
+            ultimate: player1.response.ultimate.clone(),
+            gravity: player1.response.gravity.clone(),
+        }
+    }
+
+    pub fn players(&self) -> &[String; 2] {
+        &self.players
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.status != Status::InGame
+    }
+}
+
+enum ReadyOutcome {
+    Ready,
+    TimedOut,
+    Aborted,
+    Disconnected,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
-struct SerwerResponse {
+pub struct ServerResponse {
     game: Game,
     response: MoveResponse,
     status: Status,
     your_symbol: BoardOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<EndReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refusal: Option<Refusal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ultimate: Option<UltimateBoard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gravity: Option<GravityBoard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    your_latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    opponent_latency_ms: Option<u64>,
 }
 
-impl SerwerResponse {
+impl ServerResponse {
     fn new() -> Self {
         Self {
             game: Game::default(),
             response: MoveResponse::Waiting,
             status: Status::InGame,
             your_symbol: BoardOptions::Null,
+            reason: None,
+            refusal: None,
+            ultimate: None,
+            gravity: None,
+            your_latency_ms: None,
+            opponent_latency_ms: None,
         }
     }
+    /// `State` while the game runs, `Result` once it is over.
+    fn message(&self) -> ServerMessage {
+        if self.status == Status::InGame {
+            ServerMessage::State(Box::new(self.clone()))
+        } else {
+            ServerMessage::Result(Box::new(self.clone()))
+        }
+    }
+    fn final_board(&self) -> Vec<String> {
+        let cells = if let Some(ultimate) = &self.ultimate {
+            ultimate.cells()
+        } else if let Some(gravity) = &self.gravity {
+            gravity.cells().to_vec()
+        } else {
+            self.game.board.to_vec()
+        };
+
+        cells.into_iter().map(|cell| cell.as_cell().to_string()).collect()
+    }
+
     fn first_response_player1() -> Self {
         Self {
             game: Game::default(),
             response: MoveResponse::Waiting,
             status: Status::InGame,
             your_symbol: BoardOptions::O,
+            reason: None,
+            refusal: None,
+            ultimate: None,
+            gravity: None,
+            your_latency_ms: None,
+            opponent_latency_ms: None,
         }
     }
 
@@ -135,221 +740,1534 @@ impl SerwerResponse {
             response: MoveResponse::Waiting,
             status: Status::InGame,
             your_symbol: BoardOptions::X,
+            reason: None,
+            refusal: None,
+            ultimate: None,
+            gravity: None,
+            your_latency_ms: None,
+            opponent_latency_ms: None,
         }
     }
 }
 
-#[derive(Deserialize)]
-struct TokenRequest {
-    token: String,
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GameMode {
+    #[default]
+    Ranked,
+    Casual,
 }
 
-pub async fn websocket_connect(ws: WebSocketUpgrade, State(appmod): State<AppMod>) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| search_game(socket, appmod))
-}
+pub async fn websocket_connect(ws: WebSocketUpgrade, State(appmod): State<AppMod>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, Query(query): Query<SearchQuery>) -> Response {
+    let ip = match appmod.ip_filter.check(addr, &headers) {
+        Ok(ip) => ip,
+        Err(status) => return status.into_response(),
+    };
 
-async fn search_game(mut socket: WebSocket, appmod: AppMod) {
-    let msg = match socket.recv().await {
-        Some(Ok(Message::Text(t))) => t,
-        _ => {
-            eprintln!("Websocket connection problem in search_game function");
-            return;
-        }
+    let Some(permit) = appmod.connection_limiter.acquire(ip) else {
+        warn!("Too many WebSocket connections from {}", ip);
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
     };
 
-    let token_data: TokenRequest = match serde_json::from_str(&msg) {
-        Ok(data) => data,
-        Err(_) => {
-            eprintln!("Wrong JSON format");
-            return;
+    let token = bearer_token(&headers).map(str::to_owned).or(query.token);
+
+    let token_data = match token {
+        Some(token) => {
+            match appmod.users.player_from_token(&token, appmod.config.token_ttl.as_secs_f64()).await {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    warn!("Rejected unknown session token on upgrade");
+                    return StatusCode::UNAUTHORIZED.into_response();
+                }
+                Err(e) => {
+                    error!("Checking a session token on upgrade failed: {}", e);
+                    return db_error_status(&e).into_response();
+                }
+            }
+
+            Some(TokenRequest {
+                token,
+                protocol_version: query.protocol_version,
+                client_version: query.client_version,
+                bot: query.bot,
+                #[cfg(feature = "redis-queue")]
+                join: query.join,
+                mode: query.mode,
+                format: query.format,
+                handicap: query.handicap.map(|cells| {
+                    cells.split(',').map(|cell| cell.trim().parse().unwrap_or(usize::MAX)).collect()
+                }),
+                give_handicap: query.give_handicap,
+                tournament: query.tournament,
+                challenge: query.challenge,
+                room: query.room,
+                rules: Rules { variant: query.variant, misere: query.misere, blitz: query.blitz, opening: query.opening },
+            })
         }
+        None => None,
     };
 
-    let token = token_data.token;
-    let pool = appmod.pool.clone();
-    if !does_token_exists(pool.clone(), &token).await {
-        eprintln!("Token doesn't exist");
-        return;
-    }
-
-    let mut player = Player::new(socket, &token, pool.clone()).await;
+    ws.on_upgrade(move |socket| async move {
+        // A panic ends this connection only. The socket, permit, presence and any
+        // queue or game registration are all released as the task unwinds.
+        if let Err(e) = tokio::spawn(search_game(socket, permit, appmod, token_data)).await {
+            error!("WebSocket task failed: {}", e);
+        }
+    })
+}
 
-    let outcome = {
-        let mut queue = appmod.queue.lock().unwrap();
+/// The error a signed-in player is turned away with, if any. A player whose
+/// game is holding their seat always gets back in: during maintenance that game
+/// is what the server is waiting for, and it already counts towards `MAX_GAMES`.
+fn refusal(rejoining: bool, maintenance: bool, live_games: usize, max_games: usize) -> Option<&'static str> {
+    if rejoining {
+        None
+    } else if maintenance {
+        Some("MAINTENANCE")
+    } else if max_games > 0 && live_games >= max_games {
+        Some("SERVER_FULL")
+    } else {
+        None
+    }
+}
 
-        if queue.iter().any(|(id, _)| *id == player.id) {
-            QueueOutcome::AlreadySearching
-        } else if let Some((_, tx)) = queue.pop_front() {
-            QueueOutcome::Matched(tx)
-        } else {
-            let (tx, rx) = oneshot::channel::<Player>();
-            queue.push_back((player.id, tx));
-            QueueOutcome::Waiting(rx)
+async fn receive_token(socket: &mut WebSocket, appmod: &AppMod) -> Option<TokenRequest> {
+    let msg = match tokio::time::timeout(appmod.config.token_timeout, socket.recv()).await {
+        Ok(Some(Ok(Message::Text(t)))) => t,
+        Ok(_) => {
+            warn!("WebSocket closed or sent a non-text frame before authenticating");
+            return None;
+        }
+        Err(_) => {
+            warn!("No token received within {:?}, closing connection", appmod.config.token_timeout);
+            let _ = socket.send(Message::Close(None)).await;
+            return None;
         }
     };
 
-    match outcome {
-        QueueOutcome::AlreadySearching => {
-            eprintln!("{} is already searching for a game", player.name);
-            let _ = send_json(&mut player.socket, &serde_json::json!({ "error": "ALREADY_SEARCHING" })).await;
+    let token_data = match parse_client_message(&msg) {
+        Ok(ClientMessage::Token(data)) => data,
+        Ok(other) => {
+            warn!("Expected a token message, got {:?}", other.kind());
+            let _ = send_message(socket, WireFormat::Json, &ProtocolError::Unexpected(other.kind()).reply()).await;
+            return None;
         }
-        QueueOutcome::Matched(tx) => {
-            let _ = tx.send(player);
+        Err(e) => {
+            warn!("Malformed token message: {:?}", e);
+            let _ = send_message(socket, WireFormat::Json, &e.reply()).await;
+            return None;
         }
-        QueueOutcome::Waiting(rx) => {
-            if let Ok(mut opponent) = rx.await {
-                player.response = SerwerResponse::first_response_player1();
-                opponent.response = SerwerResponse::first_response_player2();
+    };
 
-                game(player, opponent, pool.clone()).await;
-            }
+    match appmod.users.player_from_token(&token_data.token, appmod.config.token_ttl.as_secs_f64()).await {
+        Ok(Some(_)) => Some(token_data),
+        Ok(None) => {
+            warn!("Rejected unknown session token");
+            None
+        }
+        Err(e) => {
+            error!("Checking a session token failed: {}", e);
+            let _ = send_message(socket, token_data.format, &ServerMessage::error("DATABASE_ERROR")).await;
+            let _ = socket.send(Message::Close(None)).await;
+            None
         }
     }
 }
 
-enum QueueOutcome {
-    AlreadySearching,
-    Matched(oneshot::Sender<Player>),
-    Waiting(oneshot::Receiver<Player>),
-}
-
-async fn game(mut player1: Player, mut player2: Player, pool: Pool<Postgres>) {
-    let player1 = &mut player1;
-    let player2 = &mut player2;
-
-    match full_send(player1, player2, pool.clone()).await {
-        Ok(_) => {}
-        Err(_) => { eprintln!("{} disconnected", player1.name); return }
+async fn search_game(mut socket: WebSocket, permit: ConnectionPermit, appmod: AppMod, token_data: Option<TokenRequest>) {
+    let token_data = match token_data {
+        Some(token_data) => token_data,
+        None => match receive_token(&mut socket, &appmod).await {
+            Some(token_data) => token_data,
+            None => return,
+        },
     };
 
-    match full_send(player2, player1, pool.clone()).await {
-        Ok(_) => {}
-        Err(_) => { eprintln!("{} disconnected", player2.name); return }
-    };
+    if !SUPPORTED_PROTOCOLS.contains(&token_data.protocol_version) {
+        warn!("Rejected client speaking unsupported protocol version {}", token_data.protocol_version);
+        let _ = send_message(&mut socket, token_data.format, &ServerMessage::error_with("unsupported_protocol", serde_json::json!({
+            "server_supports": SUPPORTED_PROTOCOLS,
+        }))).await;
+        let _ = socket.send(Message::Close(None)).await;
+        return;
+    }
 
-    let mut disconnected = false;
+    // A missing or unparsable version counts as older than any configured one.
+    let client_version = token_data.client_version.as_deref().and_then(|v| Version::parse(v).ok());
 
-    loop {
-        tokio::select! {
-            result1 = player1.socket.recv() => {
-                match player_handler(player1, player2, &result1, pool.clone()).await {
-                    Ok(_) => {
-                        if player1.response.status != Status::InGame { break }
-                        if player2.response.status != Status::InGame { break }
-                    }
-                    Err(_) => { disconnected = true; break; }
-                }
-            }
+    if let Some(min_version) = &appmod.config.min_client_version
+        && client_version.as_ref().is_none_or(|v| v < min_version)
+    {
+        warn!("Rejected client version {:?}, minimum is {}", token_data.client_version, min_version);
+        let _ = send_message(&mut socket, token_data.format, &ServerMessage::error_with("client_outdated", serde_json::json!({
+            "min_version": min_version.to_string(),
+        }))).await;
+        let _ = socket.send(Message::Close(None)).await;
+        return;
+    }
 
-            result2 = player2.socket.recv() => {
-                match player_handler(player2, player1, &result2, pool.clone()).await {
-                    Ok(_) => {
-                        if player1.response.status != Status::InGame { break }
-                        if player2.response.status != Status::InGame { break }
-                    }
-                    Err(_) => { disconnected = true; break; }
-                }
-            }
-        }
+    if let Some(recommended) = &appmod.config.recommended_client_version
+        && client_version.as_ref().is_none_or(|v| v < recommended)
+    {
+        let _ = send_message(&mut socket, token_data.format, &ServerMessage::Warning {
+            warning: "client_outdated",
+            recommended_version: recommended.to_string(),
+        }).await;
     }
 
-    if !disconnected {
-        match &player1.response.status {
-            Status::Player1Won => {
-                add_win_id(pool.clone(), player1.id).await;
-                add_lose_id(pool.clone(), player2.id).await;
-            }
-            Status::Player2Won => {
-                add_win_id(pool.clone(), player2.id).await;
-                add_lose_id(pool.clone(), player1.id).await;
-            }
-            _ => {}
-        }
+    if !token_data.rules.is_valid() {
+        let _ = send_message(&mut socket, token_data.format, &ServerMessage::error("INVALID_RULES")).await;
+        let _ = socket.send(Message::Close(None)).await;
+        return;
     }
-}
 
-async fn player_handler(sender: &mut Player, waiting_player: &mut Player, result: &Option<Result<Message, Error>>, pool: Pool<Postgres>) -> Result<(), Error> {
-    match result {
-        Some(Ok(message)) => {
-            match message.to_text() {
-                Ok(text) => {
-                    match serde_json::from_str::<Move>(text) {
-                        Ok(player_move) => {
-                            make_a_move(player_move, &mut sender.response, &mut waiting_player.response);
-
-                            match full_send(sender, waiting_player, pool.clone()).await {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    eprintln!("{} disconnected", sender.name);
-                                    return Err(e)
-                                }
-                            };
-
-                            match full_send(waiting_player, sender, pool.clone()).await {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    eprintln!("{} disconnected", waiting_player.name);
-                                    return Err(e)
-                                }
-                            };
-
-                            Ok(())
-                        }
-                        Err(_) => {
-                            sender.response.response = MoveResponse::Refused;
-                            match full_send(sender, waiting_player, pool.clone()).await {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    eprintln!("{} sent an wrong JSON format", waiting_player.name);
-                                    return Err(e)
-                                }
-                            };
-                            Ok(())
-                        }
-                    }
-                }
-                Err(err) => {
-                    Err(err)
-                }
-            }
-        }
+    let Some(handicap) = Handicap::from_request(token_data.handicap, token_data.give_handicap, token_data.rules) else {
+        let _ = send_message(&mut socket, token_data.format, &ServerMessage::error("INVALID_HANDICAP")).await;
+        let _ = socket.send(Message::Close(None)).await;
+        return;
+    };
 
-        _ => {
-            eprintln!("{} lost connection", waiting_player.name);
-            waiting_player.response.status = Status::Error;
-            add_win_id(pool.clone(), waiting_player.id).await;
-            add_lose_id(pool.clone(), sender.id).await;
-            let _ = send_json(&mut waiting_player.socket, &waiting_player.response).await;
-            Err(Error::new("Player disconnected or invalid state"))
+    let account = match appmod.users.player_from_token(&token_data.token, appmod.config.token_ttl.as_secs_f64()).await {
+        Ok(Some(account)) => account,
+        Ok(None) => {
+            warn!("Session token was revoked before the player could join");
+            let _ = socket.send(Message::Close(None)).await;
+            return;
         }
+        Err(e) => {
+            error!("Looking up the player for a session token failed: {}", e);
+            let _ = send_message(&mut socket, token_data.format, &ServerMessage::error("DATABASE_ERROR")).await;
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
+    };
+
+    let mut player = Player::new(socket, permit, account, token_data.format, &appmod).await;
+    player.handicap = handicap;
+
+    let rejoin = appmod.rejoins.lock().unwrap().remove(&player.id).filter(|tx| !tx.is_closed());
+    let live_games = appmod.games.lock().unwrap().len();
+    if let Some(reason) = refusal(rejoin.is_some(), appmod.maintenance.load(Ordering::Relaxed), live_games, appmod.config.max_games) {
+        let _ = send_message(&mut player.socket, player.format, &ServerMessage::error(reason)).await;
+        let _ = player.socket.send(Message::Close(None)).await;
+        return;
     }
-}
 
-fn make_a_move(from_user: Move, current_player: &mut SerwerResponse, waiting_player: &mut SerwerResponse) {
-    if current_player.your_symbol != current_player.game.current_move {
-        current_player.status = Status::InGame;
-        current_player.response = MoveResponse::Refused;
-        return
+    if let Some(tx) = rejoin {
+        // The game may have given up on them a moment ago; then they just go on
+        // to search as usual.
+        match tx.send(player) {
+            Ok(()) => return,
+            Err(returned) => player = returned,
+        }
     }
 
-    if current_player.status != Status::InGame {
-        current_player.response = MoveResponse::Refused;
-        return
+    if let Some(opponent) = &token_data.challenge {
+        challenge_game(player, appmod.clone(), opponent).await;
+        return;
     }
 
-    let board = &mut current_player.game.board;
-    let symbol = current_player.your_symbol;
+    if let Some(code) = &token_data.room {
+        room_game(player, appmod.clone(), &crate::room::normalize_code(code)).await;
+        return;
+    }
 
-    if from_user.field > 8 || board[from_user.field] != BoardOptions::Null {
-        current_player.response = MoveResponse::Refused;
-        return
+    let challenges = appmod.challenges.lock().unwrap().incoming(player.id);
+    for (from, rules, expires_at) in challenges {
+        let _ = send_message(&mut player.socket, player.format, &ServerMessage::Challenge {
+            challenge: ChallengeInvite { from, rules, expires_in_secs: expires_at.saturating_duration_since(Instant::now()).as_secs() },
+        }).await;
     }
 
-    board[from_user.field] = symbol;
+    if let Some(difficulty) = token_data.bot {
+        if token_data.rules != Rules::default() {
+            let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("BOT_RULES_UNSUPPORTED")).await;
+            let _ = player.socket.send(Message::Close(None)).await;
+            return;
+        }
+        bot_game(player, difficulty, appmod.clone()).await;
+        return;
+    }
 
-    let status = check_winner(board);
+    if let Some(tournament_id) = token_data.tournament {
+        tournament_game(player, appmod.clone(), tournament_id).await;
+        return;
+    }
 
-    if status != Status::InGame {
+    #[cfg(feature = "redis-queue")]
+    if let Some(ticket) = &token_data.join {
+        match crate::redis_queue::take_joined(&appmod, ticket) {
+            Some(entry) => {
+                let _ = entry.sender.send(player);
+            }
+            None => {
+                let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("JOIN_EXPIRED")).await;
+            }
+        }
+        return;
+    }
+
+    let mode = token_data.mode;
+    let rules = token_data.rules;
+    let last_opponent = appmod.last_opponents.lock().unwrap().get(&player.id).copied();
+    let blocked = blocked_in_queue(&appmod, mode, player.id).await;
+    let outcome = enqueue_or_match(&appmod, player.id, mode, rules, &player.handicap, last_opponent, &blocked);
+
+    // Handicap requests stay local: remote adverts don't say who would give one.
+    #[cfg(feature = "redis-queue")]
+    let outcome = match appmod.redis_queue.clone().filter(|_| !matches!(player.handicap, Handicap::Receive(_))) {
+        Some(redis) => {
+            let retry = || enqueue_or_match(&appmod, player.id, mode, rules, &player.handicap, last_opponent, &blocked);
+            redis.settle(&appmod, player.id, mode, rules, outcome, retry).await
+        }
+        None => outcome,
+    };
+
+    match outcome {
+        QueueOutcome::AlreadySearching => {
+            warn!("{} is already searching for a game", player.name);
+            let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("ALREADY_SEARCHING")).await;
+        }
+        QueueOutcome::Matched(entry) => {
+            let _ = entry.sender.send(player);
+        }
+        #[cfg(feature = "redis-queue")]
+        QueueOutcome::Redirect(url) => {
+            info!("Sending {} to {} to play an opponent on another instance", player.name, url);
+            let _ = send_message(&mut player.socket, player.format, &ServerMessage::Redirect { redirect: url }).await;
+            let _ = player.socket.send(Message::Close(None)).await;
+        }
+        QueueOutcome::Waiting(mut rx) => {
+            let _slot = QueueSlot { appmod: &appmod, mode, player_id: player.id };
+            let mut announcements = appmod.announcements.subscribe();
+            let mut notices = appmod.presence.notices();
+            let mut queue_changes = appmod.queue_changed.subscribe();
+            let mut last_position = None;
+            let mut socket_closed = false;
+            let queued_at = Instant::now();
+            let matched = loop {
+                let position = queue_position(&appmod, mode, player.id);
+                if let Some(position) = position.filter(|p| Some(*p) != last_position) {
+                    last_position = Some(position);
+                    let estimated_wait_secs = appmod.wait_estimate.lock().unwrap().estimate(position);
+                    let _ = send_message(&mut player.socket, player.format, &ServerMessage::QueuePosition {
+                        queue_position: position,
+                        estimated_wait_secs,
+                    }).await;
+                }
+
+                tokio::select! {
+                    result = &mut rx => break result,
+                    Ok(text) = announcements.recv() => {
+                        let _ = send_announcement(&mut player, &text).await;
+                    }
+                    Ok(notice) = notices.recv() => {
+                        if notice.user_id == player.id {
+                            let _ = send_message(&mut player.socket, player.format, &notice.message).await;
+                        }
+                    }
+                    Ok(()) = queue_changes.changed() => {}
+                    message = player.socket.recv(), if !socket_closed => match message {
+                        Some(Ok(message)) => {
+                            // A cancel that loses the race with an opponent is
+                            // ignored: the match is already made.
+                            if matches!(decode(&message), Ok(ClientMessage::Cancel)) && leave_queue(&appmod, mode, player.id) {
+                                info!("{} stopped searching", player.name);
+                                let _ = send_message(&mut player.socket, player.format, &ServerMessage::SearchCancelled).await;
+                                let _ = player.socket.send(Message::Close(None)).await;
+                                return;
+                            }
+                        }
+                        _ => {
+                            socket_closed = true;
+                            if leave_queue(&appmod, mode, player.id) {
+                                info!("{} left the queue", player.name);
+                                return;
+                            }
+                        }
+                    },
+                }
+            };
+
+            if let Ok(opponent) = matched {
+                appmod.wait_estimate.lock().unwrap().record(queued_at.elapsed());
+
+                play_match(player, opponent, &appmod, mode, rules, true).await;
+            }
+        }
+    }
+}
+
+/// Plays `player` against `opponent`: a coin flip for `O`, registration in
+/// `AppMod::games` and the [`game`] itself, repeated for as long as both take up
+/// the rematch offered after each finished game when `rematch` is set. Returns
+/// the result of the last game.
+pub async fn play_match(player: Player, opponent: Player, appmod: &AppMod, mode: GameMode, rules: Rules, rematch: bool) -> GameResult {
+    // Last line of defence against farming stats with two sockets of one
+    // account, whichever way they were brought together.
+    if player.id == opponent.id {
+        warn!("Refused to pair {} with their own second connection", player.name);
+        for mut connection in [player, opponent] {
+            let _ = send_message(&mut connection.socket, connection.format, &ServerMessage::error("SELF_MATCH")).await;
+        }
+        return GameResult::NoResult;
+    }
+
+    let (mut player1, mut player2) = coin_flip(player, opponent);
+
+    loop {
+        let result = play_one(&mut player1, &mut player2, appmod, mode, rules).await;
+        let finished = matches!(player1.response.status, Status::Player1Won | Status::Player2Won | Status::Draw);
+        if !rematch || !finished || closed_for_maintenance(&mut player1, &mut player2, appmod).await {
+            return result;
+        }
+        // Maintenance may also have begun while the offer was open.
+        if !rematch_agreed(&mut player1, &mut player2, appmod.config.rematch_timeout).await || closed_for_maintenance(&mut player1, &mut player2, appmod).await {
+            return result;
+        }
+
+        // The old `X` player is `O` now and opens the new game.
+        info!("{} and {} agreed to a rematch", player1.name, player2.name);
+        std::mem::swap(&mut player1, &mut player2);
+    }
+}
+
+/// The two in random order; whoever comes first plays `O` and opens.
+fn coin_flip<T>(a: T, b: T) -> (T, T) {
+    if rand::random::<bool>() {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+async fn play_one(player1: &mut Player, player2: &mut Player, appmod: &AppMod, mode: GameMode, rules: Rules) -> GameResult {
+    (player1.response, player2.response) = starting_responses(rules, appmod.config.gravity_size);
+
+    let mut board = player1.response.game.board;
+    player1.handicap.place(BoardOptions::O, &mut board);
+    player2.handicap.place(BoardOptions::X, &mut board);
+    player1.response.game.board = board;
+    player2.response.game.board = board;
+
+    {
+        let mut last_opponents = appmod.last_opponents.lock().unwrap();
+        last_opponents.insert(player1.id, player2.id);
+        last_opponents.insert(player2.id, player1.id);
+    }
+
+    let game_id = appmod.next_game_id.fetch_add(1, Ordering::Relaxed);
+    let (control_tx, control_rx) = mpsc::channel::<GameControl>(1);
+    let view = watch::channel(Snapshot::of(player1, player2)).0;
+    appmod.games.lock().unwrap().insert(game_id, GameHandle {
+        control: control_tx,
+        view: view.clone(),
+        spectator_chat: broadcast::channel(32).0,
+    });
+    let registration = Registration { appmod, game_id };
+    info!("Game {} started: {} (O) vs {} (X)", game_id, player1.name, player2.name);
+    player1.presence.set_in_game(true);
+    player2.presence.set_in_game(true);
+
+    let result = game(player1, player2, appmod, mode, control_rx, &view).await;
+
+    // Spectators still attached get the final board before the game goes away.
+    let mut last = Snapshot::of(player1, player2);
+    if !last.is_over() {
+        last.status = Status::Aborted;
+    }
+    view.send_replace(last);
+
+    if mode == GameMode::Ranked {
+        appmod.top10_cache.mark_dirty();
+    }
+    appmod.stats_cache.mark_dirty();
+
+    drop(registration);
+    result
+}
+
+/// Offers both players a rematch and waits up to `timeout` for both to send
+/// `{"type": "rematch"}`. Either one leaving, or the time running out, declines
+/// it and closes both sockets.
+async fn rematch_agreed(player1: &mut Player, player2: &mut Player, timeout: Duration) -> bool {
+    if timeout.is_zero() {
+        return false;
+    }
+
+    let offer = ServerMessage::RematchOffered { expires_in_secs: timeout.as_secs() };
+    let _ = send_message(&mut player1.socket, player1.format, &offer).await;
+    let _ = send_message(&mut player2.socket, player2.format, &offer).await;
+
+    let deadline = sleep(timeout);
+    tokio::pin!(deadline);
+    let (mut accepted1, mut accepted2) = (false, false);
+
+    while !(accepted1 && accepted2) {
+        tokio::select! {
+            _ = &mut deadline => break,
+            message = player1.socket.recv() => match message {
+                Some(Ok(message)) => {
+                    if !accepted1 && is_rematch(&message) {
+                        accepted1 = true;
+                        let _ = send_message(&mut player2.socket, player2.format, &ServerMessage::RematchRequested { by: player1.name.clone() }).await;
+                    }
+                }
+                _ => break,
+            },
+            message = player2.socket.recv() => match message {
+                Some(Ok(message)) => {
+                    if !accepted2 && is_rematch(&message) {
+                        accepted2 = true;
+                        let _ = send_message(&mut player1.socket, player1.format, &ServerMessage::RematchRequested { by: player2.name.clone() }).await;
+                    }
+                }
+                _ => break,
+            },
+        }
+    }
+
+    if accepted1 && accepted2 {
+        return true;
+    }
+
+    for player in [player1, player2] {
+        let _ = send_message(&mut player.socket, player.format, &ServerMessage::RematchDeclined).await;
+        let _ = player.socket.send(Message::Close(None)).await;
+    }
+    false
+}
+
+/// During maintenance no new game starts, rematches included, or the server
+/// would never finish draining; both players are told `MAINTENANCE` instead.
+async fn closed_for_maintenance(player1: &mut Player, player2: &mut Player, appmod: &AppMod) -> bool {
+    if !appmod.maintenance.load(Ordering::Relaxed) {
+        return false;
+    }
+    for player in [player1, player2] {
+        let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("MAINTENANCE")).await;
+        let _ = player.socket.send(Message::Close(None)).await;
+    }
+    true
+}
+
+fn is_rematch(message: &Message) -> bool {
+    matches!(decode(message), Ok(ClientMessage::Rematch))
+}
+
+/// A game's entry in `AppMod::games`, removed when this is dropped — also when
+/// the game task panics, so spectators aren't left watching a game that is gone.
+struct Registration<'a> {
+    appmod: &'a AppMod,
+    game_id: u64,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut games) = self.appmod.games.lock() {
+            games.remove(&self.game_id);
+        }
+    }
+}
+
+/// A waiting player's queue entry. Dropping it takes the entry out again if no
+/// one has matched it yet, so a task that ends early leaves nothing behind.
+struct QueueSlot<'a> {
+    appmod: &'a AppMod,
+    mode: GameMode,
+    player_id: i32,
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        leave_queue(self.appmod, self.mode, self.player_id);
+    }
+}
+
+/// Takes the player's entry out of the queue. `false` if it is no longer there
+/// because an opponent has taken it and is on their way through the oneshot.
+fn leave_queue(appmod: &AppMod, mode: GameMode, player_id: i32) -> bool {
+    let Ok(mut queue) = queue_for(appmod, mode).lock() else { return false };
+    let Some(index) = queue.iter().position(|entry| entry.player_id == player_id) else { return false };
+    queue.remove(index);
+    appmod.queue_changed.send_replace(());
+    true
+}
+
+fn starting_responses(rules: Rules, gravity_size: (usize, usize)) -> (ServerResponse, ServerResponse) {
+    let mut first = ServerResponse::first_response_player1();
+    let mut second = ServerResponse::first_response_player2();
+
+    for response in [&mut first, &mut second] {
+        response.game.rules = rules;
+        if rules.variant == Variant::Ultimate {
+            response.ultimate = Some(UltimateBoard::default());
+        }
+        if rules.variant == Variant::Gravity {
+            response.gravity = Some(GravityBoard::new(gravity_size.0, gravity_size.1));
+        }
+    }
+
+    (first, second)
+}
+
+fn queue_position(appmod: &AppMod, mode: GameMode, player_id: i32) -> Option<usize> {
+    queue_for(appmod, mode).lock().unwrap().iter().position(|entry| entry.player_id == player_id).map(|index| index + 1)
+}
+
+/// How a match ended, as far as standings are concerned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GameResult {
+    Won(i32),
+    Draw,
+    /// Aborted, or someone left before it started; nothing was recorded.
+    NoResult,
+}
+
+pub enum QueueOutcome {
+    AlreadySearching,
+    Matched(QueueEntry),
+    Waiting(oneshot::Receiver<Player>),
+    #[cfg(feature = "redis-queue")]
+    Redirect(String),
+}
+
+fn queue_for(appmod: &AppMod, mode: GameMode) -> &Queue {
+    match mode {
+        GameMode::Ranked => &appmod.ranked_queue,
+        GameMode::Casual => &appmod.casual_queue,
+    }
+}
+
+pub fn enqueue_or_match(appmod: &AppMod, player_id: i32, mode: GameMode, rules: Rules, handicap: &Handicap, last_opponent: Option<i32>, blocked: &HashSet<i32>) -> QueueOutcome {
+    let mut ranked_queue = appmod.ranked_queue.lock().unwrap();
+    let mut casual_queue = appmod.casual_queue.lock().unwrap();
+    let already_searching = ranked_queue.iter().chain(casual_queue.iter()).any(|entry| entry.player_id == player_id);
+    let queue = match mode {
+        GameMode::Ranked => &mut ranked_queue,
+        GameMode::Casual => &mut casual_queue,
+    };
+
+    if already_searching {
+        QueueOutcome::AlreadySearching
+    } else if let Some(index) = pick_opponent(queue, player_id, last_opponent, rules, handicap, blocked) {
+        let entry = queue.remove(index).unwrap();
+        appmod.queue_changed.send_replace(());
+        QueueOutcome::Matched(entry)
+    } else {
+        let (tx, rx) = oneshot::channel::<Player>();
+        queue.push_back(QueueEntry {
+            player_id,
+            rules,
+            handicap: handicap.clone(),
+            last_opponent,
+            #[cfg(feature = "redis-queue")]
+            ticket: uuid::Uuid::new_v4().simple().to_string(),
+            sender: tx,
+        });
+        appmod.queue_changed.send_replace(());
+        QueueOutcome::Waiting(rx)
+    }
+}
+
+/// Removes the waiting entry holding `ticket` from either queue.
+#[cfg(feature = "redis-queue")]
+pub fn take_queued(appmod: &AppMod, ticket: &str) -> Option<(GameMode, QueueEntry)> {
+    for mode in [GameMode::Ranked, GameMode::Casual] {
+        let mut queue = queue_for(appmod, mode).lock().unwrap();
+        if let Some(index) = queue.iter().position(|entry| entry.ticket == ticket) {
+            let entry = queue.remove(index).unwrap();
+            appmod.queue_changed.send_replace(());
+            return Some((mode, entry));
+        }
+    }
+    None
+}
+
+/// Ticket of `player_id`'s waiting entry, if they are queued.
+#[cfg(feature = "redis-queue")]
+pub fn queued_ticket(appmod: &AppMod, mode: GameMode, player_id: i32) -> Option<String> {
+    queue_for(appmod, mode).lock().unwrap().iter().find(|entry| entry.player_id == player_id).map(|entry| entry.ticket.clone())
+}
+
+/// Puts a previously taken entry back at the head of its queue.
+#[cfg(feature = "redis-queue")]
+pub fn requeue(appmod: &AppMod, mode: GameMode, entry: QueueEntry) {
+    queue_for(appmod, mode).lock().unwrap().push_front(entry);
+    appmod.queue_changed.send_replace(());
+}
+
+async fn game(player1: &mut Player, player2: &mut Player, appmod: &AppMod, mode: GameMode, mut control: mpsc::Receiver<GameControl>, view: &watch::Sender<Snapshot>) -> GameResult {
+
+    let _ = send_match_found(player1, &player2.name).await;
+    let _ = send_match_found(player2, &player1.name).await;
+
+    match full_send(player1, player2).await {
+        Ok(_) => {}
+        Err(_) => { warn!("{} disconnected before the game started", player1.name); return GameResult::NoResult }
+    };
+
+    match full_send(player2, player1).await {
+        Ok(_) => {}
+        Err(_) => { warn!("{} disconnected before the game started", player2.name); return GameResult::NoResult }
+    };
+
+    let outcome = ready_up(player1, player2, appmod, &mut control).await;
+    if !matches!(outcome, ReadyOutcome::Ready) {
+        info!("{} vs {} was aborted before both players were ready", player1.name, player2.name);
+        for player in [&mut *player1, &mut *player2] {
+            player.response.status = Status::Aborted;
+            if matches!(outcome, ReadyOutcome::TimedOut) {
+                player.response.reason = Some(EndReason::NotReady);
+            }
+            let _ = send_message(&mut player.socket, player.format, &player.response.message()).await;
+        }
+        return GameResult::NoResult;
+    }
+
+    let all_ready = ServerMessage::AllReady { all_ready: true };
+    let _ = send_message(&mut player1.socket, player1.format, &all_ready).await;
+    let _ = send_message(&mut player2.socket, player2.format, &all_ready).await;
+    player1.turn_started = Instant::now();
+    player2.turn_started = Instant::now();
+    player1.started_at = Some(Utc::now());
+    player2.started_at = player1.started_at;
+    player1.latency.last_pong = Instant::now();
+    player2.latency.last_pong = Instant::now();
+
+    let deadline = Instant::now() + appmod.config.max_game_duration;
+    let blitz = player1.response.game.rules.blitz;
+    let mut turn = player1.response.game.current_move;
+    let mut turn_deadline = Instant::now() + appmod.config.blitz_turn;
+    let turn_timeout = appmod.config.turn_timeout;
+    let mut forfeit_deadline = Instant::now() + turn_timeout;
+    let idle_timeout = appmod.config.idle_game_timeout;
+    let mut moves_played = 0;
+    let mut idle_deadline = Instant::now() + idle_timeout;
+    let ping_interval = appmod.config.ping_interval;
+    let mut ping = tokio::time::interval(ping_interval.max(Duration::from_secs(1)));
+    let mut time_sync = tokio::time::interval(TIME_SYNC_INTERVAL);
+    let mut disconnected = false;
+    let mut aborted = false;
+    let mut draw_reason = None;
+    let mut announcements = appmod.announcements.subscribe();
+    let mut notices = appmod.presence.notices();
+
+    loop {
+        let mut lost_connection = false;
+
+        if player1.response.game.current_move != turn {
+            turn = player1.response.game.current_move;
+            turn_deadline = Instant::now() + appmod.config.blitz_turn;
+            forfeit_deadline = Instant::now() + turn_timeout;
+        }
+
+        if player1.moves.len() + player2.moves.len() != moves_played {
+            moves_played = player1.moves.len() + player2.moves.len();
+            idle_deadline = Instant::now() + idle_timeout;
+        }
+
+        let silence_deadline = player1.latency.last_pong.min(player2.latency.last_pong) + ping_interval * 2;
+
+        view.send_if_modified(|snapshot| {
+            let current = Snapshot::of(player1, player2);
+            let changed = *snapshot != current;
+            *snapshot = current;
+            changed
+        });
+
+        tokio::select! {
+            Ok(text) = announcements.recv() => {
+                let _ = send_announcement(player1, &text).await;
+                let _ = send_announcement(player2, &text).await;
+            }
+
+            Ok(notice) = notices.recv() => {
+                for player in [&mut *player1, &mut *player2] {
+                    if player.id == notice.user_id {
+                        let _ = send_message(&mut player.socket, player.format, &notice.message).await;
+                    }
+                }
+            }
+
+            _ = sleep_until(turn_deadline), if blitz => {
+                let (mover, waiting) = if player1.response.your_symbol == turn {
+                    (&mut *player1, &mut *player2)
+                } else {
+                    (&mut *player2, &mut *player1)
+                };
+
+                match auto_move(mover, waiting).await {
+                    Ok(_) => {
+                        if player1.response.status != Status::InGame { break }
+                        if player2.response.status != Status::InGame { break }
+                    }
+                    Err(_) => lost_connection = true,
+                }
+            }
+
+            _ = sleep_until(forfeit_deadline), if !turn_timeout.is_zero() => {
+                let (mover, waiting) = if player1.response.your_symbol == turn {
+                    (&mut *player1, &mut *player2)
+                } else {
+                    (&mut *player2, &mut *player1)
+                };
+
+                info!("{} took longer than {:?} to move and forfeits to {}", mover.name, turn_timeout, waiting.name);
+                let status = win_for(waiting.response.your_symbol);
+                for player in [mover, waiting] {
+                    player.response.status = status.clone();
+                    player.response.reason = Some(EndReason::TurnTimeout);
+                    player.response.game.current_move = BoardOptions::Null;
+                    let _ = send_message(&mut player.socket, player.format, &player.response.message()).await;
+                }
+                break;
+            }
+
+            Some(GameControl::End) = control.recv() => {
+                aborted = true;
+                break;
+            }
+
+            _ = sleep_until(deadline) => {
+                draw_reason = Some(EndReason::TimeLimit);
+                break;
+            }
+
+            _ = sleep_until(idle_deadline), if !idle_timeout.is_zero() => {
+                draw_reason = Some(EndReason::Idle);
+                break;
+            }
+
+            _ = sleep_until(silence_deadline), if !ping_interval.is_zero() => {
+                let (silent, connected) = if player1.latency.last_pong <= player2.latency.last_pong {
+                    (&mut *player1, &mut *player2)
+                } else {
+                    (&mut *player2, &mut *player1)
+                };
+
+                warn!("{} stopped answering pings", silent.name);
+                connected.response.status = Status::Error;
+                connected.response.reason = Some(EndReason::Unresponsive);
+                lost_connection = true;
+            }
+
+            _ = ping.tick(), if !ping_interval.is_zero() => {
+                let _ = player1.socket.send(player1.latency.ping()).await;
+                let _ = player2.socket.send(player2.latency.ping()).await;
+            }
+
+            _ = time_sync.tick() => {
+                let _ = send_time_sync(player1, deadline).await;
+                let _ = send_time_sync(player2, deadline).await;
+            }
+
+            result1 = player1.socket.recv() => {
+                match player_handler(player1, player2, &result1).await {
+                    Ok(_) => {
+                        if player1.response.status != Status::InGame { break }
+                        if player2.response.status != Status::InGame { break }
+                    }
+                    Err(_) => lost_connection = true,
+                }
+            }
+
+            result2 = player2.socket.recv() => {
+                match player_handler(player2, player1, &result2).await {
+                    Ok(_) => {
+                        if player1.response.status != Status::InGame { break }
+                        if player2.response.status != Status::InGame { break }
+                    }
+                    Err(_) => lost_connection = true,
+                }
+            }
+        }
+
+        if lost_connection {
+            match hold_for_rejoin(player1, player2, appmod, &mut control).await {
+                Rejoin::Back => {
+                    turn_deadline = Instant::now() + appmod.config.blitz_turn;
+                    forfeit_deadline = Instant::now() + turn_timeout;
+                    idle_deadline = Instant::now() + idle_timeout;
+                }
+                Rejoin::Gone => { disconnected = true; break; }
+                Rejoin::Aborted => { aborted = true; break; }
+            }
+        }
+    }
+
+    if aborted {
+        player1.response.status = Status::Aborted;
+        player2.response.status = Status::Aborted;
+        let _ = send_message(&mut player1.socket, player1.format, &player1.response.message()).await;
+        let _ = send_message(&mut player2.socket, player2.format, &player2.response.message()).await;
+        return GameResult::NoResult;
+    }
+
+    if let Some(reason) = draw_reason {
+        match reason {
+            EndReason::Idle => info!("{} vs {} saw no move for {:?}, ending as a draw", player1.name, player2.name, idle_timeout),
+            _ => info!("{} vs {} hit the time limit, ending as a draw", player1.name, player2.name),
+        }
+        for player in [&mut *player1, &mut *player2] {
+            player.response.status = Status::Draw;
+            player.response.reason = Some(reason.clone());
+            player.response.game.current_move = BoardOptions::Null;
+            let _ = send_message(&mut player.socket, player.format, &player.response.message()).await;
+        }
+    }
+
+    if disconnected {
+        info!("{} vs {} ended with a disconnect", player1.name, player2.name);
+    }
+
+    // The one place a game that got going reaches the standings.
+    let result = final_result((player1.id, &player1.response.status), (player2.id, &player2.response.status));
+    match result {
+        GameResult::Won(winner_id) => record_game(appmod, mode, player1, player2, Some(winner_id)).await,
+        GameResult::Draw => record_game(appmod, mode, player1, player2, None).await,
+        GameResult::NoResult => {}
+    }
+    result
+}
+
+enum Rejoin {
+    Back,
+    Gone,
+    Aborted,
+}
+
+/// Keeps a game open for `RECONNECT_GRACE_SECS` after one player's connection
+/// is lost, so they can connect to `/api/search` again and carry on. A lost
+/// connection marks the player who stayed with `Status::Error`, which is how
+/// the two are told apart. If the time runs out, or the other player leaves as
+/// well, the one who dropped first loses as they always have.
+async fn hold_for_rejoin(player1: &mut Player, player2: &mut Player, appmod: &AppMod, control: &mut mpsc::Receiver<GameControl>) -> Rejoin {
+    let (dropped, stayed) = match (&player1.response.status, &player2.response.status) {
+        (Status::InGame, Status::Error) => (player1, player2),
+        (Status::Error, Status::InGame) => (player2, player1),
+        // The board was already decided; only the result failed to go out.
+        _ => return Rejoin::Gone,
+    };
+
+    let grace = appmod.config.reconnect_grace;
+    let reason = stayed.response.reason.take();
+    let returned = if grace.is_zero() {
+        None
+    } else {
+        stayed.response.status = Status::InGame;
+        let (tx, mut rx) = oneshot::channel::<Player>();
+        appmod.rejoins.lock().unwrap().insert(dropped.id, tx);
+        info!("Holding {}'s game against {} for {:?} to let them reconnect", dropped.name, stayed.name, grace);
+        let _ = send_message(&mut stayed.socket, stayed.format, &ServerMessage::OpponentDisconnected {
+            reconnect_within_secs: grace.as_secs(),
+        }).await;
+
+        let deadline = Instant::now() + grace;
+        let returned = loop {
+            tokio::select! {
+                result = &mut rx => break result.ok(),
+                _ = sleep_until(deadline) => break None,
+                Some(GameControl::End) = control.recv() => {
+                    drop(rx);
+                    appmod.rejoins.lock().unwrap().retain(|_, tx| !tx.is_closed());
+                    return Rejoin::Aborted;
+                }
+                message = stayed.socket.recv() => match message {
+                    Some(Ok(Message::Pong(payload))) => stayed.latency.pong(&payload),
+                    // Nothing to move against or chat to until they're back.
+                    Some(Ok(_)) => {}
+                    _ => {
+                        warn!("{} left while waiting for {} to reconnect", stayed.name, dropped.name);
+                        break None;
+                    }
+                },
+            }
+        };
+        drop(rx);
+        appmod.rejoins.lock().unwrap().retain(|_, tx| !tx.is_closed());
+        returned
+    };
+
+    let Some(connection) = returned else {
+        stayed.response.status = Status::Error;
+        stayed.response.reason = reason;
+        stayed.response.game.current_move = BoardOptions::Null;
+        let _ = send_message(&mut stayed.socket, stayed.format, &stayed.response.message()).await;
+        return Rejoin::Gone;
+    };
+
+    info!("{} reconnected to their game against {}", dropped.name, stayed.name);
+    dropped.take_over(connection);
+    stayed.latency.last_pong = Instant::now();
+    let _ = send_match_found(dropped, &stayed.name).await;
+    let _ = full_send(dropped, stayed).await;
+    let _ = send_message(&mut dropped.socket, dropped.format, &ServerMessage::AllReady { all_ready: true }).await;
+    let _ = send_message(&mut stayed.socket, stayed.format, &ServerMessage::OpponentReconnected).await;
+    Rejoin::Back
+}
+
+/// Reads the result off the final statuses. A decided board counts even if a
+/// socket dropped while the result was being sent; a disconnect mid-game leaves
+/// the player who stayed connected with `Status::Error`, and they win.
+fn final_result((id1, status1): (i32, &Status), (id2, status2): (i32, &Status)) -> GameResult {
+    match (status1, status2) {
+        (Status::Player1Won, _) => GameResult::Won(id1),
+        (Status::Player2Won, _) => GameResult::Won(id2),
+        (Status::Draw, _) => GameResult::Draw,
+        (Status::Error, _) => GameResult::Won(id1),
+        (_, Status::Error) => GameResult::Won(id2),
+        _ => GameResult::NoResult,
+    }
+}
+
+async fn ready_up(player1: &mut Player, player2: &mut Player, appmod: &AppMod, control: &mut mpsc::Receiver<GameControl>) -> ReadyOutcome {
+    let deadline = Instant::now() + appmod.config.ready_timeout;
+    let mut ready = (false, false);
+
+    while !(ready.0 && ready.1) {
+        tokio::select! {
+            Some(GameControl::End) = control.recv() => return ReadyOutcome::Aborted,
+
+            _ = sleep_until(deadline) => return ReadyOutcome::TimedOut,
+
+            result1 = player1.socket.recv() => match ready_message(player1, result1).await {
+                Some(is_ready) => ready.0 |= is_ready,
+                None => return ReadyOutcome::Disconnected,
+            },
+
+            result2 = player2.socket.recv() => match ready_message(player2, result2).await {
+                Some(is_ready) => ready.1 |= is_ready,
+                None => return ReadyOutcome::Disconnected,
+            },
+        }
+    }
+
+    ReadyOutcome::Ready
+}
+
+async fn ready_message(player: &mut Player, result: Option<Result<Message, Error>>) -> Option<bool> {
+    let Some(Ok(message)) = result else { return None };
+
+    match message {
+        Message::Close(_) => return None,
+        Message::Ping(_) | Message::Pong(_) => return Some(false),
+        _ => {}
+    }
+
+    match decode(&message) {
+        Ok(ClientMessage::Ready(Ready { ready: true })) => return Some(true),
+        // Early moves and the like just get the waiting state again.
+        Ok(_) => {}
+        Err(e) => send_message(&mut player.socket, player.format, &e.reply()).await.ok()?,
+    }
+
+    player.response.response = MoveResponse::Waiting;
+    send_message(&mut player.socket, player.format, &player.response.message()).await.ok()?;
+    Some(false)
+}
+
+/// Pairs the player with an opponent from one of their open tournament matches
+/// instead of going through matchmaking. Whoever connects first waits in the
+/// lobby, and wins by forfeit if nobody comes within `TOURNAMENT_NO_SHOW_SECS`.
+async fn tournament_game(mut player: Player, appmod: AppMod, tournament_id: i64) {
+    player.handicap = Handicap::None;
+
+    // Tournaments are only created with Postgres.
+    let Some(pool) = appmod.pool.clone() else {
+        let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("NO_TOURNAMENT_MATCH")).await;
+        return;
+    };
+    let matches = match pending_tournament_matches(pool, tournament_id, player.id).await {
+        Ok(matches) if !matches.is_empty() => matches,
+        Ok(_) => {
+            let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("NO_TOURNAMENT_MATCH")).await;
+            return;
+        }
+        Err(e) => {
+            error!("Failed to look up tournament matches for {}: {}", player.name, e);
+            return;
+        }
+    };
+
+    let waiting = {
+        let mut lobby = appmod.tournament_lobby.lock().unwrap();
+        match lobby.take_opponent(player.id, &matches) {
+            Some(opponent) => Ok(opponent),
+            None => {
+                let (tx, rx) = oneshot::channel::<(Player, i64)>();
+                lobby.wait(player.id, &matches, tx);
+                Err(rx)
+            }
+        }
+    };
+
+    let mut rx = match waiting {
+        Ok((match_id, sender)) => {
+            if let Err((mut player, _)) = sender.send((player, match_id)) {
+                warn!("Tournament opponent of {} left the lobby", player.name);
+                let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("OPPONENT_LEFT")).await;
+            }
+            return;
+        }
+        Err(rx) => rx,
+    };
+
+    let _ = send_message(&mut player.socket, player.format, &ServerMessage::TournamentWaiting { tournament_waiting: matches.clone() }).await;
+    let mut announcements = appmod.announcements.subscribe();
+    let no_show = sleep(appmod.config.tournament_no_show);
+    tokio::pin!(no_show);
+    let mut player_left = false;
+
+    let matched = loop {
+        tokio::select! {
+            result = &mut rx => break result,
+            Ok(text) = announcements.recv() => {
+                let _ = send_announcement(&mut player, &text).await;
+            }
+            message = player.socket.recv(), if !player_left => {
+                // Someone who disconnects while waiting can't win by forfeit.
+                if !matches!(message, Some(Ok(_))) {
+                    player_left = true;
+                    if appmod.tournament_lobby.lock().unwrap().leave(player.id).is_some() {
+                        return;
+                    }
+                }
+            }
+            _ = &mut no_show, if !player_left => {
+                // If an opponent has just taken us out of the lobby, their
+                // player is already on its way through `rx`.
+                if appmod.tournament_lobby.lock().unwrap().leave(player.id).is_some() {
+                    crate::tournament::record_forfeit(&appmod, matches[0], player.id).await;
+                    let _ = send_message(&mut player.socket, player.format, &ServerMessage::TournamentForfeit {
+                        tournament_forfeit: TournamentForfeit { match_id: matches[0], winner: player.name.clone() },
+                    }).await;
+                    let _ = player.socket.send(Message::Close(None)).await;
+                    return;
+                }
+            }
+        }
+    };
+
+    if let Ok((opponent, match_id)) = matched {
+        // A bracket match is decided by one game.
+        let result = play_match(player, opponent, &appmod, GameMode::Ranked, Rules::default(), false).await;
+        crate::tournament::record_result(&appmod, match_id, result).await;
+    }
+}
+
+async fn challenge_game(mut player: Player, appmod: AppMod, opponent: &str) {
+    player.handicap = Handicap::None;
+
+    // So are challenges.
+    let Some(pool) = appmod.pool.clone() else {
+        let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("NO_CHALLENGE")).await;
+        return;
+    };
+    let opponent_id = match user_id_from_name(pool, opponent).await {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("NO_CHALLENGE")).await;
+            return;
+        }
+        Err(e) => {
+            error!("Failed to look up challenge opponent of {}: {}", player.name, e);
+            return;
+        }
+    };
+
+    let (tx, mut rx) = oneshot::channel::<Player>();
+    let joined = appmod.challenges.lock().unwrap().join(player.id, opponent_id, tx);
+    let (rules, expires_at) = match joined {
+        Join::NoChallenge => {
+            let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("NO_CHALLENGE")).await;
+            return;
+        }
+        Join::Play(sender) => {
+            if let Err(mut player) = sender.send(player) {
+                warn!("Challenge opponent of {} left", player.name);
+                let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("OPPONENT_LEFT")).await;
+            }
+            return;
+        }
+        Join::Wait(rules, expires_at) => (rules, expires_at),
+    };
+
+    let _ = send_message(&mut player.socket, player.format, &ServerMessage::ChallengeWaiting { challenge_waiting: opponent.to_string() }).await;
+    let mut announcements = appmod.announcements.subscribe();
+    let mut player_left = false;
+
+    let matched = loop {
+        tokio::select! {
+            result = &mut rx => break result,
+            Ok(text) = announcements.recv() => {
+                let _ = send_announcement(&mut player, &text).await;
+            }
+            message = player.socket.recv(), if !player_left => {
+                if !matches!(message, Some(Ok(_))) {
+                    player_left = true;
+                    if appmod.challenges.lock().unwrap().leave(player.id, opponent_id) {
+                        return;
+                    }
+                }
+            }
+            _ = sleep_until(expires_at), if !player_left => {
+                // The opponent may have just joined; then their player is in `rx`.
+                if appmod.challenges.lock().unwrap().leave(player.id, opponent_id) {
+                    let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("CHALLENGE_EXPIRED")).await;
+                    let _ = player.socket.send(Message::Close(None)).await;
+                    return;
+                }
+            }
+        }
+    };
+
+    if let Ok(opponent) = matched {
+        play_match(player, opponent, &appmod, GameMode::Casual, rules, true).await;
+    }
+}
+
+async fn room_game(mut player: Player, appmod: AppMod, code: &str) {
+    player.handicap = Handicap::None;
+
+    let (tx, mut rx) = oneshot::channel::<Player>();
+    let joined = appmod.rooms.lock().unwrap().join(code, player.id, tx);
+    let (rules, expires_at) = match joined {
+        room::Join::Wait(rules, expires_at) => (rules, expires_at),
+        room::Join::Play(sender) => {
+            if let Err(mut player) = sender.send(player) {
+                warn!("Room {} opponent of {} left", code, player.name);
+                let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("OPPONENT_LEFT")).await;
+            }
+            return;
+        }
+        refused => {
+            let error = match refused {
+                room::Join::Full => "ROOM_FULL",
+                room::Join::SelfMatch => "SELF_MATCH",
+                _ => "NO_ROOM",
+            };
+            let _ = send_message(&mut player.socket, player.format, &ServerMessage::error(error)).await;
+            let _ = player.socket.send(Message::Close(None)).await;
+            return;
+        }
+    };
+
+    let _ = send_message(&mut player.socket, player.format, &ServerMessage::RoomWaiting { room_waiting: code.to_string() }).await;
+    let mut announcements = appmod.announcements.subscribe();
+    let mut player_left = false;
+
+    let matched = loop {
+        tokio::select! {
+            result = &mut rx => break result,
+            Ok(text) = announcements.recv() => {
+                let _ = send_announcement(&mut player, &text).await;
+            }
+            message = player.socket.recv(), if !player_left => {
+                if !matches!(message, Some(Ok(_))) {
+                    player_left = true;
+                    if appmod.rooms.lock().unwrap().leave(code, player.id) {
+                        return;
+                    }
+                }
+            }
+            _ = sleep_until(expires_at), if !player_left => {
+                // The other player may have just joined; then they are in `rx`.
+                if appmod.rooms.lock().unwrap().leave(code, player.id) {
+                    let _ = send_message(&mut player.socket, player.format, &ServerMessage::error("ROOM_EXPIRED")).await;
+                    let _ = player.socket.send(Message::Close(None)).await;
+                    return;
+                }
+            }
+        }
+    };
+
+    if let Ok(opponent) = matched {
+        info!("Room {} is full, starting {} vs {}", code, player.name, opponent.name);
+        play_match(player, opponent, &appmod, GameMode::Casual, rules, true).await;
+    }
+}
+
+async fn bot_game(mut player: Player, difficulty: Difficulty, appmod: AppMod) {
+    player.presence.set_in_game(true);
+    player.response = ServerResponse::first_response_player1();
+    let mut bot = ServerResponse::first_response_player2();
+    player.handicap.place(BoardOptions::O, &mut player.response.game.board);
+    bot.game.board = player.response.game.board;
+    let mut announcements = appmod.announcements.subscribe();
+
+    if send_message(&mut player.socket, player.format, &player.response.message()).await.is_err() {
+        warn!("{} disconnected", player.name);
+        return;
+    }
+
+    loop {
+        let message = tokio::select! {
+            Ok(text) = announcements.recv() => {
+                let _ = send_announcement(&mut player, &text).await;
+                continue;
+            }
+            message = player.socket.recv() => message,
+        };
+
+        let Some(Ok(message)) = message else { break };
+
+        match message {
+            Message::Close(_) => break,
+            Message::Ping(_) | Message::Pong(_) => continue,
+            _ => {}
+        }
+
+        match decode(&message).and_then(ClientMessage::into_move) {
+            Ok(player_move) => make_a_move(player_move, &mut player.response, &mut bot),
+            Err(e) => {
+                let _ = send_message(&mut player.socket, player.format, &e.reply()).await;
+                player.response.response = MoveResponse::Refused;
+                player.response.refusal = None;
+            }
+        }
+
+        if send_message(&mut player.socket, player.format, &player.response.message()).await.is_err() {
+            warn!("{} disconnected", player.name);
+            return;
+        }
+
+        if player.response.status != Status::InGame { break }
+        if bot.game.current_move != bot.your_symbol { continue }
+
+        if let Some(field) = difficulty.pick_move(&bot.game.board, bot.your_symbol) {
+            make_a_move(Move { field, symbol: None, board: None }, &mut bot, &mut player.response);
+
+            if send_message(&mut player.socket, player.format, &player.response.message()).await.is_err() {
+                warn!("{} disconnected", player.name);
+                return;
+            }
+        }
+
+        if player.response.status != Status::InGame { break }
+    }
+}
+
+async fn player_handler(sender: &mut Player, waiting_player: &mut Player, result: &Option<Result<Message, Error>>) -> Result<(), Error> {
+    match result {
+        Some(Ok(Message::Pong(payload))) => {
+            sender.latency.pong(payload);
+            Ok(())
+        }
+
+        // Answered by axum; not a move.
+        Some(Ok(Message::Ping(_))) => Ok(()),
+
+        // A close frame falls through to the disconnect below.
+        Some(Ok(message)) if !matches!(message, Message::Close(_)) => {
+            let player_move = match decode(message) {
+                Ok(ClientMessage::Chat(Chat { chat })) => {
+                    forward_chat(sender, waiting_player, chat).await;
+                    return Ok(());
+                }
+                other => other.and_then(ClientMessage::into_move),
+            };
+
+            match player_move {
+                Ok(player_move) => {
+                    let (field, board) = (player_move.field, player_move.board);
+                    make_a_move(player_move, &mut sender.response, &mut waiting_player.response);
+                    if matches!(sender.response.response, MoveResponse::Accepted) {
+                        log_move(sender, waiting_player, field, board);
+                    }
+
+                    match full_send(sender, waiting_player).await {
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!("{} disconnected", sender.name);
+                            return Err(e)
+                        }
+                    };
+
+                    match full_send(waiting_player, sender).await {
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!("{} disconnected", waiting_player.name);
+                            return Err(e)
+                        }
+                    };
+
+                    Ok(())
+                }
+                Err(e) => {
+                    let _ = send_message(&mut sender.socket, sender.format, &e.reply()).await;
+                    sender.response.response = MoveResponse::Refused;
+                    sender.response.refusal = None;
+                    match full_send(sender, waiting_player).await {
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!("{} disconnected while being told their move was malformed", sender.name);
+                            return Err(e)
+                        }
+                    };
+                    Ok(())
+                }
+            }
+        }
+
+        _ => {
+            warn!("{} lost connection (average latency {:?} ms)", sender.name, sender.latency.average_ms());
+            if waiting_player.response.status == Status::InGame {
+                waiting_player.response.status = Status::Error;
+            }
+            Err(Error::new("Player disconnected or invalid state"))
+        }
+    }
+}
+
+/// Passes a chat line to the opponent without touching the board. A line that
+/// is too long or follows the sender's last one too closely is refused, and
+/// only the sender hears about it.
+async fn forward_chat(sender: &mut Player, receiver: &mut Player, text: String) {
+    let refusal = if text.chars().count() > MAX_PLAYER_CHAT_LEN {
+        Some(ServerMessage::error_with("CHAT_TOO_LONG", serde_json::json!({ "max_length": MAX_PLAYER_CHAT_LEN })))
+    } else if sender.last_chat.is_some_and(|sent| sent.elapsed() < CHAT_INTERVAL) {
+        Some(ServerMessage::error("CHAT_RATE_LIMITED"))
+    } else {
+        None
+    };
+
+    if let Some(refusal) = refusal {
+        let _ = send_message(&mut sender.socket, sender.format, &refusal).await;
+        return;
+    }
+    if text.trim().is_empty() {
+        return;
+    }
+
+    sender.last_chat = Some(Instant::now());
+    let message = ServerMessage::Chat(ChatMessage { from: sender.name.clone(), text });
+    let _ = send_message(&mut receiver.socket, receiver.format, &message).await;
+}
+
+/// A uniformly random move the current player could legally make.
+fn random_legal_move(response: &ServerResponse) -> Option<Move> {
+    if let Some(ultimate) = &response.ultimate {
+        let (board, field) = *ultimate.legal_moves().choose(&mut rand::rng())?;
+        return Some(Move { field, symbol: None, board: Some(board) });
+    }
+
+    if let Some(gravity) = &response.gravity {
+        let field = *gravity.open_columns().choose(&mut rand::rng())?;
+        return Some(Move { field, symbol: None, board: None });
+    }
+
+    let board = &response.game.board;
+    let field = if is_opening(board) {
+        *response.game.rules.opening.fields().choose(&mut rand::rng())?
+    } else {
+        random_move(board)?
+    };
+    Some(Move { field, symbol: None, board: None })
+}
+
+/// Blitz timeout: plays a random legal move for `mover`, tells them so, and
+/// sends both players the new state just like a regular move.
+async fn auto_move(mover: &mut Player, waiting_player: &mut Player) -> Result<(), Error> {
+    let Some(player_move) = random_legal_move(&mover.response) else { return Ok(()) };
+    info!("{} ran out of time, auto-playing field {}", mover.name, player_move.field);
+
+    send_message(&mut mover.socket, mover.format, &ServerMessage::AutoMove {
+        auto_move: AutoMove { field: player_move.field, board: player_move.board },
+    }).await?;
+
+    let (field, board) = (player_move.field, player_move.board);
+    make_a_move(player_move, &mut mover.response, &mut waiting_player.response);
+    if matches!(mover.response.response, MoveResponse::Accepted) {
+        log_move(mover, waiting_player, field, board);
+    }
+
+    if let Err(e) = full_send(mover, waiting_player).await {
+        warn!("{} disconnected", mover.name);
+        return Err(e);
+    }
+    if let Err(e) = full_send(waiting_player, mover).await {
+        warn!("{} disconnected", waiting_player.name);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Adds an accepted move to `mover`'s log, timed from the start of their turn,
+/// and starts the opponent's turn.
+fn log_move(mover: &mut Player, waiting_player: &mut Player, field: usize, board: Option<usize>) {
+    let now = Instant::now();
+    mover.moves.push(MoveRecord {
+        player_id: mover.id,
+        field: field as i32,
+        board: board.map(|board| board as i32),
+        think_ms: i32::try_from(now.duration_since(mover.turn_started).as_millis()).unwrap_or(i32::MAX),
+        played_at: Utc::now(),
+    });
+    waiting_player.turn_started = now;
+}
+
+fn make_a_move(from_user: Move, current_player: &mut ServerResponse, waiting_player: &mut ServerResponse) {
+    current_player.refusal = None;
+
+    if current_player.your_symbol != current_player.game.current_move {
+        current_player.status = Status::InGame;
+        current_player.response = MoveResponse::Refused;
+        return
+    }
+
+    if current_player.status != Status::InGame {
+        current_player.response = MoveResponse::Refused;
+        return
+    }
+
+    let rules = current_player.game.rules;
+    let board = &mut current_player.game.board;
+    let symbol = match (rules.variant, from_user.symbol) {
+        (Variant::Wild, Some(chosen)) if chosen != BoardOptions::Null => chosen,
+        _ => current_player.your_symbol,
+    };
+
+    let outcome = if let Some(ultimate) = current_player.ultimate.as_mut() {
+        if !ultimate.place(from_user.board.unwrap_or(usize::MAX), from_user.field, symbol) {
+            current_player.response = MoveResponse::Refused;
+            return
+        }
+
+        *board = ultimate.meta_board();
+        waiting_player.ultimate = Some(ultimate.clone());
+        ultimate.status()
+    } else if let Some(gravity) = current_player.gravity.as_mut() {
+        if let Err(response) = gravity.drop_piece(from_user.field, symbol) {
+            current_player.response = response;
+            return
+        }
+
+        waiting_player.gravity = Some(gravity.clone());
+        gravity.status()
+    } else {
+        if from_user.field > 8 || board[from_user.field] != BoardOptions::Null {
+            current_player.response = MoveResponse::Refused;
+            return
+        }
+        if is_opening(board) && !rules.opening.allows(from_user.field) {
+            current_player.response = MoveResponse::Refused;
+            current_player.refusal = Some(Refusal::Opening);
+            return
+        }
+
+        board[from_user.field] = symbol;
+        check_winner(board)
+    };
+
+    let status = match outcome {
+        Status::Player1Won | Status::Player2Won if rules.misere => win_for(current_player.your_symbol.opposite()),
+        Status::Player1Won | Status::Player2Won if rules.variant == Variant::Wild => win_for(current_player.your_symbol),
+        status => status,
+    };
+
+    if status != Status::InGame {
         current_player.game.current_move = BoardOptions::Null;
         waiting_player.game.current_move = BoardOptions::Null;
     } else {
@@ -365,13 +2283,26 @@ fn make_a_move(from_user: Move, current_player: &mut SerwerResponse, waiting_pla
     current_player.status = status.clone();
     waiting_player.status = status.clone();
 
-    waiting_player.game.board = current_player.game.board.clone();
+    waiting_player.game.board = current_player.game.board;
     waiting_player.response = MoveResponse::Waiting;
 
     current_player.response = MoveResponse::Accepted;
 }
 
-fn check_winner(board: &[BoardOptions; 9]) -> Status {
+/// No mark is on the board yet, so the next move is the game's first.
+fn is_opening(board: &[BoardOptions; 9]) -> bool {
+    board.iter().all(|cell| *cell == BoardOptions::Null)
+}
+
+fn win_for(symbol: BoardOptions) -> Status {
+    if symbol == BoardOptions::O {
+        Status::Player1Won
+    } else {
+        Status::Player2Won
+    }
+}
+
+pub fn check_winner(board: &[BoardOptions; 9]) -> Status {
     for combo in WINNING_COMBINATIONS {
         let [a, b, c] = combo;
         if board[a] != BoardOptions::Null && board[a] == board[b] && board[a] == board[c] {
@@ -399,23 +2330,543 @@ fn check_winner(board: &[BoardOptions; 9]) -> Status {
     }
 }
 
-async fn full_send(receiver: &mut Player, waiting_player: &mut Player, pool: Pool<Postgres>) -> Result<(), Error> {
-    match send_json(&mut receiver.socket, &receiver.response).await {
+/// Whether a game changes Elo; see "Short games" and "Handicap" in the module docs.
+fn is_rated(moves: usize, min_rated_moves: usize, handicap: bool) -> bool {
+    !handicap && moves >= min_rated_moves
+}
+
+/// What gets saved of a game between `ids` (`O` first), as of `board`.
+fn game_record(mode: GameMode, ids: (i32, i32), winner_id: Option<i32>, board: &ServerResponse, handicap: bool, moves: Vec<MoveRecord>, min_rated_moves: usize) -> GameRecord {
+    let status = match winner_id {
+        Some(id) if id == ids.0 => Status::Player1Won,
+        Some(_) => Status::Player2Won,
+        None => Status::Draw,
+    };
+
+    GameRecord {
+        player1_id: ids.0,
+        player2_id: ids.1,
+        winner_id,
+        board: board.final_board(),
+        status: format!("{:?}", status),
+        ranked: mode == GameMode::Ranked,
+        handicap,
+        rated: is_rated(moves.len(), min_rated_moves, handicap),
+        started_at: None,
+        finished_at: None,
+        moves,
+    }
+}
+
+async fn record_game(appmod: &AppMod, mode: GameMode, a: &mut Player, b: &mut Player, winner_id: Option<i32>) {
+    let (player1, player2) = if a.response.your_symbol == BoardOptions::O { (a, b) } else { (b, a) };
+    let mut moves: Vec<MoveRecord> = std::mem::take(&mut player1.moves).into_iter().chain(std::mem::take(&mut player2.moves)).collect();
+    moves.sort_by_key(|record| record.played_at);
+
+    let handicap = [&player1.handicap, &player2.handicap].iter().any(|h| matches!(h, Handicap::Receive(_)));
+    if moves.len() < appmod.config.min_rated_moves && mode == GameMode::Ranked && winner_id.is_some() {
+        info!("{} vs {} ended after {} moves, counted without a rating change", player1.name, player2.name, moves.len());
+    }
+
+    let record = GameRecord {
+        started_at: player1.started_at,
+        ..game_record(mode, (player1.id, player2.id), winner_id, &player1.response, handicap, moves, appmod.config.min_rated_moves)
+    };
+
+    let game_id = match appmod.users.save_game(&record).await {
+        Ok(game_id) => game_id,
+        Err(err) => {
+            error!("Saving {} vs {} failed and was rolled back: {}", player1.name, player2.name, err);
+            return;
+        }
+    };
+
+    appmod.webhooks.game_finished(GameFinished {
+        event: "game_finished",
+        game_id,
+        ranked: record.ranked,
+        player1: player1.name.clone(),
+        player2: player2.name.clone(),
+        winner: winner_id.map(|id| if id == player1.id { player1.name.clone() } else { player2.name.clone() }),
+        status: record.status.clone(),
+        board: record.board.clone(),
+        finished_at: Utc::now(),
+    });
+
+    // Achievements are kept in Postgres only.
+    if let (true, Some(pool)) = (record.ranked, appmod.pool.clone()) {
+        let (won1, won2) = (winner_id == Some(player1.id), winner_id == Some(player2.id));
+        unlock_achievements(pool.clone(), player1, won1, record.handicap).await;
+        unlock_achievements(pool, player2, won2, record.handicap).await;
+    }
+}
+
+async fn unlock_achievements(pool: Pool<Postgres>, player: &mut Player, won: bool, handicap: bool) {
+    let response = &player.response;
+    let opponent = response.your_symbol.opposite();
+    let game = FinishedGame {
+        won,
+        handicap,
+        marks_placed: (response.game.rules.variant == Variant::Standard)
+            .then(|| response.game.board.iter().filter(|cell| **cell == response.your_symbol).count()),
+        opponent_boards: response.ultimate.as_ref()
+            .map(|ultimate| ultimate.meta_board().iter().filter(|cell| **cell == opponent).count()),
+    };
+
+    let stats = match player_stats(pool.clone(), player.id).await {
+        Ok(stats) => stats,
+        Err(e) => {
+            error!("Failed to load stats of {} for achievements: {}", player.name, e);
+            return;
+        }
+    };
+
+    let earned = check_achievements(&stats, &game);
+    if earned.is_empty() {
+        return;
+    }
+
+    match award_achievements(pool, player.id, &earned).await {
+        Ok(unlocked) if !unlocked.is_empty() => {
+            info!("{} unlocked {:?}", player.name, unlocked);
+            let _ = send_message(&mut player.socket, player.format, &ServerMessage::AchievementsUnlocked { achievements_unlocked: unlocked }).await;
+        }
+        Ok(_) => {}
+        Err(e) => error!("Failed to store achievements of {}: {}", player.name, e),
+    }
+}
+
+async fn full_send(receiver: &mut Player, waiting_player: &mut Player) -> Result<(), Error> {
+    receiver.response.your_latency_ms = receiver.latency.average_ms();
+    receiver.response.opponent_latency_ms = waiting_player.latency.average_ms();
+
+    match send_message(&mut receiver.socket, receiver.format, &receiver.response.message()).await {
         Ok(_) => {Ok(())}
         Err(err) => {
-            eprintln!("{} coudn't recieve message.", receiver.name);
-            waiting_player.response.status = Status::Error;
-            add_win_id(pool.clone(), waiting_player.id).await;
-            add_lose_id(pool.clone(), receiver.id).await;
+            warn!("{} couldn't receive a message (average latency {:?} ms)", receiver.name, receiver.latency.average_ms());
+            // Losing the socket after the last move doesn't change who won.
+            if waiting_player.response.status == Status::InGame {
+                waiting_player.response.status = Status::Error;
+            }
             Err(err)
         }
     }
 }
-async fn send_json<T: serde::Serialize>(socket: &mut WebSocket, from_struct: &T) -> Result<(), axum::Error> {
-    let response_json = serde_json::to_string(&from_struct)
-        .map_err(axum::Error::new)?;
+async fn send_match_found(player: &mut Player, opponent: &str) -> Result<(), axum::Error> {
+    send_message(&mut player.socket, player.format, &ServerMessage::MatchFound {
+        match_found: MatchFound {
+            you: player.name.clone(),
+            opponent: opponent.to_string(),
+            your_symbol: player.response.your_symbol,
+        },
+    }).await
+}
 
-    socket.send(Message::Text(response_json.into())).await?;
+async fn send_time_sync(player: &mut Player, deadline: Instant) -> Result<(), axum::Error> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    send_message(&mut player.socket, player.format, &ServerMessage::TimeSync {
+        server_time: Utc::now().timestamp_millis(),
+        game_remaining_ms: remaining.as_millis() as u64,
+    }).await
+}
 
-    Ok(())
-}
\ No newline at end of file
+async fn send_announcement(player: &mut Player, text: &str) -> Result<(), axum::Error> {
+    send_message(&mut player.socket, player.format, &ServerMessage::Announcement { announcement: text.to_string() }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::StandingsUpdate;
+    use BoardOptions::{Null as N, O, X};
+
+    fn at(field: usize) -> Move {
+        Move { field, symbol: None, board: None }
+    }
+
+    /// Plays `fields` in turn, `O` first, and returns both sides' responses.
+    fn play(rules: Rules, fields: &[usize]) -> (ServerResponse, ServerResponse) {
+        let (mut o, mut x) = starting_responses(rules, (7, 6));
+        for (ply, &field) in fields.iter().enumerate() {
+            if ply % 2 == 0 {
+                make_a_move(at(field), &mut o, &mut x);
+                assert!(matches!(o.response, MoveResponse::Accepted), "O's move {} was refused", field);
+            } else {
+                make_a_move(at(field), &mut x, &mut o);
+                assert!(matches!(x.response, MoveResponse::Accepted), "X's move {} was refused", field);
+            }
+        }
+        (o, x)
+    }
+
+    const DRAW: [usize; 9] = [4, 0, 2, 6, 3, 5, 7, 1, 8];
+
+    fn waiting(player_id: i32, last_opponent: Option<i32>) -> QueueEntry {
+        QueueEntry {
+            player_id,
+            rules: Rules::default(),
+            handicap: Handicap::None,
+            last_opponent,
+            #[cfg(feature = "redis-queue")]
+            ticket: String::new(),
+            sender: oneshot::channel().0,
+        }
+    }
+
+    fn pick(queue: &VecDeque<QueueEntry>, player_id: i32, last_opponent: Option<i32>, blocked: &[i32]) -> Option<usize> {
+        let blocked = blocked.iter().copied().collect();
+        pick_opponent(queue, player_id, last_opponent, Rules::default(), &Handicap::None, &blocked)
+    }
+
+    #[test]
+    fn reconnecting_players_get_in_during_maintenance() {
+        assert_eq!(refusal(true, true, 0, 0), None);
+        assert_eq!(refusal(false, true, 0, 0), Some("MAINTENANCE"));
+    }
+
+    #[test]
+    fn reconnecting_players_get_in_when_full() {
+        assert_eq!(refusal(true, false, 4, 4), None);
+        assert_eq!(refusal(false, false, 4, 4), Some("SERVER_FULL"));
+        assert_eq!(refusal(false, false, 3, 4), None);
+        // MAX_GAMES=0 is no limit.
+        assert_eq!(refusal(false, false, 4, 0), None);
+    }
+
+    #[test]
+    fn never_picks_the_same_account() {
+        let queue = VecDeque::from([waiting(1, None)]);
+        assert_eq!(pick(&queue, 1, None, &[]), None);
+
+        let queue = VecDeque::from([waiting(1, None), waiting(2, None)]);
+        assert_eq!(pick(&queue, 1, None, &[]), Some(1));
+    }
+
+    #[test]
+    fn connections_of_one_user_never_pair_up() {
+        // Every socket of user 1 ends up waiting, however many they open...
+        let mut queue = VecDeque::new();
+        for _ in 0..3 {
+            assert_eq!(pick(&queue, 1, None, &[]), None);
+            queue.push_back(waiting(1, None));
+        }
+        // ...even when the rematch fallback would take anyone.
+        assert_eq!(pick(&queue, 1, Some(1), &[]), None);
+
+        // Someone else still gets the oldest of them.
+        assert_eq!(pick(&queue, 2, None, &[]), Some(0));
+    }
+
+    #[test]
+    fn skips_blocked_players() {
+        let queue = VecDeque::from([waiting(2, None), waiting(3, None)]);
+        assert_eq!(pick(&queue, 1, None, &[2]), Some(1));
+        assert_eq!(pick(&queue, 1, None, &[2, 3]), None);
+    }
+
+    #[test]
+    fn three_players_rotate() {
+        // 1 and 2 just played each other; 3 is waiting too.
+        let queue = VecDeque::from([waiting(1, Some(2)), waiting(3, Some(1))]);
+        assert_eq!(pick(&queue, 2, Some(1), &[]), Some(1), "2 should face 3, not 1 again");
+
+        // With nobody else around, the rematch goes ahead.
+        let queue = VecDeque::from([waiting(1, Some(2))]);
+        assert_eq!(pick(&queue, 2, Some(1), &[]), Some(0));
+    }
+
+    #[test]
+    fn misere_completing_a_line_loses() {
+        let misere = Rules { misere: true, ..Rules::default() };
+        let (o, x) = play(misere, &[0, 3, 1, 4, 2]);
+        assert_eq!(o.status, Status::Player2Won);
+        assert_eq!(x.status, Status::Player2Won);
+        assert_eq!(o.game.current_move, N);
+
+        let (o, _) = play(Rules::default(), &[0, 3, 1, 4, 2]);
+        assert_eq!(o.status, Status::Player1Won);
+    }
+
+    #[test]
+    fn misere_line_by_x_hands_o_the_win() {
+        let misere = Rules { misere: true, ..Rules::default() };
+        let (o, x) = play(misere, &[0, 3, 8, 4, 7, 5]);
+        assert_eq!(x.status, Status::Player1Won);
+        assert_eq!(o.status, Status::Player1Won);
+    }
+
+    #[test]
+    fn coin_flip_is_roughly_balanced() {
+        let flips = 10_000;
+        let first = (0..flips).filter(|_| coin_flip(1, 2).0 == 1).count();
+        assert!((4_500..=5_500).contains(&first), "player 1 opened {} of {} games", first, flips);
+    }
+
+    #[test]
+    fn misere_leaves_draws_alone() {
+        let misere = Rules { misere: true, ..Rules::default() };
+        let (o, x) = play(misere, &DRAW);
+        assert_eq!(o.status, Status::Draw);
+        assert_eq!(x.status, Status::Draw);
+    }
+
+    #[test]
+    fn a_move_off_the_board_is_refused() {
+        let (mut o, mut x) = starting_responses(Rules::default(), (7, 6));
+        make_a_move(at(9), &mut o, &mut x);
+        assert!(matches!(o.response, MoveResponse::Refused));
+        assert_eq!(o.game.board, [N; 9]);
+        assert_eq!(o.game.current_move, O);
+    }
+
+    #[test]
+    fn a_move_on_a_taken_cell_is_refused() {
+        let (mut o, mut x) = play(Rules::default(), &[4]);
+        make_a_move(at(4), &mut x, &mut o);
+        assert!(matches!(x.response, MoveResponse::Refused));
+        assert_eq!(x.game.board[4], O);
+        assert_eq!(x.game.current_move, X);
+    }
+
+    #[test]
+    fn a_move_out_of_turn_is_refused() {
+        let (mut o, mut x) = starting_responses(Rules::default(), (7, 6));
+        make_a_move(at(0), &mut x, &mut o);
+        assert!(matches!(x.response, MoveResponse::Refused));
+        assert_eq!(x.game.board, [N; 9]);
+    }
+
+    #[test]
+    fn center_opening_is_enforced() {
+        let center = Rules { opening: Opening::Center, ..Rules::default() };
+        let (mut o, mut x) = starting_responses(center, (7, 6));
+        make_a_move(at(0), &mut o, &mut x);
+        assert!(matches!(o.response, MoveResponse::Refused));
+        assert_eq!(o.refusal, Some(Refusal::Opening));
+        assert_eq!(o.game.board, [N; 9]);
+
+        make_a_move(at(4), &mut o, &mut x);
+        assert!(matches!(o.response, MoveResponse::Accepted));
+        assert_eq!(o.refusal, None);
+    }
+
+    #[test]
+    fn corner_opening_is_enforced() {
+        let corner = Rules { opening: Opening::Corner, ..Rules::default() };
+        for field in [1, 3, 4, 5, 7] {
+            let (mut o, mut x) = starting_responses(corner, (7, 6));
+            make_a_move(at(field), &mut o, &mut x);
+            assert_eq!(o.refusal, Some(Refusal::Opening), "field {}", field);
+        }
+        for field in Opening::CORNERS {
+            let (mut o, mut x) = starting_responses(corner, (7, 6));
+            make_a_move(at(field), &mut o, &mut x);
+            assert!(matches!(o.response, MoveResponse::Accepted), "field {}", field);
+        }
+    }
+
+    #[test]
+    fn later_moves_are_unrestricted() {
+        // Center first, then edges and corners alike; `play` asserts each one is accepted.
+        let center = Rules { opening: Opening::Center, ..Rules::default() };
+        play(center, &[4, 1, 3, 0]);
+
+        let corner = Rules { opening: Opening::Corner, ..Rules::default() };
+        play(corner, &[0, 4, 1, 5]);
+    }
+
+    #[test]
+    fn default_rules_allow_any_opening() {
+        for field in 0..9 {
+            play(Rules::default(), &[field]);
+        }
+    }
+
+    #[test]
+    fn handicap_requests_parse() {
+        let rules = Rules::default();
+        assert_eq!(Handicap::from_request(None, false, rules), Some(Handicap::None));
+        assert_eq!(Handicap::from_request(None, true, rules), Some(Handicap::Give));
+        assert_eq!(Handicap::from_request(Some(vec![4]), false, rules), Some(Handicap::Receive(vec![4])));
+        assert_eq!(Handicap::from_request(Some(vec![0, 8]), false, rules), Some(Handicap::Receive(vec![0, 8])));
+    }
+
+    fn token_handicap(json: &str) -> Option<(Option<Vec<usize>>, bool)> {
+        match parse_client_message(json) {
+            Ok(ClientMessage::Token(token)) => Some((token.handicap, token.give_handicap)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn handicap_fields_of_the_token_message() {
+        assert_eq!(token_handicap(r#"{"token": "t"}"#), Some((None, false)));
+        assert_eq!(token_handicap(r#"{"token": "t", "handicap": [0, 8]}"#), Some((Some(vec![0, 8]), false)));
+        assert_eq!(token_handicap(r#"{"token": "t", "give_handicap": true}"#), Some((None, true)));
+        // Negative or non-numeric cells don't even parse.
+        assert_eq!(token_handicap(r#"{"token": "t", "handicap": [-1]}"#), None);
+        assert_eq!(token_handicap(r#"{"token": "t", "handicap": ["a"]}"#), None);
+    }
+
+    #[test]
+    fn invalid_handicaps_are_rejected() {
+        let rules = Rules::default();
+        // Asking for and offering a head start at once.
+        assert_eq!(Handicap::from_request(Some(vec![4]), true, rules), None);
+        assert_eq!(Handicap::from_request(Some(vec![]), false, rules), None);
+        assert_eq!(Handicap::from_request(Some(vec![0, 4, 8]), false, rules), None);
+        assert_eq!(Handicap::from_request(Some(vec![4, 4]), false, rules), None);
+    }
+
+    #[test]
+    fn handicap_cells_must_be_on_the_board() {
+        let rules = Rules::default();
+        assert_eq!(Handicap::from_request(Some(vec![9]), false, rules), None);
+        assert_eq!(Handicap::from_request(Some(vec![0, usize::MAX]), false, rules), None);
+        assert_eq!(Handicap::from_request(Some(vec![8]), false, rules), Some(Handicap::Receive(vec![8])));
+    }
+
+    #[test]
+    fn handicaps_need_standard_rules() {
+        let ultimate = Rules { variant: Variant::Ultimate, ..Rules::default() };
+        let center_opening = Rules { opening: Opening::Center, ..Rules::default() };
+        assert_eq!(Handicap::from_request(Some(vec![4]), false, ultimate), None);
+        assert_eq!(Handicap::from_request(Some(vec![4]), false, center_opening), None);
+        // Offering one is fine whatever the rules.
+        assert_eq!(Handicap::from_request(None, true, ultimate), Some(Handicap::Give));
+    }
+
+    #[test]
+    fn receivers_only_pair_with_givers() {
+        let receive = Handicap::Receive(vec![4]);
+        assert!(receive.pairs_with(&Handicap::Give));
+        assert!(!receive.pairs_with(&Handicap::None));
+        assert!(!receive.pairs_with(&receive));
+        assert!(Handicap::None.pairs_with(&Handicap::Give));
+    }
+
+    /// Lets the blitz timeout play a whole game, checking every auto-move
+    /// is accepted and hands the turn over without ending the game early.
+    fn auto_play(rules: Rules) -> Status {
+        let (mut o, mut x) = starting_responses(rules, (7, 6));
+        let mut turn = BoardOptions::O;
+        while o.status == Status::InGame {
+            let (mover, waiting) = if turn == BoardOptions::O { (&mut o, &mut x) } else { (&mut x, &mut o) };
+            let auto_move = random_legal_move(mover).expect("a running game always has a legal move");
+            let field = auto_move.field;
+            make_a_move(auto_move, mover, waiting);
+            assert!(matches!(mover.response, MoveResponse::Accepted), "auto-move on field {} was refused", field);
+            if mover.status == Status::InGame {
+                assert_eq!(mover.game.current_move, turn.opposite());
+            }
+            turn = turn.opposite();
+        }
+        o.status
+    }
+
+    #[test]
+    fn auto_moves_are_always_legal() {
+        let center = Rules { opening: Opening::Center, ..Rules::default() };
+        let corner = Rules { opening: Opening::Corner, ..Rules::default() };
+        let ultimate = Rules { variant: Variant::Ultimate, ..Rules::default() };
+        let gravity = Rules { variant: Variant::Gravity, ..Rules::default() };
+        for rules in [Rules::default(), center, corner, ultimate, gravity] {
+            for _ in 0..50 {
+                let status = auto_play(rules);
+                assert!(matches!(status, Status::Player1Won | Status::Player2Won | Status::Draw), "{:?} ended {:?}", rules, status);
+            }
+        }
+    }
+
+    #[test]
+    fn an_auto_move_passes_the_turn() {
+        let blitz = Rules { blitz: true, opening: Opening::Center, ..Rules::default() };
+        let (mut o, mut x) = starting_responses(blitz, (7, 6));
+        let auto_move = random_legal_move(&o).unwrap();
+        assert_eq!(auto_move.field, 4);
+        make_a_move(auto_move, &mut o, &mut x);
+        assert_eq!(o.status, Status::InGame);
+        assert_eq!(x.status, Status::InGame);
+        assert_eq!(x.game.current_move, BoardOptions::X);
+    }
+
+    #[test]
+    fn short_games_are_not_rated() {
+        assert!(!is_rated(0, 5, false));
+        assert!(!is_rated(1, 5, false));
+        assert!(!is_rated(4, 5, false));
+        assert!(is_rated(5, 5, false));
+        // MIN_RATED_MOVES=0 rates every game.
+        assert!(is_rated(0, 0, false));
+    }
+
+    #[test]
+    fn handicap_games_are_never_rated() {
+        assert!(!is_rated(9, 5, true));
+    }
+
+    /// What a ranked game of `moves` moves between 7 (O) and 8 (X) does to the
+    /// standings, with the default `MIN_RATED_MOVES`.
+    fn standings_after(o: &ServerResponse, x: &ServerResponse, moves: usize) -> StandingsUpdate {
+        let winner_id = match final_result((7, &o.status), (8, &x.status)) {
+            GameResult::Won(id) => Some(id),
+            _ => None,
+        };
+        let moves = (0..moves)
+            .map(|ply| MoveRecord { player_id: if ply % 2 == 0 { 7 } else { 8 }, field: ply as i32, board: None, think_ms: 0, played_at: Utc::now() })
+            .collect();
+        StandingsUpdate::of(&game_record(GameMode::Ranked, (7, 8), winner_id, o, false, moves, 5))
+    }
+
+    #[test]
+    fn a_won_game_counts_once_for_each_player() {
+        let (o, x) = play(Rules::default(), &[0, 3, 1, 4, 2]);
+        assert_eq!(standings_after(&o, &x, 5), StandingsUpdate::Win { winner_id: 7, loser_id: 8, rated: true });
+
+        let (o, x) = play(Rules::default(), &[0, 3, 1, 4, 8, 5]);
+        assert_eq!(standings_after(&o, &x, 6), StandingsUpdate::Win { winner_id: 8, loser_id: 7, rated: true });
+    }
+
+    #[test]
+    fn final_results() {
+        use Status::*;
+        let cases = [
+            // A normal win, either way round.
+            (Player1Won, Player1Won, GameResult::Won(7)),
+            (Player2Won, Player2Won, GameResult::Won(8)),
+            // O let the turn clock run out; X was waiting.
+            (win_for(X), win_for(X), GameResult::Won(8)),
+            (Draw, Draw, GameResult::Draw),
+            // A forfeit by disconnect: the one who stayed is marked `Error`.
+            (Error, InGame, GameResult::Won(7)),
+            (InGame, Error, GameResult::Won(8)),
+            (Aborted, Aborted, GameResult::NoResult),
+            (InGame, InGame, GameResult::NoResult),
+        ];
+        for (status1, status2, expected) in cases {
+            assert_eq!(final_result((7, &status1), (8, &status2)), expected, "{:?} / {:?}", status1, status2);
+        }
+    }
+
+    #[test]
+    fn a_decided_board_is_not_a_disconnect() {
+        // X's socket went while the result was being sent; O still won.
+        let (o, mut x) = play(Rules::default(), &[0, 3, 1, 4, 2]);
+        x.status = Status::Error;
+        assert_eq!(standings_after(&o, &x, 5), StandingsUpdate::Win { winner_id: 7, loser_id: 8, rated: true });
+    }
+
+    #[test]
+    fn an_immediate_surrender_counts_without_a_rating_change() {
+        // O opens and X walks out, leaving O marked `Error`: a win and a loss,
+        // but no Elo.
+        let (mut o, x) = play(Rules::default(), &[4]);
+        o.status = Status::Error;
+        assert_eq!(standings_after(&o, &x, 1), StandingsUpdate::Win { winner_id: 7, loser_id: 8, rated: false });
+
+        // X gives up before moving at all.
+        let (mut o, x) = play(Rules::default(), &[]);
+        o.status = Status::Error;
+        assert_eq!(standings_after(&o, &x, 0), StandingsUpdate::Win { winner_id: 7, loser_id: 8, rated: false });
+    }
+}
@@ -0,0 +1,97 @@
+//! # Gravity (Connect-Four) Board
+//!
+//! Board model for the `gravity` variant. A move names a column; the piece drops
+//! to the lowest empty row of that column. Four in a row — horizontally,
+//! vertically or diagonally — wins, and a full board is a draw.
+//!
+//! Cells are stored row-major with row `0` at the top, so clients can render
+//! `cells` directly. The dimensions come from `GRAVITY_COLUMNS` / `GRAVITY_ROWS`
+//! (7×6 by default).
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use serde::{Deserialize, Serialize};
+use crate::game::{BoardOptions, MoveResponse, Status};
+
+pub const CONNECT: usize = 4;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct GravityBoard {
+    columns: usize,
+    rows: usize,
+    cells: Vec<BoardOptions>,
+}
+
+impl GravityBoard {
+    pub fn new(columns: usize, rows: usize) -> Self {
+        Self {
+            columns,
+            rows,
+            cells: vec![BoardOptions::Null; columns * rows],
+        }
+    }
+
+    pub fn cells(&self) -> &[BoardOptions] {
+        &self.cells
+    }
+
+    pub fn drop_piece(&mut self, column: usize, symbol: BoardOptions) -> Result<(), MoveResponse> {
+        if column >= self.columns {
+            return Err(MoveResponse::Refused);
+        }
+
+        let row = (0..self.rows)
+            .rev()
+            .find(|&row| self.cells[row * self.columns + column] == BoardOptions::Null)
+            .ok_or(MoveResponse::ColumnFull)?;
+
+        self.cells[row * self.columns + column] = symbol;
+        Ok(())
+    }
+
+    /// Columns that still have room for a piece.
+    pub fn open_columns(&self) -> Vec<usize> {
+        (0..self.columns).filter(|&column| self.cells[column] == BoardOptions::Null).collect()
+    }
+
+    pub fn status(&self) -> Status {
+        match connect_k(&self.cells, self.columns, self.rows, CONNECT) {
+            BoardOptions::O => Status::Player1Won,
+            BoardOptions::X => Status::Player2Won,
+            BoardOptions::Null if self.cells.iter().all(|cell| *cell != BoardOptions::Null) => Status::Draw,
+            BoardOptions::Null => Status::InGame,
+        }
+    }
+}
+
+pub fn connect_k(cells: &[BoardOptions], columns: usize, rows: usize, k: usize) -> BoardOptions {
+    const DIRECTIONS: [(isize, isize); 4] = [(0, 1), (1, 0), (1, 1), (1, -1)];
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let symbol = cells[row * columns + column];
+            if symbol == BoardOptions::Null {
+                continue;
+            }
+
+            for (d_row, d_column) in DIRECTIONS {
+                let complete = (1..k as isize).all(|step| {
+                    let r = row as isize + d_row * step;
+                    let c = column as isize + d_column * step;
+                    r >= 0 && c >= 0 && (r as usize) < rows && (c as usize) < columns
+                        && cells[r as usize * columns + c as usize] == symbol
+                });
+
+                if complete {
+                    return symbol;
+                }
+            }
+        }
+    }
+
+    BoardOptions::Null
+}
@@ -0,0 +1,100 @@
+//! # Client Addresses and IP Bans
+//!
+//! Works out which address a request really comes from and turns away banned
+//! ones.
+//!
+//! Behind a reverse proxy every connection arrives from the proxy itself. When
+//! the peer is listed in `TRUSTED_PROXIES` (comma-separated), the client is taken
+//! from `X-Forwarded-For` instead: the right-most entry that is not itself a
+//! trusted proxy. Headers from any other peer are ignored, so clients can't
+//! pick their own address.
+//!
+//! Banned addresses live in the `banned_ips` table and are loaded into memory at
+//! startup; `POST /api/admin/ban-ip` and `/api/admin/unban-ip` update both.
+//! `/api/search` and the login, registration, guest and password reset
+//! endpoints answer banned addresses with `403` before doing anything else.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use axum::http::{HeaderMap, StatusCode};
+use sqlx::{Pool, Postgres};
+use crate::database::banned_ips;
+
+#[derive(Clone)]
+pub struct IpFilter {
+    trusted_proxies: Arc<HashSet<IpAddr>>,
+    banned: Arc<Mutex<HashSet<IpAddr>>>,
+}
+
+impl IpFilter {
+    /// A filter that bans nobody yet.
+    pub fn new(trusted_proxies: HashSet<IpAddr>) -> Self {
+        Self {
+            trusted_proxies: Arc::new(trusted_proxies),
+            banned: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub async fn load(pool: Pool<Postgres>, trusted_proxies: HashSet<IpAddr>) -> Result<Self, sqlx::Error> {
+        let banned = banned_ips(pool)
+            .await?
+            .into_iter()
+            .filter_map(|ip| ip.parse().ok())
+            .collect();
+
+        let filter = Self::new(trusted_proxies);
+        *filter.banned.lock().unwrap() = banned;
+        Ok(filter)
+    }
+
+    pub fn client_ip(&self, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = peer.ip();
+        if !self.trusted_proxies.contains(&peer) {
+            return peer;
+        }
+
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| ip.trim().parse().ok())
+            .collect();
+
+        forwarded
+            .iter()
+            .rev()
+            .find(|ip| !self.trusted_proxies.contains(ip))
+            .or(forwarded.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+
+    /// The client's address, or `403` if it is banned.
+    pub fn check(&self, peer: SocketAddr, headers: &HeaderMap) -> Result<IpAddr, StatusCode> {
+        let ip = self.client_ip(peer, headers);
+        if self.is_banned(ip) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(ip)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.lock().unwrap().contains(&ip)
+    }
+
+    pub fn ban(&self, ip: IpAddr) {
+        self.banned.lock().unwrap().insert(ip);
+    }
+
+    pub fn unban(&self, ip: IpAddr) {
+        self.banned.lock().unwrap().remove(&ip);
+    }
+}
@@ -0,0 +1,125 @@
+//! # Leaderboard
+//!
+//! How players are ranked, and in-memory caches in front of the leaderboard and
+//! global stats queries, for `/api/top10` and `/api/stats`.
+//!
+//! `RANKING` picks the [`RankingStrategy`] used by the leaderboard and by the rank
+//! and percentile on profiles: `elo` (the default), `points` (wins − losses,
+//! never below 0), `wins` or `league` (3 per ranked win, 1 per ranked draw).
+//! `points` is easy to farm with a string of wins over weak opponents, which
+//! Elo barely rewards; it used to be the default, and `RANKING=points` keeps
+//! the old order. Ratings live in the `elo` column, which predates ranking by
+//! them and already carries every player's history in `elo_history`.
+//! Every player's `score` under that strategy is returned alongside their stats.
+//!
+//! A cached value is served until it is older than `LEADERBOARD_TTL_SECS` or until
+//! [`Cached::mark_dirty`] is called after a game, whichever comes first.
+//! Refreshes are single-flight: when many requests find the cache stale at
+//! once, one of them queries Postgres and the rest wait for and reuse its result.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RankingStrategy {
+    Points,
+    Wins,
+    #[default]
+    Elo,
+    League,
+}
+
+impl RankingStrategy {
+    /// The score as a SQL expression over the `wins`, `loses`, `draws`, `points`
+    /// and `elo` columns of the ranking queries.
+    pub fn score(self) -> &'static str {
+        match self {
+            RankingStrategy::Points => "points",
+            RankingStrategy::Wins => "wins",
+            RankingStrategy::Elo => "elo",
+            RankingStrategy::League => "3 * wins + draws",
+        }
+    }
+
+    /// `ORDER BY` clause putting the best player first.
+    pub fn order_clause(self) -> String {
+        format!("{} DESC", self.score())
+    }
+}
+
+impl FromStr for RankingStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "points" => Ok(RankingStrategy::Points),
+            "wins" => Ok(RankingStrategy::Wins),
+            "elo" => Ok(RankingStrategy::Elo),
+            "league" => Ok(RankingStrategy::League),
+            _ => Err(String::from("expected points, wins, elo or league")),
+        }
+    }
+}
+
+pub struct Cached<T> {
+    ttl: Duration,
+    entry: RwLock<Option<(Instant, T)>>,
+    dirty: AtomicBool,
+    refresh: Mutex<()>,
+}
+
+impl<T: Clone> Cached<T> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entry: RwLock::new(None),
+            dirty: AtomicBool::new(false),
+            refresh: Mutex::new(()),
+        }
+    }
+
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn fresh(&self) -> Option<T> {
+        if self.dirty.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        match self.entry.read().unwrap().as_ref() {
+            Some((fetched_at, value)) if fetched_at.elapsed() < self.ttl => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// The cached value, or a fresh one from `load` if it is stale.
+    pub async fn get<F>(&self, load: impl FnOnce() -> F) -> Result<T, sqlx::Error>
+    where
+        F: Future<Output = Result<T, sqlx::Error>>,
+    {
+        if let Some(value) = self.fresh() {
+            return Ok(value);
+        }
+
+        let _refreshing = self.refresh.lock().await;
+        if let Some(value) = self.fresh() {
+            return Ok(value);
+        }
+
+        // Cleared before the query, so a game finishing meanwhile marks it dirty again.
+        self.dirty.store(false, Ordering::Relaxed);
+        let value = load().await?;
+        *self.entry.write().unwrap() = Some((Instant::now(), value.clone()));
+        Ok(value)
+    }
+}
@@ -10,8 +10,67 @@
 //! |--------|-----------------|----------------------------------------------------|
 //! | POST   | `/api/register` | Create a new account                               |
 //! | POST   | `/api/login`    | Authenticate and receive a UUID session token      |
-//! | GET    | `/api/search`   | Upgrade to WebSocket and enter the matchmaking queue |
-//! | GET    | `/api/top10`    | Return the top-10 leaderboard (JSON)               |
+//! | POST   | `/api/logout`   | Invalidate a session token                         |
+//! | POST   | `/api/guest`    | Create a throwaway guest account and return its token |
+//! | POST   | `/api/forgot-password` | Create a single-use password reset token for a username |
+//! | POST   | `/api/reset-password` | Set a new password with a reset token         |
+//! | POST   | `/api/email`    | Set or change your email address (Bearer token)    |
+//! | POST   | `/api/verify-email/send` | Issue a verification token for your email (Bearer token) |
+//! | POST   | `/api/verify-email/confirm` | Mark the email verified with that token      |
+//! | GET    | `/api/search`   | Upgrade to WebSocket and enter the matchmaking queue (`429` past the per-IP limits) |
+//! | GET    | `/api/ready`    | Readiness probe: `503` if the database is unreachable or in maintenance |
+//! | GET    | `/api/top10`    | Return the top-10 leaderboard (JSON) ranked by `RANKING`, cached until a ranked game ends or `LEADERBOARD_TTL_SECS` pass |
+//! | GET    | `/api/stats`    | Player and game totals, average and median game length (cached like top10) |
+//! | GET    | `/api/leaderboard` | `?sort=points` (top 10) or `?sort=active&since=` (most games played) |
+//! | GET    | `/api/top-streaks` | Top-10 longest ranked win streaks (JSON)     |
+//! | GET    | `/api/elo-history/{username}` | Elo rating series, optional `?from=&to=` (RFC 3339) |
+//! | GET    | `/api/history/{username}` | The player's last 20 games: opponent, result, time |
+//! | GET    | `/api/profile/{username}` | Stats plus score, global rank and percentile |
+//! | GET    | `/api/presence/{username}` | `offline`, `online` or `in_game`               |
+//! | GET    | `/api/users`    | Usernames starting with `?q=`, `?limit=` (1–50, default 10) |
+//! | GET    | `/api/achievements/{username}` | Achievements the player has earned  |
+//! | GET    | `/api/h2h/{a}/{b}` | Games player A won/lost/drew against player B |
+//! | GET    | `/api/recent-games` | Most recently finished games, `?limit=` (1–50, default 20) |
+//! | GET    | `/api/games`    | Live games and their players                       |
+//! | GET    | `/api/games/{id}/spectate` | Upgrade to WebSocket and watch a live game (up to `MAX_SPECTATORS`) |
+//! | POST   | `/api/tournament` | Create a bracket or round-robin tournament (admin) |
+//! | GET    | `/api/tournament/{id}` | Current bracket state, round by round      |
+//! | GET    | `/api/tournament/{id}/standings` | Points table (wins, draws, losses) |
+//! | POST   | `/api/block`    | Never be matched with the given user again (Bearer token) |
+//! | POST   | `/api/unblock`  | Lift a block (Bearer token)                        |
+//! | GET    | `/api/friends`  | Friends and pending requests, with online status (Bearer token) |
+//! | POST   | `/api/friends/request` | Send a friend request, or accept theirs (Bearer token) |
+//! | POST   | `/api/friends/accept` | Accept an incoming friend request (Bearer token) |
+//! | POST   | `/api/friends/remove` | Unfriend, or cancel/decline a request (Bearer token) |
+//! | POST   | `/api/challenge/{friend}` | Challenge a friend to a casual game (Bearer token) |
+//! | POST   | `/api/create_room` | Open a private room and get its code (Bearer token) |
+//! | POST   | `/api/rename`   | Change your username, keeping all stats (Bearer token) |
+//! | POST   | `/api/deactivate` | Hide your account and log out (Bearer token)     |
+//! | POST   | `/api/reactivate` | Same as `/api/login`; logging in reactivates     |
+//! | POST   | `/api/2fa/enroll` | Start TOTP setup; returns secret and `otpauth://` URI (Bearer token) |
+//! | POST   | `/api/2fa/verify` | Confirm TOTP setup with a code; returns recovery codes (Bearer token) |
+//! | POST   | `/api/export`   | Download all data stored about you (Bearer token)  |
+//! | POST   | `/api/admin/games/{id}/end` | Force-end a live game without recording a result (admin) |
+//! | POST   | `/api/admin/announce` | Push a message to every connected client (admin) |
+//! | POST   | `/api/admin/maintenance` | Enable/disable maintenance mode (admin)      |
+//! | GET    | `/api/admin/auth-events/{username}` | Recent logins, registrations etc. with source IP (admin) |
+//! | POST   | `/api/admin/ban-ip` | Refuse an IP address on `/api/search` and the auth endpoints (admin) |
+//! | POST   | `/api/admin/unban-ip` | Lift an IP ban (admin)                         |
+//!
+//! With a SQLite `DATABASE_URL` only the routes that [`UserStore`] covers are
+//! mounted; see [`postgres_routes`] for the rest.
+//!
+//! ## Shutdown
+//!
+//! On `SIGINT`/`SIGTERM` the server switches into maintenance mode, stops accepting
+//! HTTP connections and waits for every running game to finish before exiting.
+//! Background tasks (guest cleanup, database health check) are stopped after that.
+//!
+//! ## Panics
+//!
+//! A handler that panics answers `500` and the panic is logged with a backtrace;
+//! other requests are unaffected. A panic in a WebSocket task closes only that
+//! connection, and its queue entry and live game registration go with it.
 //!
 //! ## Author
 //! Marcel Gruszecki
@@ -19,92 +78,878 @@
 //! ## License
 //! MIT — see `LICENSE` in the repository root.
 
+mod achievements;
+mod admin;
+mod bot;
+mod challenge;
+mod room;
+mod config;
 mod database;
+mod email;
+mod friends;
 mod game;
+mod gravity;
+mod ip_filter;
+mod leaderboard;
+mod password_reset;
+mod presence;
+mod protocol;
+mod rate_limit;
+#[cfg(feature = "redis-queue")]
+mod redis_queue;
+mod request_log;
+mod seed;
+mod spectate;
+mod tournament;
+#[cfg(feature = "sqlite")]
+mod sqlite_store;
+mod two_factor;
+mod ultimate;
+mod user_store;
+mod webhook;
 
 use axum::{
     routing::{get, post},
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
     Json, Router,
 };
-use axum::extract::State;
-use axum::response::IntoResponse;
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::response::{IntoResponse, Response};
+use clap::Parser;
+use tower_http::LatencyUnit;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
-use crate::database::{check_password, connect_to_database, create_new_user, top10_from_database, UserRank};
-use std::collections::VecDeque;
+use chrono::{DateTime, Utc};
+use crate::database::{block_user, is_timeout, deactivate_user, expire_guests, export_user_data, elo_history_from_database, head_to_head, history_from_database, log_auth_event, most_active, profile, recent_games, rename_user, search_users, top_streaks_from_database, top10_from_database, global_stats, unblock_user, user_id_from_name, ActivePlayer, AuthEvent, GlobalStats, UserRank};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
-use tokio::sync::oneshot;
-use crate::game::{Player, websocket_connect};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::{broadcast, oneshot, watch};
+use tracing::{error, info, warn, Level};
+use tracing_subscriber::EnvFilter;
+use crate::achievements::achievements;
+use crate::admin::{announce, auth_history, end_game, set_maintenance};
+use crate::game::{GameHandle, Player, QueueEntry, WaitEstimator, websocket_connect};
+use crate::leaderboard::Cached;
+use crate::challenge::{challenge, Challenges};
+use crate::room::{create_room, Rooms};
+use crate::config::{Args, Config};
+use crate::ip_filter::IpFilter;
+use crate::presence::{presence, Presence};
+use crate::rate_limit::ConnectionLimiter;
+use crate::request_log::RequestSpan;
+use crate::spectate::{live_games, spectate};
+use crate::tournament::{bracket, create_tournament, standings, TournamentLobby};
+use crate::two_factor::{check_second_factor, SecretBox};
+use crate::user_store::UserStore;
+use crate::webhook::Webhooks;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Login {
     name: String,
     password: String,
     token: String,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
 }
 
+pub type Queue = Arc<Mutex<VecDeque<QueueEntry>>>;
+
 #[derive(Clone)]
 pub struct AppMod {
-    pub queue: Arc<Mutex<VecDeque<(i32, oneshot::Sender<Player>)>>>,
-    pub pool: Pool<Postgres>,
+    pub config: Arc<Config>,
+    pub ranked_queue: Queue,
+    pub casual_queue: Queue,
+    pub tournament_lobby: Arc<Mutex<TournamentLobby>>,
+    pub challenges: Arc<Mutex<Challenges>>,
+    pub rooms: Arc<Mutex<Rooms>>,
+    pub queue_changed: watch::Sender<()>,
+    pub wait_estimate: Arc<Mutex<WaitEstimator>>,
+    #[cfg(feature = "redis-queue")]
+    pub redis_queue: Option<Arc<redis_queue::RedisQueue>>,
+    pub last_opponents: Arc<Mutex<HashMap<i32, i32>>>,
+    /// Games waiting for a player who dropped out, by user id; their next
+    /// `/api/search` connection is sent here instead of into a queue.
+    pub rejoins: Arc<Mutex<HashMap<i32, oneshot::Sender<Player>>>>,
+    /// Accounts and sessions, on whichever backend `DATABASE_URL` picked.
+    pub users: Arc<dyn UserStore>,
+    /// `None` on SQLite; see [`AppMod::pg`].
+    pub pool: Option<Pool<Postgres>>,
+    pub top10_cache: Arc<Cached<Vec<UserRank>>>,
+    pub stats_cache: Arc<Cached<GlobalStats>>,
+    pub games: Arc<Mutex<HashMap<u64, GameHandle>>>,
+    pub presence: Presence,
+    pub connection_limiter: ConnectionLimiter,
+    pub ip_filter: IpFilter,
+    pub next_game_id: Arc<AtomicU64>,
+    pub announcements: broadcast::Sender<String>,
+    pub maintenance: Arc<AtomicBool>,
+    pub db_healthy: Arc<AtomicBool>,
+    pub totp_key: Option<SecretBox>,
+    pub webhooks: Webhooks,
 }
 
+impl AppMod {
+    /// The Postgres pool, for the handlers in [`postgres_routes`]. Those are
+    /// only mounted when there is one.
+    pub fn pg(&self) -> Pool<Postgres> {
+        self.pool.clone().expect("Postgres-only route mounted without PostgreSQL.")
+    }
+}
+
+/// Exit code for a missing or invalid environment variable (`EX_CONFIG`).
+const EXIT_CONFIG: i32 = 78;
+/// Exit code when the database can't be reached at startup (`EX_UNAVAILABLE`).
+const EXIT_DATABASE: i32 = 69;
+/// Exit code when `--seed` refuses a database with real accounts (`EX_NOPERM`).
+const EXIT_SEED_REFUSED: i32 = 77;
+
 #[tokio::main]
 async fn main() {
-    let pool = connect_to_database().await;
+    let args = Args::parse();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(config::log_filter(&args)).unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+    log_panics();
+
+    let config = match Config::load(&args) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            std::process::exit(EXIT_CONFIG);
+        }
+    };
+
+    let (user_store, pool) = match user_store::connect(&config).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            error!("Could not connect to the database at DATABASE_URL: {}", e);
+            std::process::exit(EXIT_DATABASE);
+        }
+    };
+
+    if let Some(users) = args.seed {
+        let Some(pool) = pool else {
+            error!("--seed needs a PostgreSQL DATABASE_URL");
+            std::process::exit(EXIT_CONFIG);
+        };
+        match seed::seed(pool, users.into(), config.bcrypt_cost).await {
+            Ok(seed::Outcome::Seeded { users, games }) => {
+                info!("Seeded {} players (password {:?}) and {} games", users, seed::PASSWORD, games);
+            }
+            Ok(seed::Outcome::AlreadySeeded) => info!("Seed players already exist, nothing to do"),
+            Ok(seed::Outcome::Refused(others)) => {
+                error!("Refusing to seed: found {} accounts that are not seed players", others);
+                std::process::exit(EXIT_SEED_REFUSED);
+            }
+            Err(e) => {
+                error!("Seeding failed: {}", e);
+                std::process::exit(EXIT_DATABASE);
+            }
+        }
+        return;
+    }
+
+    let ip_filter = match &pool {
+        Some(pool) => match IpFilter::load(pool.clone(), config.trusted_proxies.clone()).await {
+            Ok(ip_filter) => ip_filter,
+            Err(e) => {
+                error!("Could not load banned IP addresses: {}", e);
+                std::process::exit(EXIT_DATABASE);
+            }
+        },
+        // Bans are stored in Postgres; without it there are none.
+        None => IpFilter::new(config.trusted_proxies.clone()),
+    };
     let appmod = AppMod {
-        queue: Arc::new(Mutex::new(VecDeque::new())),
-        pool: pool,
+        ranked_queue: Arc::new(Mutex::new(VecDeque::new())),
+        casual_queue: Arc::new(Mutex::new(VecDeque::new())),
+        tournament_lobby: Arc::new(Mutex::new(TournamentLobby::default())),
+        challenges: Arc::new(Mutex::new(Challenges::default())),
+        rooms: Arc::new(Mutex::new(Rooms::default())),
+        queue_changed: watch::channel(()).0,
+        wait_estimate: Arc::new(Mutex::new(WaitEstimator::new())),
+        #[cfg(feature = "redis-queue")]
+        redis_queue: match &config.redis {
+            Some(redis) => Some(redis_queue::RedisQueue::connect(redis).await),
+            None => None,
+        },
+        last_opponents: Arc::new(Mutex::new(HashMap::new())),
+        rejoins: Arc::new(Mutex::new(HashMap::new())),
+        users: user_store,
+        pool,
+        top10_cache: Arc::new(Cached::new(config.leaderboard_ttl)),
+        stats_cache: Arc::new(Cached::new(config.leaderboard_ttl)),
+        games: Arc::new(Mutex::new(HashMap::new())),
+        presence: Presence::default(),
+        connection_limiter: ConnectionLimiter::new(config.ws_max_connections_per_ip, config.ws_connects_per_minute, config.ws_ip_allowlist.clone()),
+        ip_filter,
+        next_game_id: Arc::new(AtomicU64::new(1)),
+        announcements: broadcast::channel(16).0,
+        maintenance: Arc::new(AtomicBool::new(false)),
+        db_healthy: Arc::new(AtomicBool::new(true)),
+        totp_key: config.totp_key.as_ref().map(SecretBox::new),
+        webhooks: Webhooks::start(config.webhook.clone()),
+        config: config.clone(),
     };
 
-    let app = Router::new()
+    let mut routes = Router::new()
         .route("/api/register", post(check_register))
         .route("/api/login", post(check_login))
+        .route("/api/reactivate", post(check_login))
+        .route("/api/logout", post(logout))
+        .route("/api/guest", post(guest))
         .route("/api/search", get(websocket_connect))
+        .route("/api/ready", get(ready))
+        .route("/api/games", get(live_games))
+        .route("/api/games/{id}/spectate", get(spectate))
+        .route("/api/create_room", post(create_room))
+        .route("/api/admin/games/{id}/end", post(end_game))
+        .route("/api/admin/announce", post(announce))
+        .route("/api/admin/maintenance", post(set_maintenance));
+    if appmod.pool.is_some() {
+        routes = routes.merge(postgres_routes());
+    }
+
+    let app = with_layers(routes.with_state(appmod.clone()), config.request_log_level);
+
+    let guest_cleanup = appmod.pool.clone().map(|pool| tokio::spawn(expire_guests_periodically(pool, config.guest_ttl.as_secs() as i64)));
+    let health_check = tokio::spawn(check_database_periodically(appmod.clone(), config.db_health_interval));
+
+    #[cfg(feature = "redis-queue")]
+    if let Some(redis) = appmod.redis_queue.clone() {
+        tokio::spawn(redis_queue::listen_for_claims(redis, appmod.clone()));
+    }
+
+    let listener = tokio::net::TcpListener::bind(config.bind_addr).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal(appmod.clone()))
+        .await
+        .unwrap();
+
+    drain_games(&appmod).await;
+    if let Some(guest_cleanup) = guest_cleanup {
+        guest_cleanup.abort();
+    }
+    health_check.abort();
+}
+
+/// Everything that needs PostgreSQL beyond the [`UserStore`]: stats and
+/// history, social features, tournaments, 2FA, email and bans.
+fn postgres_routes() -> Router<AppMod> {
+    Router::new()
+        .route("/api/forgot-password", post(password_reset::forgot))
+        .route("/api/reset-password", post(password_reset::reset))
+        .route("/api/email", post(email::set))
+        .route("/api/verify-email/send", post(email::send))
+        .route("/api/verify-email/confirm", post(email::confirm))
         .route("/api/top10", get(top10))
-        .with_state(appmod);
+        .route("/api/stats", get(stats))
+        .route("/api/top-streaks", get(top_streaks))
+        .route("/api/leaderboard", get(leaderboard))
+        .route("/api/elo-history/{username}", get(elo_history))
+        .route("/api/history/{username}", get(history))
+        .route("/api/profile/{username}", get(player_profile))
+        .route("/api/users", get(users))
+        .route("/api/achievements/{username}", get(achievements))
+        .route("/api/presence/{username}", get(presence))
+        .route("/api/h2h/{a}/{b}", get(h2h))
+        .route("/api/recent-games", get(recent))
+        .route("/api/tournament", post(create_tournament))
+        .route("/api/tournament/{id}", get(bracket))
+        .route("/api/tournament/{id}/standings", get(standings))
+        .route("/api/block", post(block))
+        .route("/api/unblock", post(unblock))
+        .route("/api/friends", get(friends::list))
+        .route("/api/friends/request", post(friends::request))
+        .route("/api/friends/accept", post(friends::accept))
+        .route("/api/friends/remove", post(friends::remove))
+        .route("/api/challenge/{friend}", post(challenge))
+        .route("/api/rename", post(rename))
+        .route("/api/export", post(export))
+        .route("/api/2fa/enroll", post(two_factor::enroll))
+        .route("/api/2fa/verify", post(two_factor::verify))
+        .route("/api/deactivate", post(deactivate))
+        .route("/api/admin/auth-events/{username}", get(auth_history))
+        .route("/api/admin/ban-ip", post(admin::ban))
+        .route("/api/admin/unban-ip", post(admin::unban))
+}
+
+/// Panic recovery, request logging and `x-request-id`, around every route.
+fn with_layers(app: Router, request_log_level: Level) -> Router {
+    app
+        .layer(CatchPanicLayer::new())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(RequestSpan::new(request_log_level))
+                .on_request(())
+                .on_response(DefaultOnResponse::new().level(request_log_level).latency_unit(LatencyUnit::Millis)),
+        )
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+/// Sends panic messages through `tracing`, with a backtrace, instead of plain
+/// stderr. The panicking request gets `500` from [`CatchPanicLayer`]; a
+/// panicking WebSocket task only loses its own connection.
+fn log_panics() {
+    std::panic::set_hook(Box::new(|info| {
+        error!("{}\n{}", info, std::backtrace::Backtrace::force_capture());
+    }));
+}
+
+async fn shutdown_signal(appmod: AppMod) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Ctrl+C handler error.");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("SIGTERM handler error.")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+
+    appmod.maintenance.store(true, Ordering::Relaxed);
+    info!("Shutdown requested, no new games will be started");
+}
+
+async fn drain_games(appmod: &AppMod) {
+    loop {
+        let running = appmod.games.lock().unwrap().len();
+        if running == 0 {
+            break;
+        }
+        info!("Waiting for {} game(s) to finish", running);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn expire_guests_periodically(pool: Pool<Postgres>, ttl_secs: i64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(3600));
+    loop {
+        interval.tick().await;
+        match expire_guests(pool.clone(), ttl_secs).await {
+            Ok(0) => {}
+            Ok(deleted) => info!("Removed {} idle guest account(s)", deleted),
+            Err(e) => error!("Failed to expire guest accounts: {}", e),
+        }
+    }
+}
+
+async fn check_database_periodically(appmod: AppMod, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let healthy = match appmod.users.ping().await {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Database health check failed: {}", e);
+                false
+            }
+        };
+
+        if appmod.db_healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            info!("Database is {}", if healthy { "reachable again" } else { "unreachable" });
+        }
+    }
+}
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+async fn ready(State(appmod): State<AppMod>) -> impl IntoResponse {
+    let database = appmod.db_healthy.load(Ordering::Relaxed);
+    let maintenance = appmod.maintenance.load(Ordering::Relaxed);
+    let status = if database && !maintenance { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+    (status, Json(serde_json::json!({ "database": database, "maintenance": maintenance })))
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// `503` when the database is overloaded (statement or pool timeout), `500` for
+/// anything else.
+pub fn db_error_status(e: &sqlx::Error) -> StatusCode {
+    if is_timeout(e) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
 }
 
 async fn top10(State(appmod): State<AppMod>) -> impl IntoResponse {
-    if let Ok(result) = top10_from_database(appmod.pool.clone()).await {
-        (StatusCode::OK, Json(result))
+    match appmod.top10_cache.get(|| top10_from_database(appmod.pg(), appmod.config.ranking)).await {
+        Ok(result) => (StatusCode::OK, Json(result)),
+        Err(e) => (db_error_status(&e), Json(vec![UserRank::new()])),
+    }
+}
+
+async fn stats(State(appmod): State<AppMod>) -> impl IntoResponse {
+    let max_game_secs = appmod.config.max_game_duration.as_secs_f64();
+    appmod.stats_cache.get(|| global_stats(appmod.pg(), max_game_secs)).await
+        .map(Json)
+        .map_err(|e| db_error_status(&e))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum LeaderboardSort {
+    #[default]
+    Points,
+    Active,
+}
+
+#[derive(Deserialize)]
+struct LeaderboardQuery {
+    #[serde(default)]
+    sort: LeaderboardSort,
+    since: Option<DateTime<Utc>>,
+    limit: Option<i64>,
+}
+
+async fn leaderboard(State(appmod): State<AppMod>, Query(query): Query<LeaderboardQuery>) -> Response {
+    match query.sort {
+        LeaderboardSort::Points => top10(State(appmod)).await.into_response(),
+        LeaderboardSort::Active => {
+            let since = query.since.unwrap_or_else(|| Utc::now() - chrono::Duration::days(7));
+            let limit = query.limit.unwrap_or(10).clamp(1, 50);
+
+            match most_active(appmod.pg(), since, limit).await {
+                Ok(players) => (StatusCode::OK, Json(players)).into_response(),
+                Err(e) => (db_error_status(&e), Json(Vec::<ActivePlayer>::new())).into_response(),
+            }
+        }
+    }
+}
+
+async fn top_streaks(State(appmod): State<AppMod>) -> impl IntoResponse {
+    match top_streaks_from_database(appmod.pg()).await {
+        Ok(result) => (StatusCode::OK, Json(result)),
+        Err(e) => (db_error_status(&e), Json(Vec::new())),
+    }
+}
+
+#[derive(Deserialize)]
+struct TimeRange {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+async fn elo_history(State(appmod): State<AppMod>, Path(username): Path<String>, Query(range): Query<TimeRange>) -> impl IntoResponse {
+    let user_id = match user_id_from_name(appmod.pg(), &username).await {
+        Ok(Some(id)) => id,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(Vec::new())),
+        Err(e) => return (db_error_status(&e), Json(Vec::new())),
+    };
+
+    match elo_history_from_database(appmod.pg(), user_id, range.from, range.to).await {
+        Ok(history) => (StatusCode::OK, Json(history)),
+        Err(e) => (db_error_status(&e), Json(Vec::new())),
+    }
+}
+
+async fn history(State(appmod): State<AppMod>, Path(username): Path<String>) -> impl IntoResponse {
+    let user_id = match user_id_from_name(appmod.pg(), &username).await {
+        Ok(Some(id)) => id,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(Vec::new())),
+        Err(e) => return (db_error_status(&e), Json(Vec::new())),
+    };
+
+    match history_from_database(appmod.pg(), user_id).await {
+        Ok(games) => (StatusCode::OK, Json(games)),
+        Err(e) => (db_error_status(&e), Json(Vec::new())),
+    }
+}
+
+async fn player_profile(State(appmod): State<AppMod>, Path(username): Path<String>) -> impl IntoResponse {
+    match profile(appmod.pg(), &username, appmod.config.ranking).await {
+        Ok(Some(profile)) => (StatusCode::OK, Json(Some(profile))),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(None)),
+        Err(e) => (db_error_status(&e), Json(None)),
+    }
+}
+
+async fn h2h(State(appmod): State<AppMod>, Path((a, b)): Path<(String, String)>) -> impl IntoResponse {
+    let ids = (
+        user_id_from_name(appmod.pg(), &a).await,
+        user_id_from_name(appmod.pg(), &b).await,
+    );
+
+    let (a_id, b_id) = match ids {
+        (Ok(Some(a_id)), Ok(Some(b_id))) => (a_id, b_id),
+        (Ok(_), Ok(_)) => return (StatusCode::NOT_FOUND, Json(None)),
+        (Err(e), _) | (_, Err(e)) => return (db_error_status(&e), Json(None)),
+    };
+
+    match head_to_head(appmod.pg(), a_id, b_id).await {
+        Ok(record) => (StatusCode::OK, Json(Some(record))),
+        Err(e) => (db_error_status(&e), Json(None)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Limit {
+    pub limit: Option<i64>,
+}
+
+async fn recent(State(appmod): State<AppMod>, Query(query): Query<Limit>) -> impl IntoResponse {
+    let limit = query.limit.unwrap_or(20).clamp(1, 50);
+
+    match recent_games(appmod.pg(), limit).await {
+        Ok(games) => (StatusCode::OK, Json(games)),
+        Err(e) => (db_error_status(&e), Json(Vec::new())),
+    }
+}
+
+#[derive(Deserialize)]
+struct UserSearch {
+    #[serde(default)]
+    q: String,
+    limit: Option<i64>,
+}
+
+async fn users(State(appmod): State<AppMod>, Query(query): Query<UserSearch>) -> impl IntoResponse {
+    let prefix = query.q.trim();
+    if prefix.is_empty() {
+        return (StatusCode::OK, Json(Vec::new()));
+    }
+
+    let limit = query.limit.unwrap_or(10).clamp(1, 50);
+    match search_users(appmod.pg(), prefix, limit).await {
+        Ok(names) => (StatusCode::OK, Json(names)),
+        Err(e) => (db_error_status(&e), Json(Vec::new())),
+    }
+}
+
+#[derive(Deserialize)]
+struct BlockRequest {
+    username: String,
+}
+
+pub async fn authenticated_user(appmod: &AppMod, headers: &HeaderMap) -> Result<i32, StatusCode> {
+    let token = bearer_token(headers).ok_or(StatusCode::UNAUTHORIZED)?;
+    appmod.users.player_from_token(token, appmod.config.token_ttl.as_secs_f64())
+        .await
+        .map_err(|e| db_error_status(&e))?
+        .map(|(id, _)| id)
+        .ok_or(StatusCode::UNAUTHORIZED)
+}
+
+/// The caller's id and the id of `username`, who must be someone else.
+pub async fn other_user(appmod: &AppMod, headers: &HeaderMap, username: &str) -> Result<(i32, i32), StatusCode> {
+    let me = authenticated_user(appmod, headers).await?;
+    let other = user_id_from_name(appmod.pg(), username)
+        .await
+        .map_err(|e| db_error_status(&e))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if me == other {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok((me, other))
+}
+
+async fn block(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<BlockRequest>) -> StatusCode {
+    let (me, other) = match other_user(&appmod, &headers, &payload.username).await {
+        Ok(ids) => ids,
+        Err(status) => return status,
+    };
+
+    match block_user(appmod.pg(), me, other).await {
+        Ok(()) => StatusCode::OK,
+        Err(e) => db_error_status(&e),
+    }
+}
+
+async fn unblock(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<BlockRequest>) -> StatusCode {
+    let (me, other) = match other_user(&appmod, &headers, &payload.username).await {
+        Ok(ids) => ids,
+        Err(status) => return status,
+    };
+
+    match unblock_user(appmod.pg(), me, other).await {
+        Ok(()) => StatusCode::OK,
+        Err(e) => db_error_status(&e),
+    }
+}
+
+async fn guest(State(appmod): State<AppMod>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap) -> impl IntoResponse {
+    let ip = match appmod.ip_filter.check(addr, &headers) {
+        Ok(ip) => ip,
+        Err(status) => return (status, Json(None)),
+    };
+
+    match appmod.users.create_guest(appmod.config.token_format).await {
+        Ok(guest) => {
+            info!("Guest {} created", guest.username);
+            audit(&appmod, &guest.username, AuthEvent::Guest, ip);
+            (StatusCode::CREATED, Json(Some(guest)))
+        }
+        Err(e) => {
+            error!("Failed to create guest: {}", e);
+            (db_error_status(&e), Json(None))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Rename {
+    username: String,
+}
+
+async fn rename(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<Rename>) -> StatusCode {
+    let id = match authenticated_user(&appmod, &headers).await {
+        Ok(id) => id,
+        Err(status) => return status,
+    };
+
+    let Ok(username) = validate_username(&payload.username) else {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    };
+
+    match rename_user(appmod.pg(), id, username).await {
+        Ok(true) => {
+            info!("User {} renamed to {}", id, username);
+            StatusCode::OK
+        }
+        Ok(false) => StatusCode::CONFLICT,
+        Err(e) => db_error_status(&e),
+    }
+}
+
+async fn export(State(appmod): State<AppMod>, headers: HeaderMap) -> impl IntoResponse {
+    let id = match authenticated_user(&appmod, &headers).await {
+        Ok(id) => id,
+        Err(status) => return (status, Json(None)),
+    };
+
+    match export_user_data(appmod.pg(), id).await {
+        Ok(data) => (StatusCode::OK, Json(Some(data))),
+        Err(e) => {
+            error!("Failed to export data for user {}: {}", id, e);
+            (db_error_status(&e), Json(None))
+        }
+    }
+}
+
+async fn deactivate(State(appmod): State<AppMod>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap) -> StatusCode {
+    let id = match authenticated_user(&appmod, &headers).await {
+        Ok(id) => id,
+        Err(status) => return status,
+    };
+
+    match deactivate_user(appmod.pg(), id).await {
+        Ok(username) => {
+            info!("{} deactivated their account", username);
+            audit(&appmod, &username, AuthEvent::Deactivate, appmod.ip_filter.client_ip(addr, &headers));
+            StatusCode::OK
+        }
+        Err(e) => db_error_status(&e),
+    }
+}
+
+/// Trims the name and checks it is 3–20 characters of `A–Z`, `a–z`, `0–9` and
+/// `_`. Returns the trimmed name, or the code of the rule it breaks.
+pub fn validate_username(name: &str) -> Result<&str, &'static str> {
+    let name = name.trim();
+    if name.len() < 3 {
+        Err("USERNAME_TOO_SHORT")
+    } else if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Err("USERNAME_INVALID_CHARACTERS")
+    } else if name.len() > 20 {
+        Err("USERNAME_TOO_LONG")
     } else {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(vec![UserRank::new()]))
+        Ok(name)
     }
 }
 
-async fn check_login(State(appmod): State<AppMod>, Json(payload): Json<Login>) -> impl IntoResponse {
-    println!("Przyszedl login {:?}", payload);
-    let (result, token) = check_password(appmod.pool, &payload).await;
-    if result {
-        println!("Użytkownik {:?} zostal zalogowany. Token {}", payload, token);
-        (StatusCode::ACCEPTED, Json(token))
+/// At least 8 characters, with at least one letter and one digit. The error is
+/// the code of the first rule the password breaks.
+pub fn validate_password(password: &str) -> Result<(), &'static str> {
+    if password.chars().count() < 8 {
+        Err("PASSWORD_TOO_SHORT")
+    } else if !password.chars().any(char::is_alphabetic) {
+        Err("PASSWORD_NEEDS_LETTER")
+    } else if !password.chars().any(|c| c.is_ascii_digit()) {
+        Err("PASSWORD_NEEDS_DIGIT")
     } else {
-        println!("Użytkownik {:?} nie zostal zalogowany. Id {}", payload, token);
-        (StatusCode::NOT_FOUND, Json(String::from("ERROR")))
+        Ok(())
     }
 }
 
-async fn check_register(State(appmod): State<AppMod>, Json(payload): Json<Login>) -> StatusCode {
-    println!("Przyszla rejstracja {:?}", payload);
+/// Records an authentication event in the background; a failed write is logged
+/// and otherwise ignored so it can never break the request itself.
+pub fn audit(appmod: &AppMod, username: &str, event: AuthEvent, ip: IpAddr) {
+    // The audit log is kept in Postgres only.
+    let Some(pool) = appmod.pool.clone() else { return };
+    let username = username.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = log_auth_event(pool, &username, event, &ip.to_string()).await {
+            warn!("Failed to record {:?} for {}: {}", event, username, e);
+        }
+    });
+}
+
+async fn check_login(State(appmod): State<AppMod>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, Json(payload): Json<Login>) -> impl IntoResponse {
+    let ip = match appmod.ip_filter.check(addr, &headers) {
+        Ok(ip) => ip,
+        Err(status) => return (status, Json(String::from("BANNED"))),
+    };
+
+    info!("Login attempt for {}", payload.name);
+    let verified = match appmod.users.verify_password(&payload).await {
+        Ok(verified) => verified,
+        Err(e) => {
+            error!("Checking the password of {} failed: {}", payload.name, e);
+            return (db_error_status(&e), Json(String::from("ERROR")));
+        }
+    };
+    if !verified {
+        info!("Login failed for {}", payload.name);
+        audit(&appmod, &payload.name, AuthEvent::LoginFailure, ip);
+        return (StatusCode::NOT_FOUND, Json(String::from("ERROR")));
+    }
+
+    if let Err(status) = check_second_factor(&appmod, &payload.name, payload.code.as_deref()).await {
+        info!("Login for {} stopped at the second factor", payload.name);
+        audit(&appmod, &payload.name, AuthEvent::LoginFailure, ip);
+        return (status, Json(String::from("TOTP_REQUIRED")));
+    }
+
+    let token = match appmod.users.new_token(&payload, appmod.config.token_format).await {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Storing a new token for {} failed: {}", payload.name, e);
+            return (db_error_status(&e), Json(String::from("ERROR")));
+        }
+    };
+    info!("{} logged in", payload.name);
+    audit(&appmod, &payload.name, AuthEvent::LoginSuccess, ip);
+    (StatusCode::ACCEPTED, Json(token))
+}
 
-    let name_len = payload.name.trim().chars().count();
-    let password_len = payload.password.chars().count();
+#[derive(Deserialize)]
+struct LogoutRequest {
+    token: String,
+}
 
-    if name_len < 8 || password_len < 8 {
-        println!("Rejestracja odrzucona: nazwa lub haslo krotsze niz 8 znakow.");
+async fn logout(State(appmod): State<AppMod>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, payload: Option<Json<LogoutRequest>>) -> StatusCode {
+    let Some(token) = payload.map(|Json(body)| body.token).or_else(|| bearer_token(&headers).map(str::to_owned)) else {
         return StatusCode::BAD_REQUEST;
+    };
+
+    match appmod.users.clear_token(&token).await {
+        Ok(Some(username)) => {
+            info!("{} logged out", username);
+            audit(&appmod, &username, AuthEvent::Logout, appmod.ip_filter.client_ip(addr, &headers));
+            StatusCode::OK
+        }
+        Ok(None) => StatusCode::NOT_FOUND,
+        Err(e) => db_error_status(&e),
     }
+}
 
-    if create_new_user(appmod.pool, &payload).await {
-        println!("Użytkownik {:?} zostal utworzony.", payload);
-        StatusCode::ACCEPTED
-    } else {
-        println!("Użytkownik {:?} nie zostal utworzony.", payload);
-        StatusCode::FOUND
+async fn check_register(State(appmod): State<AppMod>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, Json(mut payload): Json<Login>) -> Response {
+    let ip = match appmod.ip_filter.check(addr, &headers) {
+        Ok(ip) => ip,
+        Err(status) => return status.into_response(),
+    };
+
+    info!("Registration attempt for {}", payload.name);
+
+    payload.name = match validate_username(&payload.name) {
+        Ok(name) => name.to_string(),
+        Err(rule) => {
+            info!("Registration rejected for {:?}: {}", payload.name, rule);
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": rule }))).into_response();
+        }
+    };
+
+    if let Err(rule) = validate_password(&payload.password) {
+        info!("Registration rejected for {}: {}", payload.name, rule);
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": rule }))).into_response();
     }
-}
\ No newline at end of file
+
+    payload.email = payload.email.map(|email| email.trim().to_string()).filter(|email| !email.is_empty());
+    if let Some(email) = &payload.email {
+        if !email::is_valid_email(email) {
+            info!("Registration rejected for {}: invalid email address", payload.name);
+            return StatusCode::UNPROCESSABLE_ENTITY.into_response();
+        }
+        match appmod.users.is_email_taken(email).await {
+            Ok(false) => {}
+            Ok(true) => {
+                info!("Registration rejected for {}: email already in use", payload.name);
+                return StatusCode::CONFLICT.into_response();
+            }
+            Err(e) => return db_error_status(&e).into_response(),
+        }
+    }
+
+    let status = match appmod.users.create_user(&payload, appmod.config.bcrypt_cost).await {
+        Ok(true) => {
+            info!("User {} created", payload.name);
+            audit(&appmod, &payload.name, AuthEvent::Register, ip);
+            StatusCode::ACCEPTED
+        }
+        Ok(false) => {
+            info!("User {} not created: name already taken", payload.name);
+            StatusCode::FOUND
+        }
+        Err(e) => {
+            error!("Creating user {} failed: {}", payload.name, e);
+            db_error_status(&e)
+        }
+    };
+    status.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    async fn status_of(app: Router, path: &str) -> StatusCode {
+        app.oneshot(Request::get(path).body(Body::empty()).unwrap()).await.unwrap().status()
+    }
+
+    async fn panics() -> StatusCode {
+        panic!("deliberate panic")
+    }
+
+    #[tokio::test]
+    async fn a_panicking_route_answers_500() {
+        let app = with_layers(
+            Router::new()
+                .route("/panic", get(panics))
+                .route("/ok", get(|| async { "ok" })),
+            Level::INFO,
+        );
+
+        assert_eq!(status_of(app.clone(), "/panic").await, StatusCode::INTERNAL_SERVER_ERROR);
+        // The router is still serving afterwards.
+        assert_eq!(status_of(app, "/ok").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,119 @@
+//! # Password Reset
+//!
+//! Getting back into an account whose password is forgotten.
+//!
+//! 1. `POST /api/forgot-password` with a username or a verified email address
+//!    creates a reset token that is valid for `PASSWORD_RESET_TTL_SECS`. Asking
+//!    again replaces the previous one.
+//! 2. `POST /api/reset-password` with that token and a new password sets the
+//!    password and ends the account's current session.
+//!
+//! Tokens are stored as SHA-256 hashes and deleted as soon as they are used, so
+//! each works once; expired tokens are refused and cleared out the next time a
+//! reset is requested. The answer to step 1 is the same whether or not the
+//! account exists.
+//!
+//! There is no email delivery yet, so the token has to reach the user some other
+//! way. With `MAIL_DEV=true` it is logged and returned in the response, which is
+//! only meant for local development.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use std::net::SocketAddr;
+use axum::Json;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::info;
+use crate::{audit, db_error_status, validate_password, AppMod};
+use crate::database::{create_password_reset, generate_token, update_password, use_password_reset, username_from_verified_email, AuthEvent, TokenFormat};
+
+const RESET_TOKEN_BYTES: usize = 32;
+
+#[derive(Deserialize)]
+pub struct ForgotPassword {
+    username: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    token: String,
+    password: String,
+}
+
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.trim().as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub async fn forgot(State(appmod): State<AppMod>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, Json(payload): Json<ForgotPassword>) -> impl IntoResponse {
+    if let Err(status) = appmod.ip_filter.check(addr, &headers) {
+        return (status, Json(None));
+    }
+
+    let username = match (payload.username, payload.email) {
+        (Some(username), _) => Some(username),
+        (None, Some(email)) => match username_from_verified_email(appmod.pg(), email.trim()).await {
+            Ok(username) => username,
+            Err(e) => return (db_error_status(&e), Json(None)),
+        },
+        (None, None) => return (StatusCode::BAD_REQUEST, Json(None)),
+    };
+
+    let token = generate_token(TokenFormat::Random { bytes: RESET_TOKEN_BYTES });
+    let expires_in = appmod.config.password_reset_ttl;
+
+    let created = match &username {
+        Some(username) => match create_password_reset(appmod.pg(), username, &hash_token(&token), expires_in.as_secs_f64()).await {
+            Ok(created) => created,
+            Err(e) => return (db_error_status(&e), Json(None)),
+        },
+        None => false,
+    };
+
+    let mut body = serde_json::json!({ "expires_in_secs": expires_in.as_secs() });
+    if let Some(username) = username.filter(|_| created) {
+        info!("Password reset requested for {}", username);
+        if appmod.config.mail_dev {
+            info!("Password reset token for {}: {}", username, token);
+            body["token"] = token.into();
+        }
+    }
+
+    (StatusCode::ACCEPTED, Json(Some(body)))
+}
+
+pub async fn reset(State(appmod): State<AppMod>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, Json(payload): Json<ResetPassword>) -> StatusCode {
+    let ip = match appmod.ip_filter.check(addr, &headers) {
+        Ok(ip) => ip,
+        Err(status) => return status,
+    };
+
+    if validate_password(&payload.password).is_err() {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let id = match use_password_reset(appmod.pg(), &hash_token(&payload.token)).await {
+        Ok(Some(id)) => id,
+        Ok(None) => return StatusCode::NOT_FOUND,
+        Err(e) => return db_error_status(&e),
+    };
+
+    match update_password(appmod.pg(), id, &payload.password, appmod.config.bcrypt_cost).await {
+        Ok(username) => {
+            info!("{} reset their password", username);
+            audit(&appmod, &username, AuthEvent::PasswordReset, ip);
+            StatusCode::OK
+        }
+        Err(e) => db_error_status(&e),
+    }
+}
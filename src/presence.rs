@@ -0,0 +1,195 @@
+//! # Presence
+//!
+//! Which users are connected right now.
+//!
+//! Every `/api/search` socket holds a [`PresenceGuard`] for its user for as long
+//! as the connection lives, whether it is waiting in a queue, playing or being
+//! turned away. The guard travels with the socket inside the `Player`, so the
+//! user is marked offline however the connection ends. A user with several
+//! sockets stays online until the last one closes. While one of those sockets
+//! is playing a game ([`PresenceGuard::set_in_game`]) the user is
+//! [`PresenceState::InGame`].
+//!
+//! [`Presence::notify`] reaches a user on whatever socket they have open, for
+//! things like challenges from friends. Sockets waiting in a queue or playing a
+//! game pick these [`Notice`]s up; nothing is kept for users who are offline.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::database::user_id_from_name;
+use crate::protocol::ServerMessage;
+use crate::{db_error_status, AppMod};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    Offline,
+    Online,
+    InGame,
+}
+
+/// Open sockets of one user, and how many of them are in a game.
+#[derive(Default)]
+struct Connections {
+    sockets: usize,
+    in_game: usize,
+}
+
+/// A message for one user's open sockets.
+#[derive(Clone)]
+pub struct Notice {
+    pub user_id: i32,
+    pub message: ServerMessage,
+}
+
+#[derive(Clone)]
+pub struct Presence {
+    connections: Arc<Mutex<HashMap<i32, Connections>>>,
+    notices: broadcast::Sender<Notice>,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            notices: broadcast::channel(64).0,
+        }
+    }
+}
+
+impl Presence {
+    pub fn connect(&self, user_id: i32) -> PresenceGuard {
+        self.connections.lock().unwrap().entry(user_id).or_default().sockets += 1;
+        PresenceGuard { presence: self.clone(), user_id, in_game: false }
+    }
+
+    pub fn state(&self, user_id: i32) -> PresenceState {
+        match self.connections.lock().unwrap().get(&user_id) {
+            None => PresenceState::Offline,
+            Some(connections) if connections.in_game > 0 => PresenceState::InGame,
+            Some(_) => PresenceState::Online,
+        }
+    }
+
+    pub fn is_online(&self, user_id: i32) -> bool {
+        self.state(user_id) != PresenceState::Offline
+    }
+
+    pub fn notify(&self, user_id: i32, message: ServerMessage) {
+        let _ = self.notices.send(Notice { user_id, message });
+    }
+
+    pub fn notices(&self) -> broadcast::Receiver<Notice> {
+        self.notices.subscribe()
+    }
+}
+
+/// One open connection of `user_id`; dropping it closes the connection's entry.
+pub struct PresenceGuard {
+    presence: Presence,
+    user_id: i32,
+    in_game: bool,
+}
+
+impl PresenceGuard {
+    pub fn set_in_game(&mut self, in_game: bool) {
+        if self.in_game == in_game {
+            return;
+        }
+        self.in_game = in_game;
+
+        let mut connections = self.presence.connections.lock().unwrap();
+        if let Some(connections) = connections.get_mut(&self.user_id) {
+            if in_game {
+                connections.in_game += 1;
+            } else {
+                connections.in_game -= 1;
+            }
+        }
+    }
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        self.set_in_game(false);
+
+        let mut connections = self.presence.connections.lock().unwrap();
+        if let Some(entry) = connections.get_mut(&self.user_id) {
+            entry.sockets -= 1;
+            if entry.sockets == 0 {
+                connections.remove(&self.user_id);
+            }
+        }
+    }
+}
+
+pub async fn presence(State(appmod): State<AppMod>, Path(username): Path<String>) -> Result<Json<serde_json::Value>, StatusCode> {
+    let user_id = user_id_from_name(appmod.pg(), &username)
+        .await
+        .map_err(|e| db_error_status(&e))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(serde_json::json!({ "status": appmod.presence.state(user_id) })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaving_the_queue_goes_offline() {
+        let presence = Presence::default();
+        let guard = presence.connect(1);
+        assert_eq!(presence.state(1), PresenceState::Online);
+
+        drop(guard);
+        assert_eq!(presence.state(1), PresenceState::Offline);
+    }
+
+    #[test]
+    fn game_end_goes_back_online() {
+        let presence = Presence::default();
+        let mut guard = presence.connect(1);
+        guard.set_in_game(true);
+        assert_eq!(presence.state(1), PresenceState::InGame);
+
+        guard.set_in_game(false);
+        assert_eq!(presence.state(1), PresenceState::Online);
+    }
+
+    #[test]
+    fn dropping_out_of_a_game_goes_offline() {
+        let presence = Presence::default();
+        let mut guard = presence.connect(1);
+        guard.set_in_game(true);
+
+        drop(guard);
+        assert_eq!(presence.state(1), PresenceState::Offline);
+    }
+
+    #[test]
+    fn online_until_the_last_socket_closes() {
+        let presence = Presence::default();
+        let mut playing = presence.connect(1);
+        let waiting = presence.connect(1);
+        playing.set_in_game(true);
+        // Marking the same socket twice doesn't count it twice.
+        playing.set_in_game(true);
+
+        drop(playing);
+        assert_eq!(presence.state(1), PresenceState::Online);
+        drop(waiting);
+        assert_eq!(presence.state(1), PresenceState::Offline);
+    }
+}
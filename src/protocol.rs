@@ -0,0 +1,368 @@
+//! # Wire Protocol
+//!
+//! The messages exchanged over the game and spectator WebSockets, and how they
+//! are encoded.
+//!
+//! Everything a client sends is defined here — the token message that opens
+//! `/api/search` (or its query-string form), `ready`, moves and spectator chat —
+//! and everything the server sends back, together with [`send_message`] and
+//! [`decode`], which apply the connection's [`WireFormat`] in both directions. `game.rs` and `spectate.rs` take these
+//! types from this module only, so the two sockets can't drift apart.
+//!
+//! ## Client messages
+//!
+//! Every frame a client sends is one [`ClientMessage`], named by its `"type"`:
+//!
+//! ```json
+//! { "type": "token", "token": "…", "protocol_version": 1 }
+//! { "type": "ready", "ready": true }
+//! { "type": "move", "field": 4 }
+//! { "type": "chat", "text": "nice move" }
+//! { "type": "rematch" }
+//! { "type": "cancel" }
+//! ```
+//!
+//! Messages without a `"type"` are still understood by the field that gives
+//! them away (`token`, `field`, `ready` or `chat`, or an `"action"` of
+//! `"rematch"` or `"cancel"`),
+//! which is how clients written before the tag existed talk.
+//!
+//! All parsing goes through [`parse_client_message`] (or [`decode`] for either
+//! frame type). Anything it can't make sense of comes back as a
+//! [`ProtocolError`], whose [`ProtocolError::reply`] is sent to the client:
+//!
+//! | `error` | Meaning |
+//! |---------|---------|
+//! | `MALFORMED_MESSAGE` | Not a JSON / MessagePack object, or no way to tell its type |
+//! | `UNKNOWN_MESSAGE_TYPE` | A `type` this server doesn't know; echoed as `message_type` |
+//! | `INVALID_MESSAGE` | Known `type`, but fields missing or wrong; `detail` says which |
+//! | `UNEXPECTED_MESSAGE` | Valid, but meaningless right now (e.g. a move while queued) |
+//!
+//! ## Server messages
+//!
+//! Everything the server sends is one [`ServerMessage`], and it is tagged the
+//! same way, so clients can dispatch on `"type"` instead of probing for fields.
+//! Apart from the tag each message keeps the keys it had before, e.g.
+//!
+//! ```json
+//! { "type": "match_found", "match_found": { "you": "alice", "opponent": "bob", "your_symbol": "O" } }
+//! { "type": "queue_position", "queue_position": 2, "estimated_wait_secs": 14 }
+//! { "type": "error", "error": "SERVER_FULL" }
+//! ```
+//!
+//! which is the compatibility shim: a client that ignores `type` sees exactly
+//! what it saw before. Game state is `state` while the game runs and `result`
+//! once it has ended; spectators get `snapshot` instead.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use axum::extract::ws::{Message, WebSocket};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::bot::Difficulty;
+use crate::game::{BoardOptions, GameMode, Opening, Rules, ServerResponse, Snapshot, Variant};
+
+/// Wire protocol spoken by this server. Bump it, and extend
+/// [`SUPPORTED_PROTOCOLS`], whenever a change would confuse older clients.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const SUPPORTED_PROTOCOLS: &[u32] = &[PROTOCOL_VERSION];
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+/// Clients that predate the handshake don't send a version; they speak version 1.
+fn legacy_protocol() -> u32 {
+    1
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Token(TokenRequest),
+    Ready(Ready),
+    Move(Move),
+    Chat(Chat),
+    Rematch,
+    Cancel,
+}
+
+impl ClientMessage {
+    /// Every `"type"` above, for telling an unknown one from a malformed message.
+    const TYPES: &[&str] = &["token", "ready", "move", "chat", "rematch", "cancel"];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientMessage::Token(_) => "token",
+            ClientMessage::Ready(_) => "ready",
+            ClientMessage::Move(_) => "move",
+            ClientMessage::Chat(_) => "chat",
+            ClientMessage::Rematch => "rematch",
+            ClientMessage::Cancel => "cancel",
+        }
+    }
+
+    pub fn into_move(self) -> Result<Move, ProtocolError> {
+        match self {
+            ClientMessage::Move(player_move) => Ok(player_move),
+            other => Err(ProtocolError::Unexpected(other.kind())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Malformed,
+    UnknownType(String),
+    Invalid { kind: String, detail: String },
+    Unexpected(&'static str),
+}
+
+impl ProtocolError {
+    /// What the client is told.
+    pub fn reply(&self) -> ServerMessage {
+        match self {
+            ProtocolError::Malformed => ServerMessage::error("MALFORMED_MESSAGE"),
+            ProtocolError::UnknownType(kind) => ServerMessage::error_with("UNKNOWN_MESSAGE_TYPE", serde_json::json!({ "message_type": kind })),
+            ProtocolError::Invalid { kind, detail } => {
+                ServerMessage::error_with("INVALID_MESSAGE", serde_json::json!({ "message_type": kind, "detail": detail }))
+            }
+            ProtocolError::Unexpected(kind) => ServerMessage::error_with("UNEXPECTED_MESSAGE", serde_json::json!({ "message_type": kind })),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenRequest {
+    pub token: String,
+    #[serde(default = "legacy_protocol")]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub client_version: Option<String>,
+    #[serde(default)]
+    pub bot: Option<Difficulty>,
+    #[cfg(feature = "redis-queue")]
+    #[serde(default)]
+    pub join: Option<String>,
+    #[serde(default)]
+    pub mode: GameMode,
+    #[serde(default)]
+    pub format: WireFormat,
+    #[serde(default)]
+    pub handicap: Option<Vec<usize>>,
+    #[serde(default)]
+    pub give_handicap: bool,
+    #[serde(default)]
+    pub tournament: Option<i64>,
+    #[serde(default)]
+    pub challenge: Option<String>,
+    #[serde(default)]
+    pub room: Option<String>,
+    #[serde(flatten)]
+    pub rules: Rules,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub token: Option<String>,
+    #[serde(default = "legacy_protocol")]
+    pub protocol_version: u32,
+    pub client_version: Option<String>,
+    pub bot: Option<Difficulty>,
+    #[cfg(feature = "redis-queue")]
+    pub join: Option<String>,
+    #[serde(default)]
+    pub mode: GameMode,
+    #[serde(default)]
+    pub format: WireFormat,
+    /// Comma-separated cells, e.g. `handicap=4,0`.
+    pub handicap: Option<String>,
+    #[serde(default)]
+    pub give_handicap: bool,
+    pub tournament: Option<i64>,
+    pub challenge: Option<String>,
+    pub room: Option<String>,
+    #[serde(default)]
+    pub variant: Variant,
+    #[serde(default)]
+    pub misere: bool,
+    #[serde(default)]
+    pub blitz: bool,
+    #[serde(default)]
+    pub opening: Opening,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Move {
+    pub field: usize,
+    #[serde(default)]
+    pub symbol: Option<BoardOptions>,
+    #[serde(default)]
+    pub board: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Ready {
+    pub ready: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Chat {
+    /// Players' clients tend to call it `text`; both are accepted.
+    #[serde(alias = "text")]
+    pub chat: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChatMessage {
+    pub from: String,
+    pub text: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    MatchFound { match_found: MatchFound },
+    State(Box<ServerResponse>),
+    Result(Box<ServerResponse>),
+    Snapshot(Box<Snapshot>),
+    /// From the opponent, during a game.
+    Chat(ChatMessage),
+    SpectatorChat { spectator_chat: ChatMessage },
+    Error {
+        error: &'static str,
+        /// Extra fields for this error, such as `max_length` for `CHAT_TOO_LONG`.
+        #[serde(flatten)]
+        detail: serde_json::Map<String, Value>,
+    },
+    Warning { warning: &'static str, recommended_version: String },
+    Announcement { announcement: String },
+    QueuePosition { queue_position: usize, estimated_wait_secs: u64 },
+    AllReady { all_ready: bool },
+    TimeSync { server_time: i64, game_remaining_ms: u64 },
+    AutoMove { auto_move: AutoMove },
+    AchievementsUnlocked { achievements_unlocked: Vec<String> },
+    Challenge { challenge: ChallengeInvite },
+    ChallengeWaiting { challenge_waiting: String },
+    RoomWaiting { room_waiting: String },
+    TournamentWaiting { tournament_waiting: Vec<i64> },
+    TournamentForfeit { tournament_forfeit: TournamentForfeit },
+    RematchOffered { expires_in_secs: u64 },
+    RematchRequested { by: String },
+    RematchDeclined,
+    SearchCancelled,
+    OpponentDisconnected { reconnect_within_secs: u64 },
+    OpponentReconnected,
+    #[cfg(feature = "redis-queue")]
+    Redirect { redirect: String },
+}
+
+impl ServerMessage {
+    pub fn error(error: &'static str) -> Self {
+        ServerMessage::Error { error, detail: serde_json::Map::new() }
+    }
+
+    /// An error with extra fields; `detail` must be a JSON object.
+    pub fn error_with(error: &'static str, detail: Value) -> Self {
+        match detail {
+            Value::Object(detail) => ServerMessage::Error { error, detail },
+            _ => ServerMessage::error(error),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct MatchFound {
+    pub you: String,
+    pub opponent: String,
+    pub your_symbol: BoardOptions,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AutoMove {
+    pub field: usize,
+    pub board: Option<usize>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChallengeInvite {
+    pub from: String,
+    pub rules: Rules,
+    pub expires_in_secs: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TournamentForfeit {
+    #[serde(rename = "match")]
+    pub match_id: i64,
+    pub winner: String,
+}
+
+pub async fn send_message(socket: &mut WebSocket, format: WireFormat, message: &ServerMessage) -> Result<(), axum::Error> {
+    let message = match format {
+        WireFormat::Json => {
+            let response_json = serde_json::to_string(message).map_err(axum::Error::new)?;
+            Message::Text(response_json.into())
+        }
+        WireFormat::Msgpack => {
+            let response_msgpack = rmp_serde::to_vec_named(message).map_err(axum::Error::new)?;
+            Message::Binary(response_msgpack.into())
+        }
+    };
+
+    socket.send(message).await?;
+
+    Ok(())
+}
+
+pub fn parse_client_message(text: &str) -> Result<ClientMessage, ProtocolError> {
+    serde_json::from_str(text).map_err(|_| ProtocolError::Malformed).and_then(from_value)
+}
+
+/// A client message from a text (JSON) or binary (MessagePack) frame.
+pub fn decode(message: &Message) -> Result<ClientMessage, ProtocolError> {
+    match message {
+        Message::Text(text) => parse_client_message(text),
+        Message::Binary(bytes) => rmp_serde::from_slice(bytes).map_err(|_| ProtocolError::Malformed).and_then(from_value),
+        _ => Err(ProtocolError::Malformed),
+    }
+}
+
+fn from_value(mut value: Value) -> Result<ClientMessage, ProtocolError> {
+    let object = value.as_object_mut().ok_or(ProtocolError::Malformed)?;
+    let kind = match object.get("type") {
+        Some(Value::String(kind)) => kind.clone(),
+        Some(_) => return Err(ProtocolError::Malformed),
+        None => {
+            let kind = legacy_type(object).ok_or(ProtocolError::Malformed)?;
+            object.insert("type".to_string(), kind.into());
+            kind.to_string()
+        }
+    };
+
+    if !ClientMessage::TYPES.contains(&kind.as_str()) {
+        return Err(ProtocolError::UnknownType(kind));
+    }
+    serde_json::from_value(value).map_err(|e| ProtocolError::Invalid { kind, detail: e.to_string() })
+}
+
+/// The type of an untagged message, from the field only that type has.
+fn legacy_type(object: &serde_json::Map<String, Value>) -> Option<&'static str> {
+    ["token", "ready", "chat"]
+        .into_iter()
+        .find(|key| object.contains_key(*key))
+        .or_else(|| object.contains_key("field").then_some("move"))
+        .or_else(|| match object.get("action").and_then(Value::as_str) {
+            Some("rematch") => Some("rematch"),
+            Some("cancel") => Some("cancel"),
+            _ => None,
+        })
+}
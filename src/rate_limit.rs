@@ -0,0 +1,97 @@
+//! # Connection Limits
+//!
+//! Caps how hard a single IP address can hit `/api/search`.
+//!
+//! Every upgrade takes a [`ConnectionPermit`] from the [`ConnectionLimiter`]
+//! before anything else happens. An address may hold at most
+//! `WS_MAX_CONNECTIONS_PER_IP` open sockets and open at most
+//! `WS_CONNECTS_PER_MINUTE` of them in any 60 seconds; past either limit the
+//! request is answered with `429` and never upgraded. The permit lives as long
+//! as the socket, so the open count goes down again however the connection ends.
+//!
+//! Addresses are the client's as seen through `TRUSTED_PROXIES` (see
+//! [`crate::ip_filter`]). Addresses in `WS_IP_ALLOWLIST` (comma-separated) are
+//! never limited, which is meant for a frontend host that many players share.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Usage {
+    open: usize,
+    /// When each connection of the last [`WINDOW`] was opened, oldest first.
+    recent: VecDeque<Instant>,
+}
+
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    usage: Arc<Mutex<HashMap<IpAddr, Usage>>>,
+    max_open: usize,
+    per_minute: usize,
+    allowlist: Arc<HashSet<IpAddr>>,
+}
+
+impl ConnectionLimiter {
+    /// A limit of `0` switches that limit off.
+    pub fn new(max_open: usize, per_minute: usize, allowlist: HashSet<IpAddr>) -> Self {
+        Self {
+            usage: Arc::new(Mutex::new(HashMap::new())),
+            max_open,
+            per_minute,
+            allowlist: Arc::new(allowlist),
+        }
+    }
+
+    /// A permit for one more connection from `ip`, or `None` if it is over a limit.
+    pub fn acquire(&self, ip: IpAddr) -> Option<ConnectionPermit> {
+        if self.allowlist.contains(&ip) {
+            return Some(ConnectionPermit { limiter: None, ip });
+        }
+
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        usage.retain(|_, entry| {
+            while entry.recent.front().is_some_and(|opened| now.duration_since(*opened) >= WINDOW) {
+                entry.recent.pop_front();
+            }
+            entry.open > 0 || !entry.recent.is_empty()
+        });
+
+        let entry = usage.entry(ip).or_default();
+        if (self.max_open > 0 && entry.open >= self.max_open) || (self.per_minute > 0 && entry.recent.len() >= self.per_minute) {
+            return None;
+        }
+
+        entry.open += 1;
+        entry.recent.push_back(now);
+        Some(ConnectionPermit { limiter: Some(self.clone()), ip })
+    }
+}
+
+/// One open connection of `ip`; dropping it frees its place.
+pub struct ConnectionPermit {
+    limiter: Option<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let Some(limiter) = &self.limiter else { return };
+
+        let mut usage = limiter.usage.lock().unwrap();
+        if let Some(entry) = usage.get_mut(&self.ip) {
+            entry.open -= 1;
+        }
+    }
+}
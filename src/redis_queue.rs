@@ -0,0 +1,259 @@
+//! # Redis Matchmaking
+//!
+//! Cross-instance matchmaking for running several server replicas, compiled in
+//! with the `redis-queue` feature and switched on by setting `REDIS_URL`.
+//!
+//! Every instance still pairs its own players through the in-process queues
+//! first. In addition, each waiting player is advertised in a Redis list keyed
+//! by mode and [`Rules`], tagged with a ticket and the `INSTANCE_URL` of the
+//! server holding their socket. A player who finds nobody locally claims the
+//! oldest compatible advert from another instance and is told to reconnect
+//! there:
+//!
+//! ```json
+//! { "redirect": "wss://eu-2.example.com/api/search?join=3f2a…" }
+//! ```
+//!
+//! The client opens that URL with its usual token and is paired straight away.
+//!
+//! A claim is a `SET NX` on the ticket, so a waiting player is handed out at
+//! most once even when a local and a remote newcomer race for them. The owning
+//! instance learns about remote claims over pub/sub and parks the entry until
+//! the redirected player arrives; if they don't show up within
+//! [`JOIN_TIMEOUT`] the entry goes back to the front of the queue.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use futures_util::StreamExt;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info, warn};
+use crate::AppMod;
+use crate::config::RedisConfig;
+use crate::database::is_blocked;
+use crate::game::{queued_ticket, requeue, take_queued, GameMode, QueueEntry, QueueOutcome, Rules};
+
+pub const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+const CLAIM_TTL_SECS: u64 = 60;
+const SCAN_LIMIT: isize = 50;
+
+#[derive(Serialize, Deserialize)]
+struct Advert {
+    ticket: String,
+    player_id: i32,
+    instance_url: String,
+}
+
+pub struct RedisQueue {
+    client: redis::Client,
+    conn: ConnectionManager,
+    instance_url: String,
+    reserved: Mutex<HashMap<String, (GameMode, QueueEntry)>>,
+}
+
+impl RedisQueue {
+    pub async fn connect(config: &RedisConfig) -> Arc<Self> {
+        let client = redis::Client::open(config.url.as_str()).expect("REDIS_URL is not a valid Redis URL");
+        let conn = client.get_connection_manager().await.expect("Connecting to Redis failed.");
+
+        Arc::new(Self {
+            client,
+            conn,
+            instance_url: config.instance_url.clone(),
+            reserved: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn queue_key(mode: GameMode, rules: Rules) -> String {
+        format!("tictactoe:queue:{}", serde_json::to_string(&(mode, rules)).unwrap())
+    }
+
+    fn claimed_channel(instance_url: &str) -> String {
+        format!("tictactoe:claimed:{}", instance_url)
+    }
+
+    fn advert(&self, ticket: &str, player_id: i32) -> String {
+        serde_json::to_string(&Advert {
+            ticket: ticket.to_string(),
+            player_id,
+            instance_url: self.instance_url.clone(),
+        }).unwrap()
+    }
+
+    async fn advertise(&self, mode: GameMode, rules: Rules, ticket: &str, player_id: i32) -> redis::RedisResult<()> {
+        redis::cmd("RPUSH")
+            .arg(Self::queue_key(mode, rules))
+            .arg(self.advert(ticket, player_id))
+            .query_async::<()>(&mut self.conn.clone())
+            .await
+    }
+
+    async fn withdraw(&self, mode: GameMode, rules: Rules, ticket: &str, player_id: i32) -> redis::RedisResult<()> {
+        redis::cmd("LREM")
+            .arg(Self::queue_key(mode, rules))
+            .arg(1)
+            .arg(self.advert(ticket, player_id))
+            .query_async::<()>(&mut self.conn.clone())
+            .await
+    }
+
+    /// `true` if this call claimed `ticket`; `false` if someone else already did.
+    async fn claim(&self, ticket: &str) -> redis::RedisResult<bool> {
+        let reply: Option<String> = redis::cmd("SET")
+            .arg(format!("tictactoe:claim:{}", ticket))
+            .arg(&self.instance_url)
+            .arg("NX")
+            .arg("EX")
+            .arg(CLAIM_TTL_SECS)
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(reply.is_some())
+    }
+
+    async fn claim_remote(&self, appmod: &AppMod, player_id: i32, mode: GameMode, rules: Rules) -> redis::RedisResult<Option<String>> {
+        let key = Self::queue_key(mode, rules);
+        let adverts: Vec<String> = redis::cmd("LRANGE")
+            .arg(&key)
+            .arg(0)
+            .arg(SCAN_LIMIT - 1)
+            .query_async(&mut self.conn.clone())
+            .await?;
+
+        for raw in adverts {
+            let Ok(advert) = serde_json::from_str::<Advert>(&raw) else { continue };
+            if advert.instance_url == self.instance_url || advert.player_id == player_id {
+                continue;
+            }
+            let blocked = match appmod.pool.clone() {
+                Some(pool) => is_blocked(pool, player_id, advert.player_id).await.unwrap_or(true),
+                None => false,
+            };
+            if blocked {
+                continue;
+            }
+            if !self.claim(&advert.ticket).await? {
+                continue;
+            }
+
+            redis::cmd("LREM").arg(&key).arg(1).arg(&raw).query_async::<()>(&mut self.conn.clone()).await?;
+            redis::cmd("PUBLISH")
+                .arg(Self::claimed_channel(&advert.instance_url))
+                .arg(&advert.ticket)
+                .query_async::<()>(&mut self.conn.clone())
+                .await?;
+
+            return Ok(Some(format!("{}/api/search?join={}", advert.instance_url, advert.ticket)));
+        }
+
+        Ok(None)
+    }
+
+    /// Reconciles a local queue decision with the shared Redis queue.
+    ///
+    /// A local match only stands if its ticket can still be claimed; otherwise
+    /// the opponent is parked for the remote player who got there first and
+    /// `retry` runs the local matching again. A player left waiting tries to
+    /// claim a remote opponent and, failing that, is advertised to the others.
+    pub async fn settle(
+        self: &Arc<Self>,
+        appmod: &AppMod,
+        player_id: i32,
+        mode: GameMode,
+        rules: Rules,
+        mut outcome: QueueOutcome,
+        retry: impl Fn() -> QueueOutcome,
+    ) -> QueueOutcome {
+        loop {
+            match outcome {
+                QueueOutcome::Matched(entry) => match self.claim(entry.ticket()).await {
+                    Ok(true) | Err(_) => {
+                        let _ = self.withdraw(mode, entry.rules(), entry.ticket(), entry.player_id()).await;
+                        return QueueOutcome::Matched(entry);
+                    }
+                    Ok(false) => {
+                        self.reserve(appmod, mode, entry);
+                        outcome = retry();
+                    }
+                },
+                QueueOutcome::Waiting(rx) => {
+                    let Some(ticket) = queued_ticket(appmod, mode, player_id) else {
+                        return QueueOutcome::Waiting(rx);
+                    };
+
+                    match self.claim_remote(appmod, player_id, mode, rules).await {
+                        Ok(Some(url)) => {
+                            take_queued(appmod, &ticket);
+                            return QueueOutcome::Redirect(url);
+                        }
+                        Ok(None) => {}
+                        Err(e) => error!("Redis matchmaking failed, staying local: {}", e),
+                    }
+
+                    if let Err(e) = self.advertise(mode, rules, &ticket, player_id).await {
+                        error!("Failed to advertise ticket in Redis: {}", e);
+                    }
+                    return QueueOutcome::Waiting(rx);
+                }
+                other => return other,
+            }
+        }
+    }
+
+    fn reserve(self: &Arc<Self>, appmod: &AppMod, mode: GameMode, entry: QueueEntry) {
+        let ticket = entry.ticket().to_string();
+        self.reserved.lock().unwrap().insert(ticket.clone(), (mode, entry));
+
+        let redis = self.clone();
+        let appmod = appmod.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(JOIN_TIMEOUT).await;
+            let expired = redis.reserved.lock().unwrap().remove(&ticket);
+            if let Some((mode, entry)) = expired {
+                warn!("Redirected opponent for ticket {} never arrived, requeueing", ticket);
+                let (rules, player_id) = (entry.rules(), entry.player_id());
+                requeue(&appmod, mode, entry);
+                let _ = redis.advertise(mode, rules, &ticket, player_id).await;
+            }
+        });
+    }
+}
+
+/// The parked (or still queued) entry a redirected player came to join.
+pub fn take_joined(appmod: &AppMod, ticket: &str) -> Option<QueueEntry> {
+    let redis = appmod.redis_queue.as_ref()?;
+    let reserved = redis.reserved.lock().unwrap().remove(ticket);
+    reserved.or_else(|| take_queued(appmod, ticket)).map(|(_, entry)| entry)
+}
+
+/// Parks local entries as soon as another instance claims them, so they are no
+/// longer offered to local newcomers while the claimant is being redirected.
+pub async fn listen_for_claims(redis: Arc<RedisQueue>, appmod: AppMod) {
+    let mut pubsub = match redis.client.get_async_pubsub().await {
+        Ok(pubsub) => pubsub,
+        Err(e) => {
+            error!("Redis pub/sub unavailable: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = pubsub.subscribe(RedisQueue::claimed_channel(&redis.instance_url)).await {
+        error!("Failed to subscribe to Redis claims: {}", e);
+        return;
+    }
+    info!("Listening for remote claims as {}", redis.instance_url);
+
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let Ok(ticket) = message.get_payload::<String>() else { continue };
+        if let Some((mode, entry)) = take_queued(&appmod, &ticket) {
+            redis.reserve(&appmod, mode, entry);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! # Request Logging
+//!
+//! One log line per HTTP request with its method, path, status and how long it
+//! took, written by `tower_http`'s `TraceLayer` at `REQUEST_LOG_LEVEL` (default
+//! `info`). Server errors are additionally logged at `error`.
+//!
+//! Every request carries an `x-request-id`: the one the client or proxy sent,
+//! or a fresh UUID. It is a field of the request's span, so anything a handler
+//! logs while serving it can be matched up with the request line, and it is
+//! echoed back in the response header.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use axum::http::Request;
+use tower_http::trace::MakeSpan;
+use tracing::{Level, Span};
+
+#[derive(Clone)]
+pub struct RequestSpan {
+    level: Level,
+}
+
+impl RequestSpan {
+    pub fn new(level: Level) -> Self {
+        Self { level }
+    }
+}
+
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let request_id = request
+            .headers()
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        // `span!` needs its level at compile time.
+        macro_rules! span {
+            ($level:expr) => {
+                tracing::span!($level, "request", method = %request.method(), path = %request.uri().path(), request_id)
+            };
+        }
+
+        match self.level {
+            Level::ERROR => span!(Level::ERROR),
+            Level::WARN => span!(Level::WARN),
+            Level::INFO => span!(Level::INFO),
+            Level::DEBUG => span!(Level::DEBUG),
+            Level::TRACE => span!(Level::TRACE),
+        }
+    }
+}
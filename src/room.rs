@@ -0,0 +1,144 @@
+//! # Private rooms
+//!
+//! Games between two players who share a code, instead of whoever is in the
+//! queue.
+//!
+//! `POST /api/create_room` opens a room and returns its six-character code.
+//! Anyone who has the code connects to `/api/search` with `"room": "<code>"`;
+//! whoever comes first waits for the other, and the two then play a casual game
+//! with the rules given when the room was made. A room takes exactly two
+//! players: a third one gets `{"error": "ROOM_FULL"}`. A room nobody fills
+//! within `ROOM_TIMEOUT_SECS` is dropped.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use std::collections::HashMap;
+use axum::Json;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use rand::seq::IndexedRandom;
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tracing::info;
+use crate::game::{Player, Rules};
+use crate::{authenticated_user, AppMod};
+
+const CODE_LEN: usize = 6;
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+struct Room {
+    rules: Rules,
+    expires_at: Instant,
+    /// The player who joined first, waiting for the other one.
+    waiting: Option<(i32, oneshot::Sender<Player>)>,
+    /// Both players have joined; kept until it expires so latecomers learn
+    /// the room is taken rather than that it doesn't exist.
+    full: bool,
+}
+
+pub enum Join {
+    NoRoom,
+    Full,
+    /// The same account is already waiting in the room.
+    SelfMatch,
+    /// The other player is already waiting; send them this player.
+    Play(oneshot::Sender<Player>),
+    /// This player waits until the deadline for the other to connect.
+    Wait(Rules, Instant),
+}
+
+#[derive(Default)]
+pub struct Rooms {
+    open: HashMap<String, Room>,
+}
+
+impl Rooms {
+    fn purge(&mut self) {
+        let now = Instant::now();
+        self.open.retain(|_, room| room.expires_at > now);
+    }
+
+    fn create(&mut self, rules: Rules, expires_at: Instant) -> String {
+        self.purge();
+        let mut rng = rand::rng();
+        let code = loop {
+            let code: String = (0..CODE_LEN)
+                .map(|_| *CODE_ALPHABET.choose(&mut rng).unwrap() as char)
+                .collect();
+            if !self.open.contains_key(&code) {
+                break code;
+            }
+        };
+
+        self.open.insert(code.clone(), Room { rules, expires_at, waiting: None, full: false });
+        code
+    }
+
+    pub fn join(&mut self, code: &str, player_id: i32, sender: oneshot::Sender<Player>) -> Join {
+        self.purge();
+        let Some(room) = self.open.get_mut(code) else {
+            return Join::NoRoom;
+        };
+        if room.full {
+            return Join::Full;
+        }
+
+        match room.waiting.take() {
+            Some((waiting_id, waiting)) if waiting_id == player_id => {
+                room.waiting = Some((waiting_id, waiting));
+                Join::SelfMatch
+            }
+            Some((_, waiting)) => {
+                room.full = true;
+                Join::Play(waiting)
+            }
+            None => {
+                room.waiting = Some((player_id, sender));
+                Join::Wait(room.rules, room.expires_at)
+            }
+        }
+    }
+
+    /// Stops `player_id` waiting; `false` if the other player has already taken them.
+    pub fn leave(&mut self, code: &str, player_id: i32) -> bool {
+        self.open
+            .get_mut(code)
+            .and_then(|room| room.waiting.take_if(|(waiting_id, _)| *waiting_id == player_id))
+            .is_some()
+    }
+}
+
+/// Room codes are matched case-insensitively.
+pub fn normalize_code(code: &str) -> String {
+    code.trim().to_ascii_uppercase()
+}
+
+#[derive(Deserialize)]
+pub struct NewRoom {
+    #[serde(flatten)]
+    rules: Rules,
+}
+
+pub async fn create_room(State(appmod): State<AppMod>, headers: HeaderMap, payload: Option<Json<NewRoom>>) -> impl IntoResponse {
+    let me = match authenticated_user(&appmod, &headers).await {
+        Ok(id) => id,
+        Err(status) => return (status, Json(None)),
+    };
+
+    let rules = payload.map(|Json(body)| body.rules).unwrap_or_default();
+    if !rules.is_valid() {
+        return (StatusCode::BAD_REQUEST, Json(Some(serde_json::json!({ "error": "INVALID_RULES" }))));
+    }
+
+    let expires_in = appmod.config.room_timeout;
+    let code = appmod.rooms.lock().unwrap().create(rules, Instant::now() + expires_in);
+    info!("User {} opened room {}", me, code);
+
+    (StatusCode::CREATED, Json(Some(serde_json::json!({ "room": code, "expires_in_secs": expires_in.as_secs() }))))
+}
@@ -0,0 +1,146 @@
+//! # Development Data
+//!
+//! `--seed [N]` fills an empty database with `N` sample players (default 20)
+//! and a few weeks of finished games between them, so the leaderboard, profiles
+//! and game history have something to show right away. The server exits once
+//! seeding is done instead of starting.
+//!
+//! Players are called `seed_player_01`, `seed_player_02`, … and all share the
+//! password [`PASSWORD`]. Each has a fixed skill: on every move they play the
+//! minimax move with a probability that grows with their number and a random
+//! one otherwise, so records range from mostly losses to mostly wins. Games go
+//! through [`save_game`] like real ones, which keeps wins, losses, streaks and
+//! Elo consistent with the game log.
+//!
+//! Seeding never touches a database with real accounts in it: if any user
+//! other than a seed player exists it refuses, and if the seed players are
+//! already there it does nothing, so running it twice is harmless.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use rand::seq::index::sample;
+use sqlx::{Pool, Postgres};
+use crate::bot::{minimax_move, random_move};
+use crate::database::{save_game, GameRecord, MoveRecord};
+use crate::game::{check_winner, BoardOptions, Status};
+
+pub const PASSWORD: &str = "password";
+
+const PREFIX: &str = "seed_player_";
+const GAMES_PER_USER: usize = 10;
+const RANKED_SHARE: f64 = 0.9;
+const HISTORY_DAYS: i64 = 30;
+
+pub enum Outcome {
+    Seeded { users: usize, games: usize },
+    AlreadySeeded,
+    /// The database has this many accounts that aren't seed players.
+    Refused(i64),
+}
+
+pub async fn seed(pool: Pool<Postgres>, users: usize, bcrypt_cost: u32) -> Result<Outcome, sqlx::Error> {
+    let (seeded, others): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*) FILTER (WHERE username LIKE $1 || '%'),
+                COUNT(*) FILTER (WHERE username NOT LIKE $1 || '%')
+         FROM users"
+    )
+        .bind(PREFIX)
+        .fetch_one(&pool)
+        .await?;
+
+    if others > 0 {
+        return Ok(Outcome::Refused(others));
+    }
+    if seeded > 0 {
+        return Ok(Outcome::AlreadySeeded);
+    }
+
+    let users = users.max(2);
+    let names: Vec<String> = (1..=users).map(|n| format!("{}{:02}", PREFIX, n)).collect();
+    let password = bcrypt::hash(PASSWORD, bcrypt_cost).expect("Password hashing error.");
+
+    let ids: Vec<i32> = sqlx::query_scalar(
+        "INSERT INTO users (username, password)
+         SELECT name, $2 FROM UNNEST($1::text[]) WITH ORDINALITY AS u(name, n)
+         ORDER BY n
+         RETURNING id"
+    )
+        .bind(&names)
+        .bind(password)
+        .fetch_all(&pool)
+        .await?;
+
+    let games = play_games(&ids);
+    for game in &games {
+        save_game(pool.clone(), game).await?;
+    }
+
+    Ok(Outcome::Seeded { users: ids.len(), games: games.len() })
+}
+
+/// Random games between the players, oldest first. The last player is the
+/// strongest.
+fn play_games(ids: &[i32]) -> Vec<GameRecord> {
+    let mut rng = rand::rng();
+    let count = ids.len() * GAMES_PER_USER;
+    let first = Utc::now() - Duration::days(HISTORY_DAYS);
+    let spacing = Duration::days(HISTORY_DAYS) / count as i32;
+
+    (0..count)
+        .map(|n| {
+            let pair = sample(&mut rng, ids.len(), 2);
+            let skills = pair.iter().map(|i| i as f64 / (ids.len() - 1) as f64).collect::<Vec<_>>();
+            let players = [ids[pair.index(0)], ids[pair.index(1)]];
+            play_game(&mut rng, players, [skills[0], skills[1]], first + spacing * n as i32)
+        })
+        .collect()
+}
+
+/// One game; `players[0]` plays `O` and moves first.
+fn play_game(rng: &mut impl Rng, players: [i32; 2], skills: [f64; 2], started_at: DateTime<Utc>) -> GameRecord {
+    let mut board = [BoardOptions::Null; 9];
+    let mut symbol = BoardOptions::O;
+    let mut played_at = started_at;
+    let mut moves = Vec::new();
+
+    while check_winner(&board) == Status::InGame {
+        let turn = usize::from(symbol == BoardOptions::X);
+        let field = if rng.random_bool(skills[turn] * 0.8) {
+            minimax_move(&board, symbol)
+        } else {
+            random_move(&board)
+        }
+        .expect("An unfinished board has an empty cell.");
+
+        let think_ms = rng.random_range(400..8000);
+        played_at += Duration::milliseconds(i64::from(think_ms));
+        board[field] = symbol;
+        moves.push(MoveRecord { player_id: players[turn], field: field as i32, board: None, think_ms, played_at });
+        symbol = symbol.opposite();
+    }
+
+    let status = check_winner(&board);
+    GameRecord {
+        player1_id: players[0],
+        player2_id: players[1],
+        winner_id: match status {
+            Status::Player1Won => Some(players[0]),
+            Status::Player2Won => Some(players[1]),
+            _ => None,
+        },
+        board: board.iter().map(|cell| cell.as_cell().to_string()).collect(),
+        status: format!("{:?}", status),
+        ranked: rng.random_bool(RANKED_SHARE),
+        handicap: false,
+        rated: true,
+        started_at: Some(started_at),
+        finished_at: Some(played_at),
+        moves,
+    }
+}
@@ -0,0 +1,170 @@
+//! # Spectating
+//!
+//! Watching live games without taking part in them.
+//!
+//! `GET /api/games` lists the games currently running. Connecting a WebSocket to
+//! `/api/games/{id}/spectate` attaches to one of them: the current
+//! [`Snapshot`] is sent straight away, then again every time the board or
+//! status changes. Once the game is over the final snapshot is sent and the
+//! socket is closed, which also covers a game that ends while the spectator is
+//! still joining.
+//!
+//! Spectators can chat among themselves with `{"type": "chat", "chat": "..."}`;
+//! every spectator of the game, the sender included, gets
+//! `{"type": "spectator_chat", "spectator_chat": {"from": ..., "text": ...}}`. The players never see it. Chatting needs a session token,
+//! passed like on `/api/search` (`Authorization: Bearer …` or `?token=`), whose
+//! username tags the message; anonymous spectators can only watch. Messages are
+//! limited to [`MAX_CHAT_LEN`] characters and one per [`CHAT_INTERVAL`].
+//!
+//! At most `MAX_SPECTATORS` sockets watch a game at once. Further spectators get
+//! `{"error": "SPECTATORS_FULL"}` and are disconnected; a place frees up as soon
+//! as a spectator's socket closes and its subscription is dropped.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use std::time::Duration;
+use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, watch};
+use tokio::time::Instant;
+use crate::{bearer_token, db_error_status, AppMod};
+use crate::game::Snapshot;
+use crate::protocol::{parse_client_message, send_message, Chat, ChatMessage, ClientMessage, ProtocolError, ServerMessage, WireFormat};
+
+pub const MAX_CHAT_LEN: usize = 200;
+pub const CHAT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+pub struct LiveGame {
+    id: u64,
+    players: [String; 2],
+}
+
+pub async fn live_games(State(appmod): State<AppMod>) -> Json<Vec<LiveGame>> {
+    let mut games: Vec<LiveGame> = appmod.games.lock().unwrap()
+        .iter()
+        .map(|(&id, handle)| LiveGame { id, players: handle.view.borrow().players().clone() })
+        .collect();
+    games.sort_by_key(|game| game.id);
+    Json(games)
+}
+
+#[derive(Deserialize)]
+pub struct SpectateQuery {
+    token: Option<String>,
+}
+
+pub async fn spectate(ws: WebSocketUpgrade, State(appmod): State<AppMod>, headers: HeaderMap, Path(id): Path<u64>, Query(query): Query<SpectateQuery>) -> Response {
+    let name = match bearer_token(&headers).map(str::to_owned).or(query.token) {
+        Some(token) => match appmod.users.player_from_token(&token, appmod.config.token_ttl.as_secs_f64()).await {
+            Ok(Some((_, name))) => Some(name),
+            Ok(None) => return StatusCode::UNAUTHORIZED.into_response(),
+            Err(e) => return db_error_status(&e).into_response(),
+        },
+        None => None,
+    };
+
+    // Every receiver of the view is a spectator, so counting and subscribing
+    // under the registry lock keeps the limit exact.
+    let view = {
+        let games = appmod.games.lock().unwrap();
+        match games.get(&id) {
+            Some(handle) if handle.view.receiver_count() >= appmod.config.max_spectators => Err(()),
+            Some(handle) => Ok((handle.view.subscribe(), handle.spectator_chat.clone())),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        }
+    };
+
+    match view {
+        Ok((view, chat)) => ws.on_upgrade(move |socket| watch_game(socket, view, chat, name)),
+        Err(()) => {
+            let max_spectators = appmod.config.max_spectators;
+            ws.on_upgrade(move |mut socket| async move {
+                let _ = send_message(&mut socket, WireFormat::Json, &ServerMessage::error_with("SPECTATORS_FULL", serde_json::json!({
+                    "max_spectators": max_spectators,
+                }))).await;
+                let _ = socket.send(Message::Close(None)).await;
+            })
+        }
+    }
+}
+
+async fn watch_game(mut socket: WebSocket, mut view: watch::Receiver<Snapshot>, chat: broadcast::Sender<ChatMessage>, name: Option<String>) {
+    let mut chat_messages = chat.subscribe();
+    let mut last_chat: Option<Instant> = None;
+
+    loop {
+        let snapshot = view.borrow_and_update().clone();
+        if send_message(&mut socket, WireFormat::Json, &ServerMessage::Snapshot(Box::new(snapshot.clone()))).await.is_err() {
+            return;
+        }
+        if snapshot.is_over() {
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
+
+        loop {
+            tokio::select! {
+                changed = view.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    break;
+                }
+                Ok(message) = chat_messages.recv() => {
+                    let _ = send_message(&mut socket, WireFormat::Json, &ServerMessage::SpectatorChat { spectator_chat: message }).await;
+                }
+                message = socket.recv() => {
+                    let text = match message {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(_)) => continue,
+                        _ => return,
+                    };
+                    let text = match parse_client_message(&text) {
+                        Ok(ClientMessage::Chat(Chat { chat })) => chat,
+                        Ok(other) => {
+                            let _ = send_message(&mut socket, WireFormat::Json, &ProtocolError::Unexpected(other.kind()).reply()).await;
+                            continue;
+                        }
+                        Err(e) => {
+                            let _ = send_message(&mut socket, WireFormat::Json, &e.reply()).await;
+                            continue;
+                        }
+                    };
+
+                    if let Err(error) = post_chat(&chat, name.as_deref(), text.trim(), &mut last_chat) {
+                        let _ = send_message(&mut socket, WireFormat::Json, &error).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Publishes one spectator message, or says why it can't be sent.
+fn post_chat(chat: &broadcast::Sender<ChatMessage>, name: Option<&str>, text: &str, last_chat: &mut Option<Instant>) -> Result<(), ServerMessage> {
+    let Some(name) = name else {
+        return Err(ServerMessage::error("LOGIN_REQUIRED"));
+    };
+    if text.is_empty() {
+        return Ok(());
+    }
+    if text.chars().count() > MAX_CHAT_LEN {
+        return Err(ServerMessage::error_with("CHAT_TOO_LONG", serde_json::json!({ "max_length": MAX_CHAT_LEN })));
+    }
+    if last_chat.is_some_and(|sent| sent.elapsed() < CHAT_INTERVAL) {
+        return Err(ServerMessage::error("CHAT_RATE_LIMITED"));
+    }
+
+    *last_chat = Some(Instant::now());
+    let _ = chat.send(ChatMessage { from: name.to_string(), text: text.to_string() });
+    Ok(())
+}
@@ -0,0 +1,402 @@
+//! # SQLite User Store
+//!
+//! [`UserStore`] on a local SQLite file, compiled in with the `sqlite` feature
+//! and picked by a `sqlite:` `DATABASE_URL`, e.g. `sqlite://tictactoe.db`. The
+//! file is created on first start and its schema comes from
+//! `migrations/sqlite`.
+//!
+//! Meant for `cargo run` during development, not for production: the pool has
+//! a single connection, so every query waits its turn and SQLite never reports
+//! the database as busy.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use std::str::FromStr;
+use bcrypt::{hash, verify};
+use chrono::Utc;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use crate::Login;
+use crate::database::{elo_change, generate_token, GameRecord, Guest, StandingsUpdate, TokenFormat};
+use crate::user_store::{StoreFuture, UserStore};
+
+/// Same as the Postgres `LIVE_TOKEN`: the token bound as `?1`, younger than
+/// `?2` seconds unless `?2` is zero.
+const LIVE_TOKEN: &str = "token = ?1 AND (?2 = 0 OR token_created_at > unixepoch() - ?2)";
+
+pub struct SqliteUserStore {
+    pool: Pool<Sqlite>,
+}
+
+impl SqliteUserStore {
+    pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .foreign_keys(true);
+
+        // One connection that is never recycled, which also keeps a
+        // `sqlite::memory:` database alive for as long as the store.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await?;
+
+        sqlx::migrate!("./migrations/sqlite").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+impl UserStore for SqliteUserStore {
+    fn create_user<'a>(&'a self, log: &'a Login, bcrypt_cost: u32) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let hashed_password = hash(&log.password, bcrypt_cost).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+
+            // The unique index on lower(username) turns away "Bob" once "bob" exists.
+            let inserted = sqlx::query("INSERT INTO users (username, password, email) VALUES (?1, ?2, ?3) ON CONFLICT DO NOTHING")
+                .bind(&log.name)
+                .bind(hashed_password)
+                .bind(&log.email)
+                .execute(&self.pool)
+                .await?;
+
+            Ok(inserted.rows_affected() == 1)
+        })
+    }
+
+    fn is_email_taken<'a>(&'a self, email: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE lower(email) = lower(?1))")
+                .bind(email)
+                .fetch_one(&self.pool)
+                .await
+        })
+    }
+
+    fn verify_password<'a>(&'a self, log: &'a Login) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let result: Option<String> = sqlx::query_scalar("SELECT password FROM users WHERE lower(username) = lower(?1) AND NOT guest")
+                .bind(&log.name)
+                .fetch_optional(&self.pool)
+                .await?;
+
+            Ok(result.is_some_and(|result| verify(&log.password, &result).unwrap_or(false)))
+        })
+    }
+
+    fn create_guest(&self, format: TokenFormat) -> StoreFuture<'_, Guest> {
+        Box::pin(async move {
+            let suffix = uuid::Uuid::new_v4().simple().to_string();
+            let guest = Guest {
+                username: format!("guest-{}", &suffix[..8]),
+                token: generate_token(format),
+            };
+
+            sqlx::query("INSERT INTO users (username, password, token, token_created_at, guest) VALUES (?1, '', ?2, unixepoch(), 1)")
+                .bind(&guest.username)
+                .bind(&guest.token)
+                .execute(&self.pool)
+                .await?;
+
+            Ok(guest)
+        })
+    }
+
+    fn new_token<'a>(&'a self, log: &'a Login, format: TokenFormat) -> StoreFuture<'a, String> {
+        Box::pin(async move {
+            let token = generate_token(format);
+            sqlx::query("UPDATE users SET token = ?1, token_created_at = unixepoch(), deactivated = 0 WHERE lower(username) = lower(?2)")
+                .bind(&token)
+                .bind(&log.name)
+                .execute(&self.pool)
+                .await?;
+            Ok(token)
+        })
+    }
+
+    fn clear_token<'a>(&'a self, token: &'a str) -> StoreFuture<'a, Option<String>> {
+        Box::pin(async move {
+            sqlx::query_scalar("UPDATE users SET token = NULL WHERE token = ?1 RETURNING username")
+                .bind(token)
+                .fetch_optional(&self.pool)
+                .await
+        })
+    }
+
+    fn player_from_token<'a>(&'a self, token: &'a str, ttl_secs: f64) -> StoreFuture<'a, Option<(i32, String)>> {
+        Box::pin(async move {
+            sqlx::query_as(&format!("SELECT id, username FROM users WHERE {LIVE_TOKEN}"))
+                .bind(token)
+                .bind(ttl_secs)
+                .fetch_optional(&self.pool)
+                .await
+        })
+    }
+
+    fn mark_seen(&self, id: i32) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            sqlx::query("UPDATE users SET last_seen = unixepoch() WHERE id = ?1")
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn save_game<'a>(&'a self, record: &'a GameRecord) -> StoreFuture<'a, i64> {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await?;
+
+            let board = serde_json::to_string(&record.board).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+            let game_id: i64 = sqlx::query_scalar(
+                "INSERT INTO games (player1_id, player2_id, winner_id, board, status, ranked, handicap, started_at, finished_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 RETURNING id"
+            )
+                .bind(record.player1_id)
+                .bind(record.player2_id)
+                .bind(record.winner_id)
+                .bind(board)
+                .bind(&record.status)
+                .bind(record.ranked)
+                .bind(record.handicap)
+                .bind(record.started_at)
+                .bind(record.finished_at.unwrap_or_else(Utc::now))
+                .fetch_one(&mut *tx)
+                .await?;
+
+            for (ply, played) in record.moves.iter().enumerate() {
+                sqlx::query(
+                    "INSERT INTO moves (game_id, ply, player_id, field, board, think_ms, played_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+                )
+                    .bind(game_id)
+                    .bind(ply as i64 + 1)
+                    .bind(played.player_id)
+                    .bind(played.field)
+                    .bind(played.board)
+                    .bind(played.think_ms)
+                    .bind(played.played_at)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+
+            match StandingsUpdate::of(record) {
+                StandingsUpdate::Win { winner_id, loser_id, rated } => {
+                    add_win_id(&mut tx, winner_id).await?;
+                    add_lose_id(&mut tx, loser_id).await?;
+                    if rated {
+                        update_elo(&mut tx, winner_id, loser_id, 1.0).await?;
+                    }
+                }
+                StandingsUpdate::Draw { rated } => {
+                    add_draw_id(&mut tx, record.player1_id).await?;
+                    add_draw_id(&mut tx, record.player2_id).await?;
+                    if rated {
+                        update_elo(&mut tx, record.player1_id, record.player2_id, 0.5).await?;
+                    }
+                }
+                StandingsUpdate::None => {}
+            }
+
+            tx.commit().await?;
+            Ok(game_id)
+        })
+    }
+
+    fn ping(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            sqlx::query("SELECT 1").execute(&self.pool).await?;
+            Ok(())
+        })
+    }
+}
+
+async fn add_win_id(conn: &mut SqliteConnection, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE users
+         SET wins = wins + 1,
+             current_streak = current_streak + 1,
+             best_streak = MAX(best_streak, current_streak + 1)
+         WHERE id = ?1"
+    )
+        .bind(id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+async fn add_lose_id(conn: &mut SqliteConnection, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET loses = loses + 1, current_streak = 0 WHERE id = ?1")
+        .bind(id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+async fn add_draw_id(conn: &mut SqliteConnection, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET draws = draws + 1 WHERE id = ?1")
+        .bind(id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// No row locks needed: with a single connection the transaction already has
+/// the database to itself.
+async fn update_elo(conn: &mut SqliteConnection, player_id: i32, opponent_id: i32, score: f64) -> Result<(), sqlx::Error> {
+    let elo = |id| sqlx::query_scalar::<_, i32>("SELECT elo FROM users WHERE id = ?1").bind(id);
+    let player_elo = elo(player_id).fetch_one(&mut *conn).await?;
+    let opponent_elo = elo(opponent_id).fetch_one(&mut *conn).await?;
+
+    let delta = elo_change(player_elo, opponent_elo, score);
+    for (id, new_elo) in [(player_id, player_elo + delta), (opponent_id, opponent_elo - delta)] {
+        sqlx::query("UPDATE users SET elo = ?2 WHERE id = ?1")
+            .bind(id)
+            .bind(new_elo)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("INSERT INTO elo_history (user_id, elo) VALUES (?1, ?2)")
+            .bind(id)
+            .bind(new_elo)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::MoveRecord;
+
+    async fn store() -> SqliteUserStore {
+        SqliteUserStore::connect("sqlite::memory:").await.unwrap()
+    }
+
+    fn login(name: &str) -> Login {
+        Login { name: name.to_string(), password: "secret123".to_string(), token: String::new(), code: None, email: None }
+    }
+
+    /// Registers `name` and returns their id.
+    async fn register(store: &SqliteUserStore, name: &str) -> i32 {
+        assert!(store.create_user(&login(name), 4).await.unwrap());
+        let token = store.new_token(&login(name), TokenFormat::Uuid).await.unwrap();
+        store.player_from_token(&token, 0.0).await.unwrap().unwrap().0
+    }
+
+    fn game(player1_id: i32, player2_id: i32, winner_id: Option<i32>) -> GameRecord {
+        GameRecord {
+            player1_id,
+            player2_id,
+            winner_id,
+            board: vec!["O".to_string(); 9],
+            status: "Player1Won".to_string(),
+            ranked: true,
+            handicap: false,
+            rated: true,
+            started_at: None,
+            finished_at: None,
+            moves: vec![MoveRecord { player_id: player1_id, field: 4, board: None, think_ms: 300, played_at: Utc::now() }],
+        }
+    }
+
+    async fn standings(store: &SqliteUserStore, id: i32) -> (i32, i32, i32, i32) {
+        sqlx::query_as("SELECT wins, loses, draws, elo FROM users WHERE id = ?1")
+            .bind(id)
+            .fetch_one(&store.pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn usernames_are_unique_ignoring_case() {
+        let store = store().await;
+        assert!(store.create_user(&login("alice"), 4).await.unwrap());
+        assert!(!store.create_user(&login("Alice"), 4).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn login_and_logout() {
+        let store = store().await;
+        store.create_user(&login("alice"), 4).await.unwrap();
+
+        assert!(store.verify_password(&login("alice")).await.unwrap());
+        let wrong = Login { password: "wrong".to_string(), ..login("alice") };
+        assert!(!store.verify_password(&wrong).await.unwrap());
+
+        let token = store.new_token(&login("alice"), TokenFormat::Uuid).await.unwrap();
+        assert_eq!(store.player_from_token(&token, 60.0).await.unwrap().map(|(_, name)| name).as_deref(), Some("alice"));
+        assert_eq!(store.clear_token(&token).await.unwrap().as_deref(), Some("alice"));
+        assert_eq!(store.player_from_token(&token, 60.0).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn logins_ignore_case() {
+        let store = store().await;
+        store.create_user(&login("alice"), 4).await.unwrap();
+
+        assert!(store.verify_password(&login("ALICE")).await.unwrap());
+        let token = store.new_token(&login("Alice"), TokenFormat::Uuid).await.unwrap();
+        assert_eq!(store.player_from_token(&token, 60.0).await.unwrap().map(|(_, name)| name).as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn stale_tokens_are_refused() {
+        let store = store().await;
+        let alice = register(&store, "alice").await;
+        let token = store.new_token(&login("alice"), TokenFormat::Uuid).await.unwrap();
+        sqlx::query("UPDATE users SET token_created_at = unixepoch() - 90000 WHERE id = ?1")
+            .bind(alice)
+            .execute(&store.pool)
+            .await
+            .unwrap();
+
+        assert_eq!(store.player_from_token(&token, 86400.0).await.unwrap(), None);
+        // TOKEN_TTL_SECS=0 keeps tokens forever.
+        assert!(store.player_from_token(&token, 0.0).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn guests_cannot_log_in_by_name() {
+        let store = store().await;
+        let guest = store.create_guest(TokenFormat::Uuid).await.unwrap();
+        assert!(store.player_from_token(&guest.token, 0.0).await.unwrap().is_some());
+
+        let by_name = Login { password: String::new(), ..login(&guest.username) };
+        assert!(!store.verify_password(&by_name).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn saved_games_move_the_standings() {
+        let store = store().await;
+        let (alice, bob) = (register(&store, "alice").await, register(&store, "bob").await);
+
+        store.save_game(&game(alice, bob, Some(alice))).await.unwrap();
+        assert_eq!(standings(&store, alice).await, (1, 0, 0, 1216));
+        assert_eq!(standings(&store, bob).await, (0, 1, 0, 1184));
+
+        store.save_game(&game(alice, bob, None)).await.unwrap();
+        assert_eq!(standings(&store, alice).await.2, 1);
+        assert_eq!(standings(&store, bob).await.2, 1);
+
+        let casual = GameRecord { ranked: false, ..game(alice, bob, Some(bob)) };
+        store.save_game(&casual).await.unwrap();
+        assert_eq!(standings(&store, bob).await.0, 0);
+    }
+
+    #[tokio::test]
+    async fn unrated_wins_count_without_moving_elo() {
+        let store = store().await;
+        let (alice, bob) = (register(&store, "alice").await, register(&store, "bob").await);
+
+        store.save_game(&GameRecord { rated: false, ..game(alice, bob, Some(alice)) }).await.unwrap();
+        assert_eq!(standings(&store, alice).await, (1, 0, 0, 1200));
+        assert_eq!(standings(&store, bob).await, (0, 1, 0, 1200));
+    }
+}
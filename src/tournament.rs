@@ -0,0 +1,277 @@
+//! # Tournaments
+//!
+//! Organised events in two formats.
+//!
+//! - **Bracket** (single elimination). Entrants are seeded by Elo and placed in
+//!   the usual bracket order (1 v 16, 8 v 9, …), so the strongest players can
+//!   only meet late. When the field isn't a power of two the top seeds get byes
+//!   and start in round 2. The winner advances; a draw or an abandoned start
+//!   leaves the match open to be replayed.
+//! - **Round robin**. Everyone plays everyone else once, scheduled in rounds
+//!   with the circle method. Every result counts towards a points table
+//!   (2 for a win, 1 for a draw), and the leader after the last match wins.
+//!
+//! Tournament games don't go through matchmaking. A player connects to
+//! `/api/search` with `"tournament": <id>` and waits in the [`TournamentLobby`]
+//! until an opponent from one of their open matches connects too. The game is a
+//! standard, ranked one. Someone still waiting after `TOURNAMENT_NO_SHOW_SECS`
+//! wins their earliest open match by forfeit.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::oneshot;
+use tracing::{error, info};
+use crate::admin::is_admin;
+use crate::database::{self, entrants, finish_tournament_match, tournament, tournament_matches, tournament_standings, Entrant, NewTournamentMatch, Tournament, TournamentMatch};
+use crate::game::{GameResult, Player};
+use crate::{db_error_status, AppMod};
+
+pub const BRACKET: &str = "bracket";
+pub const ROUND_ROBIN: &str = "round_robin";
+const MAX_PLAYERS: usize = 64;
+
+/// Players waiting for a tournament opponent. A waiting player is listed under
+/// every open match they have, so whichever of their opponents turns up first
+/// gets the game.
+#[derive(Default)]
+pub struct TournamentLobby {
+    waiting: HashMap<i64, i32>,
+    senders: HashMap<i32, oneshot::Sender<(Player, i64)>>,
+}
+
+impl TournamentLobby {
+    /// Takes a waiting opponent from one of `matches`, along with that match id.
+    pub fn take_opponent(&mut self, player_id: i32, matches: &[i64]) -> Option<(i64, oneshot::Sender<(Player, i64)>)> {
+        for &match_id in matches {
+            let Some(&waiting_id) = self.waiting.get(&match_id) else { continue };
+            if waiting_id == player_id {
+                continue;
+            }
+            if let Some(sender) = self.leave(waiting_id) {
+                return Some((match_id, sender));
+            }
+        }
+        None
+    }
+
+    pub fn wait(&mut self, player_id: i32, matches: &[i64], sender: oneshot::Sender<(Player, i64)>) {
+        self.leave(player_id);
+        for &match_id in matches {
+            self.waiting.insert(match_id, player_id);
+        }
+        self.senders.insert(player_id, sender);
+    }
+
+    /// Removes the player; `None` means nobody was waiting under that id (or an
+    /// opponent has already taken them).
+    pub fn leave(&mut self, player_id: i32) -> Option<oneshot::Sender<(Player, i64)>> {
+        self.waiting.retain(|_, waiting_id| *waiting_id != player_id);
+        self.senders.remove(&player_id)
+    }
+}
+
+/// Bracket positions of seeds `1..=size`, top to bottom, e.g. `[1, 4, 2, 3]`.
+fn seed_order(size: usize) -> Vec<usize> {
+    let mut order = vec![1];
+    while order.len() < size {
+        let mirror = order.len() * 2 + 1;
+        order = order.iter().flat_map(|&seed| [seed, mirror - seed]).collect();
+    }
+    order
+}
+
+/// Every match of the bracket, with round 1 filled in and byes already
+/// advanced into round 2.
+fn seed_bracket(entrants: &[Entrant]) -> Vec<NewTournamentMatch> {
+    let size = entrants.len().next_power_of_two();
+    let rounds = size.trailing_zeros() as i32;
+    let seeded = |seed: usize| entrants.get(seed - 1).map(|entrant| entrant.id);
+
+    let mut matches: Vec<NewTournamentMatch> = (1..=rounds)
+        .flat_map(|round| {
+            (0..(size >> round) as i32).map(move |slot| NewTournamentMatch {
+                round,
+                slot,
+                player1_id: None,
+                player2_id: None,
+                winner_id: None,
+            })
+        })
+        .collect();
+
+    for (slot, pair) in seed_order(size).chunks(2).enumerate() {
+        let (player1, player2) = (seeded(pair[0]), seeded(pair[1]));
+        matches[slot].player1_id = player1;
+        matches[slot].player2_id = player2;
+
+        let bye = match (player1, player2) {
+            (Some(player), None) | (None, Some(player)) => player,
+            _ => continue,
+        };
+        matches[slot].winner_id = Some(bye);
+
+        let next = &mut matches[size / 2 + slot / 2];
+        if slot % 2 == 0 {
+            next.player1_id = Some(bye);
+        } else {
+            next.player2_id = Some(bye);
+        }
+    }
+
+    matches
+}
+
+/// Every pairing exactly once, round by round (circle method). With an odd
+/// number of players one of them sits out each round.
+fn round_robin_schedule(entrants: &[Entrant]) -> Vec<NewTournamentMatch> {
+    let mut seats: Vec<Option<i32>> = entrants.iter().map(|entrant| Some(entrant.id)).collect();
+    if seats.len() % 2 == 1 {
+        seats.push(None);
+    }
+
+    let mut matches = Vec::new();
+    for round in 1..seats.len() as i32 {
+        let half = seats.len() / 2;
+        let pairs = (0..half).filter_map(|i| Some((seats[i]?, seats[seats.len() - 1 - i]?)));
+        for (slot, (player1, player2)) in pairs.enumerate() {
+            matches.push(NewTournamentMatch {
+                round,
+                slot: slot as i32,
+                player1_id: Some(player1),
+                player2_id: Some(player2),
+                winner_id: None,
+            });
+        }
+        seats[1..].rotate_right(1);
+    }
+
+    matches
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Bracket,
+    RoundRobin,
+}
+
+#[derive(Deserialize)]
+pub struct NewTournament {
+    name: String,
+    players: Vec<String>,
+    #[serde(default)]
+    format: Format,
+}
+
+pub async fn create_tournament(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<NewTournament>) -> impl IntoResponse {
+    if let Err(status) = is_admin(&appmod, &headers) {
+        return (status, Json(serde_json::json!({ "error": "UNAUTHORIZED" })));
+    }
+
+    let requested: HashSet<&String> = payload.players.iter().collect();
+    if payload.name.trim().is_empty() || !(2..=MAX_PLAYERS).contains(&requested.len()) || requested.len() != payload.players.len() {
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": "INVALID_TOURNAMENT" })));
+    }
+
+    let entrants = match entrants(appmod.pg(), &payload.players).await {
+        Ok(entrants) => entrants,
+        Err(e) => return (db_error_status(&e), Json(serde_json::json!({ "error": "ERROR" }))),
+    };
+
+    if entrants.len() != requested.len() {
+        let found: HashSet<&String> = entrants.iter().map(|entrant| &entrant.username).collect();
+        let unknown: Vec<&String> = requested.difference(&found).copied().collect();
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": "UNKNOWN_PLAYERS", "players": unknown })));
+    }
+
+    let (format, matches) = match payload.format {
+        Format::Bracket => (BRACKET, seed_bracket(&entrants)),
+        Format::RoundRobin => (ROUND_ROBIN, round_robin_schedule(&entrants)),
+    };
+    match database::create_tournament(appmod.pg(), payload.name.trim(), format, &matches).await {
+        Ok(id) => {
+            info!("Tournament {} created with {} players", id, entrants.len());
+            (StatusCode::CREATED, Json(serde_json::json!({ "id": id })))
+        }
+        Err(e) => {
+            error!("Failed to create tournament: {}", e);
+            (db_error_status(&e), Json(serde_json::json!({ "error": "ERROR" })))
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Bracket {
+    #[serde(flatten)]
+    tournament: Tournament,
+    rounds: Vec<Vec<TournamentMatch>>,
+}
+
+pub async fn bracket(State(appmod): State<AppMod>, Path(id): Path<i64>) -> impl IntoResponse {
+    let tournament = match tournament(appmod.pg(), id).await {
+        Ok(Some(tournament)) => tournament,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => return Err(db_error_status(&e)),
+    };
+
+    let matches = tournament_matches(appmod.pg(), id).await.map_err(|e| db_error_status(&e))?;
+
+    let mut rounds: Vec<Vec<TournamentMatch>> = Vec::new();
+    for m in matches {
+        match rounds.last_mut() {
+            Some(round) if round[0].round == m.round => round.push(m),
+            _ => rounds.push(vec![m]),
+        }
+    }
+
+    Ok(Json(Bracket { tournament, rounds }))
+}
+
+pub async fn standings(State(appmod): State<AppMod>, Path(id): Path<i64>) -> impl IntoResponse {
+    match tournament(appmod.pg(), id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => return Err(db_error_status(&e)),
+    }
+
+    tournament_standings(appmod.pg(), id).await
+        .map(Json)
+        .map_err(|e| db_error_status(&e))
+}
+
+/// Called once a tournament game is over. A win always counts; whether a draw
+/// does depends on the format (see [`finish_tournament_match`]). After an
+/// aborted start the same pairing is played again.
+pub async fn record_result(appmod: &AppMod, match_id: i64, result: GameResult) {
+    let winner_id = match result {
+        GameResult::Won(winner_id) => Some(winner_id),
+        GameResult::Draw => None,
+        GameResult::NoResult => {
+            info!("Tournament match {} was not played and stays open", match_id);
+            return;
+        }
+    };
+
+    if let Err(e) = finish_tournament_match(appmod.pg(), match_id, winner_id, false).await {
+        error!("Failed to record tournament match {}: {}", match_id, e);
+    }
+}
+
+/// Awards `match_id` to `winner_id` because the opponent never showed up.
+pub async fn record_forfeit(appmod: &AppMod, match_id: i64, winner_id: i32) {
+    info!("Tournament match {} forfeited to player {}", match_id, winner_id);
+    if let Err(e) = finish_tournament_match(appmod.pg(), match_id, Some(winner_id), true).await {
+        error!("Failed to record forfeit of tournament match {}: {}", match_id, e);
+    }
+}
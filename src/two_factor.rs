@@ -0,0 +1,218 @@
+//! # Two-Factor Authentication
+//!
+//! Optional TOTP (RFC 6238) second factor for password logins.
+//!
+//! 1. `POST /api/2fa/enroll` creates a fresh secret and returns it together with
+//!    an `otpauth://` URI for authenticator apps. 2FA is not active yet.
+//! 2. `POST /api/2fa/verify` with a current code from the app switches 2FA on and
+//!    returns a one-off set of recovery codes.
+//! 3. From then on `/api/login` needs a `"code"` next to the password — either the
+//!    current TOTP code or one unused recovery code.
+//!
+//! Secrets are sealed with ChaCha20-Poly1305 under `TOTP_KEY` (32 bytes, base64)
+//! before they reach the database; recovery codes are stored as SHA-256 hashes.
+//! Without `TOTP_KEY` the endpoints answer `503`, and so do logins to accounts
+//! that already have 2FA on.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use axum::Json;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::seq::IndexedRandom;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+use tracing::info;
+use crate::{authenticated_user, db_error_status, AppMod};
+use crate::database::{enable_two_factor, set_totp_secret, two_factor_by_id, two_factor_by_name, use_recovery_code};
+
+const ISSUER: &str = "TicTacToe";
+const RECOVERY_CODES: usize = 8;
+const RECOVERY_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const NONCE_LEN: usize = 12;
+
+#[derive(Clone)]
+pub struct SecretBox {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SecretBox {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self { cipher: ChaCha20Poly1305::new(Key::from_slice(key)) }
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, plaintext).expect("Secret encryption error.");
+        STANDARD.encode([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn open(&self, sealed: &str) -> Option<Vec<u8>> {
+        let bytes = STANDARD.decode(sealed).ok()?;
+        if bytes.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        self.cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+    }
+}
+
+fn totp(secret: Vec<u8>, username: &str) -> Option<TOTP> {
+    TOTP::new(Algorithm::SHA1, 6, 1, 30, secret, Some(ISSUER.to_string()), username.replace(':', "_")).ok()
+}
+
+fn recovery_codes() -> Vec<String> {
+    let mut rng = rand::rng();
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code: String = (0..10)
+                .map(|_| *RECOVERY_ALPHABET.choose(&mut rng).unwrap() as char)
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    Sha256::digest(normalized.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Second step of a password login. Passes straight through for accounts
+/// without 2FA; otherwise `code` must be a current TOTP code or an unused
+/// recovery code, which is then used up.
+pub async fn check_second_factor(appmod: &AppMod, username: &str, code: Option<&str>) -> Result<(), StatusCode> {
+    // 2FA can only be enrolled with Postgres.
+    let Some(pool) = appmod.pool.clone() else { return Ok(()) };
+    let state = two_factor_by_name(pool.clone(), username)
+        .await
+        .map_err(|e| db_error_status(&e))?;
+
+    let Some(state) = state.filter(|state| state.enabled) else {
+        return Ok(());
+    };
+
+    let secret_box = appmod.totp_key.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let code = code.ok_or(StatusCode::UNAUTHORIZED)?.trim();
+
+    let totp = state
+        .secret
+        .as_deref()
+        .and_then(|sealed| secret_box.open(sealed))
+        .and_then(|secret| totp(secret, username))
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if totp.check_current(code).unwrap_or(false) {
+        return Ok(());
+    }
+
+    match use_recovery_code(pool, state.id, &hash_recovery_code(code)).await {
+        Ok(true) => {
+            info!("{} logged in with a recovery code", username);
+            Ok(())
+        }
+        Ok(false) => Err(StatusCode::UNAUTHORIZED),
+        Err(e) => Err(db_error_status(&e)),
+    }
+}
+
+pub async fn enroll(State(appmod): State<AppMod>, headers: HeaderMap) -> impl IntoResponse {
+    let Some(secret_box) = appmod.totp_key.clone() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({ "error": "2FA_DISABLED" })));
+    };
+
+    let id = match authenticated_user(&appmod, &headers).await {
+        Ok(id) => id,
+        Err(status) => return (status, Json(serde_json::json!({ "error": "UNAUTHORIZED" }))),
+    };
+
+    let state = match two_factor_by_id(appmod.pg(), id).await {
+        Ok(state) => state,
+        Err(e) => return (db_error_status(&e), Json(serde_json::json!({ "error": "DATABASE" }))),
+    };
+
+    if state.enabled {
+        return (StatusCode::CONFLICT, Json(serde_json::json!({ "error": "ALREADY_ENABLED" })));
+    }
+
+    let secret = Secret::generate_secret().to_bytes().expect("TOTP secret generation error.");
+    let Some(totp) = totp(secret.clone(), &state.username) else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "error": "TOTP" })));
+    };
+
+    if let Err(e) = set_totp_secret(appmod.pg(), id, &secret_box.seal(&secret)).await {
+        return (db_error_status(&e), Json(serde_json::json!({ "error": "DATABASE" })));
+    }
+
+    (StatusCode::OK, Json(serde_json::json!({
+        "secret": totp.get_secret_base32(),
+        "otpauth_url": totp.get_url(),
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct VerifyCode {
+    code: String,
+}
+
+pub async fn verify(State(appmod): State<AppMod>, headers: HeaderMap, Json(payload): Json<VerifyCode>) -> impl IntoResponse {
+    let Some(secret_box) = appmod.totp_key.clone() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({ "error": "2FA_DISABLED" })));
+    };
+
+    let id = match authenticated_user(&appmod, &headers).await {
+        Ok(id) => id,
+        Err(status) => return (status, Json(serde_json::json!({ "error": "UNAUTHORIZED" }))),
+    };
+
+    let state = match two_factor_by_id(appmod.pg(), id).await {
+        Ok(state) => state,
+        Err(e) => return (db_error_status(&e), Json(serde_json::json!({ "error": "DATABASE" }))),
+    };
+
+    if state.enabled {
+        return (StatusCode::CONFLICT, Json(serde_json::json!({ "error": "ALREADY_ENABLED" })));
+    }
+
+    let totp = state
+        .secret
+        .as_deref()
+        .and_then(|sealed| secret_box.open(sealed))
+        .and_then(|secret| totp(secret, &state.username));
+
+    let Some(totp) = totp else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "NOT_ENROLLED" })));
+    };
+
+    if !totp.check_current(payload.code.trim()).unwrap_or(false) {
+        return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({ "error": "INVALID_CODE" })));
+    }
+
+    let codes = recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+
+    if let Err(e) = enable_two_factor(appmod.pg(), id, &hashes).await {
+        return (db_error_status(&e), Json(serde_json::json!({ "error": "DATABASE" })));
+    }
+
+    info!("{} enabled two-factor authentication", state.username);
+    (StatusCode::OK, Json(serde_json::json!({ "recovery_codes": codes })))
+}
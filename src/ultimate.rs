@@ -0,0 +1,91 @@
+//! # Ultimate Tic-Tac-Toe
+//!
+//! Board model for the `ultimate` variant: a 3×3 grid of classic 3×3 boards.
+//!
+//! A move names a sub-board and a cell inside it. The cell index then dictates
+//! the sub-board the opponent must play in next; if that sub-board is already
+//! closed (won or full) the opponent may play in any open one. Winning a
+//! sub-board claims the matching cell of the meta-board, and three claimed
+//! cells in a row win the game.
+//!
+//! The classic [`crate::game`] board is left untouched; in this variant it
+//! mirrors [`UltimateBoard::meta_board`].
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use serde::{Deserialize, Serialize};
+use crate::game::{check_winner, BoardOptions, Status};
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct UltimateBoard {
+    boards: [[BoardOptions; 9]; 9],
+    next_board: Option<usize>,
+}
+
+impl Default for UltimateBoard {
+    fn default() -> Self {
+        Self {
+            boards: [[BoardOptions::Null; 9]; 9],
+            next_board: None,
+        }
+    }
+}
+
+impl UltimateBoard {
+    pub fn meta_board(&self) -> [BoardOptions; 9] {
+        self.boards.map(|board| match check_winner(&board) {
+            Status::Player1Won => BoardOptions::O,
+            Status::Player2Won => BoardOptions::X,
+            _ => BoardOptions::Null,
+        })
+    }
+
+    pub fn cells(&self) -> Vec<BoardOptions> {
+        self.boards.concat()
+    }
+
+    fn is_closed(&self, board: usize) -> bool {
+        check_winner(&self.boards[board]) != Status::InGame
+    }
+
+    pub fn place(&mut self, board: usize, cell: usize, symbol: BoardOptions) -> bool {
+        if board > 8 || cell > 8 || self.is_closed(board) {
+            return false;
+        }
+
+        if self.next_board.is_some_and(|next| next != board) {
+            return false;
+        }
+
+        if self.boards[board][cell] != BoardOptions::Null {
+            return false;
+        }
+
+        self.boards[board][cell] = symbol;
+        self.next_board = if self.is_closed(cell) { None } else { Some(cell) };
+        true
+    }
+
+    /// Every `(board, cell)` pair [`place`](Self::place) would currently accept.
+    pub fn legal_moves(&self) -> Vec<(usize, usize)> {
+        (0..9)
+            .filter(|&board| !self.is_closed(board) && self.next_board.is_none_or(|next| next == board))
+            .flat_map(|board| (0..9).map(move |cell| (board, cell)))
+            .filter(|&(board, cell)| self.boards[board][cell] == BoardOptions::Null)
+            .collect()
+    }
+
+    pub fn status(&self) -> Status {
+        let status = check_winner(&self.meta_board());
+
+        if status == Status::InGame && (0..9).all(|board| self.is_closed(board)) {
+            Status::Draw
+        } else {
+            status
+        }
+    }
+}
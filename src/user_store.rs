@@ -0,0 +1,112 @@
+//! # User Store
+//!
+//! Accounts, sessions and game results behind one interface, so they can live in
+//! PostgreSQL or, for local development, in a SQLite file.
+//!
+//! The backend follows the `DATABASE_URL` scheme (see [`Backend`]). PostgreSQL
+//! is the default and the only backend for production: everything else the
+//! server stores — friends, blocks, tournaments, achievements, 2FA, bans,
+//! leaderboards — is written against Postgres, and those routes are only
+//! mounted when it is the backend. A server built with the `sqlite` feature and
+//! started with a `sqlite:` URL keeps its users in a [`SqliteUserStore`]
+//! instead, which is enough to register, log in and play, with results counted.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use sqlx::{Pool, Postgres};
+use crate::Login;
+use crate::config::{Backend, Config};
+use crate::database::{self, GameRecord, Guest, TokenFormat};
+#[cfg(feature = "sqlite")]
+use crate::sqlite_store::SqliteUserStore;
+
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, sqlx::Error>> + Send + 'a>>;
+
+/// The account operations every backend provides. They behave like the
+/// functions of the same name in [`crate::database`].
+pub trait UserStore: Send + Sync {
+    /// `false` if the username is already taken, in any case.
+    fn create_user<'a>(&'a self, log: &'a Login, bcrypt_cost: u32) -> StoreFuture<'a, bool>;
+    fn is_email_taken<'a>(&'a self, email: &'a str) -> StoreFuture<'a, bool>;
+    fn verify_password<'a>(&'a self, log: &'a Login) -> StoreFuture<'a, bool>;
+    fn create_guest(&self, format: TokenFormat) -> StoreFuture<'_, Guest>;
+    fn new_token<'a>(&'a self, log: &'a Login, format: TokenFormat) -> StoreFuture<'a, String>;
+    /// Ends the session; returns the username, or `None` if no one holds the token.
+    fn clear_token<'a>(&'a self, token: &'a str) -> StoreFuture<'a, Option<String>>;
+    /// Id and username of the token's owner while the token is live.
+    fn player_from_token<'a>(&'a self, token: &'a str, ttl_secs: f64) -> StoreFuture<'a, Option<(i32, String)>>;
+    fn mark_seen(&self, id: i32) -> StoreFuture<'_, ()>;
+    /// Stores a finished game, updates the standings and returns the game id.
+    fn save_game<'a>(&'a self, record: &'a GameRecord) -> StoreFuture<'a, i64>;
+    /// A trivial query, for the database health check.
+    fn ping(&self) -> StoreFuture<'_, ()>;
+}
+
+pub struct PgUserStore {
+    pool: Pool<Postgres>,
+}
+
+impl UserStore for PgUserStore {
+    fn create_user<'a>(&'a self, log: &'a Login, bcrypt_cost: u32) -> StoreFuture<'a, bool> {
+        Box::pin(database::create_new_user(self.pool.clone(), log, bcrypt_cost))
+    }
+
+    fn is_email_taken<'a>(&'a self, email: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(database::is_email_taken(self.pool.clone(), email))
+    }
+
+    fn verify_password<'a>(&'a self, log: &'a Login) -> StoreFuture<'a, bool> {
+        Box::pin(database::verify_password(self.pool.clone(), log))
+    }
+
+    fn create_guest(&self, format: TokenFormat) -> StoreFuture<'_, Guest> {
+        Box::pin(database::create_guest(self.pool.clone(), format))
+    }
+
+    fn new_token<'a>(&'a self, log: &'a Login, format: TokenFormat) -> StoreFuture<'a, String> {
+        Box::pin(database::new_token(self.pool.clone(), log, format))
+    }
+
+    fn clear_token<'a>(&'a self, token: &'a str) -> StoreFuture<'a, Option<String>> {
+        Box::pin(database::clear_token(self.pool.clone(), token))
+    }
+
+    fn player_from_token<'a>(&'a self, token: &'a str, ttl_secs: f64) -> StoreFuture<'a, Option<(i32, String)>> {
+        Box::pin(database::player_from_token(self.pool.clone(), token, ttl_secs))
+    }
+
+    fn mark_seen(&self, id: i32) -> StoreFuture<'_, ()> {
+        Box::pin(database::mark_seen(self.pool.clone(), id))
+    }
+
+    fn save_game<'a>(&'a self, record: &'a GameRecord) -> StoreFuture<'a, i64> {
+        Box::pin(database::save_game(self.pool.clone(), record))
+    }
+
+    fn ping(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            sqlx::query("SELECT 1").execute(&self.pool).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Opens the store `DATABASE_URL` points at. The Postgres pool is returned as
+/// well for everything outside the store; with SQLite there is none.
+pub async fn connect(config: &Config) -> Result<(Arc<dyn UserStore>, Option<Pool<Postgres>>), sqlx::Error> {
+    match config.backend {
+        Backend::Postgres => {
+            let pool = database::connect_to_database(config).await?;
+            Ok((Arc::new(PgUserStore { pool: pool.clone() }), Some(pool)))
+        }
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Ok((Arc::new(SqliteUserStore::connect(&config.database_url).await?), None)),
+    }
+}
@@ -0,0 +1,139 @@
+//! # Webhooks
+//!
+//! Tells an outside service about every finished game, for Discord bots,
+//! external leaderboards and the like.
+//!
+//! With `WEBHOOK_URL` set, each game that [`record_game`](crate::game) stores
+//! is also `POST`ed there as JSON:
+//!
+//! ```json
+//! {
+//!   "event": "game_finished",
+//!   "game_id": 4711,
+//!   "ranked": true,
+//!   "player1": "alice",
+//!   "player2": "bob",
+//!   "winner": "alice",
+//!   "status": "Player1Won",
+//!   "board": ["O", "X", "", "", "O", "X", "", "", "O"],
+//!   "finished_at": "2026-10-16T12:00:00Z"
+//! }
+//! ```
+//!
+//! `winner` is `null` for a draw. With `WEBHOOK_SECRET` set, the request also
+//! carries `X-Webhook-Signature: sha256=<hex>`, the HMAC-SHA256 of the body
+//! under the secret, so the receiver can tell the payload came from here.
+//!
+//! Delivery never holds up a game: events go into a bounded queue that a
+//! background task drains. A request that fails or times out is retried twice
+//! with a growing pause, then dropped and logged. When the queue is full because
+//! the receiver can't keep up, new events are dropped with a warning.
+//!
+//! ## Author
+//! Marcel Gruszecki
+//!
+//! ## License
+//! MIT — see `LICENSE` in the repository root.
+
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::sleep;
+use tracing::{error, warn};
+use crate::config::WebhookConfig;
+
+const QUEUE_SIZE: usize = 256;
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug)]
+pub struct GameFinished {
+    pub event: &'static str,
+    pub game_id: i64,
+    pub ranked: bool,
+    pub player1: String,
+    pub player2: String,
+    pub winner: Option<String>,
+    pub status: String,
+    pub board: Vec<String>,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// Handle to the delivery task; does nothing when no webhook is configured.
+#[derive(Clone, Default)]
+pub struct Webhooks {
+    sender: Option<mpsc::Sender<GameFinished>>,
+}
+
+impl Webhooks {
+    pub fn start(config: Option<WebhookConfig>) -> Self {
+        let Some(config) = config else {
+            return Self::default();
+        };
+
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(deliver(config, receiver));
+        Self { sender: Some(sender) }
+    }
+
+    pub fn game_finished(&self, event: GameFinished) {
+        let Some(sender) = &self.sender else { return };
+        if let Err(TrySendError::Full(event)) = sender.try_send(event) {
+            warn!("Webhook queue is full, dropping game {}", event.game_id);
+        }
+    }
+}
+
+async fn deliver(config: WebhookConfig, mut receiver: mpsc::Receiver<GameFinished>) {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Building the webhook HTTP client failed.");
+
+    while let Some(event) = receiver.recv().await {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Serializing the webhook for game {} failed: {}", event.game_id, e);
+                continue;
+            }
+        };
+
+        for attempt in 1..=ATTEMPTS {
+            match post(&client, &config, &body).await {
+                Ok(()) => break,
+                Err(e) if attempt < ATTEMPTS => {
+                    warn!("Webhook for game {} failed (attempt {}/{}): {}", event.game_id, attempt, ATTEMPTS, e);
+                    sleep(RETRY_DELAY * attempt).await;
+                }
+                Err(e) => error!("Dropping webhook for game {} after {} attempts: {}", event.game_id, ATTEMPTS, e),
+            }
+        }
+    }
+}
+
+async fn post(client: &reqwest::Client, config: &WebhookConfig, body: &[u8]) -> Result<(), reqwest::Error> {
+    let mut request = client
+        .post(&config.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_vec());
+
+    if let Some(secret) = &config.secret {
+        request = request.header("x-webhook-signature", format!("sha256={}", sign(secret, body)));
+    }
+
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+/// Hex HMAC-SHA256 of `body` under `secret`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length.");
+    mac.update(body);
+    mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}